@@ -0,0 +1,77 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use bevy::log::Level;
+
+use isotokyo::args::{exit_with_usage, next_value};
+
+const USAGE: &str = "\
+Usage: client [OPTIONS]
+
+Options:
+    --connect <ADDR>     Server to connect to [default: 127.0.0.1:5000]
+    --name <NAME>        Name shown to other players
+    --offline            Walk around the map without connecting to a server
+    --spectate           Join the server to watch, without a player
+    --dev                Enable developer tools like the stats overlay (F10)
+    --config <PATH>      Read the game config from this file [default: config/config.ron]
+    --log-level <LEVEL>  Minimum level of messages to log [default: info]
+    --help               Print this message";
+
+pub struct Args {
+    pub connect: SocketAddr,
+    pub name: String,
+    pub offline: bool,
+    pub spectate: bool,
+    pub dev: bool,
+    pub config: Option<PathBuf>,
+    pub log_level: Level,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            connect: "127.0.0.1:5000".parse().unwrap(),
+            name: "Player".into(),
+            offline: false,
+            spectate: false,
+            dev: false,
+            config: None,
+            log_level: Level::INFO,
+        }
+    }
+}
+
+impl Args {
+    pub fn parse() -> Self {
+        let mut args = std::env::args().skip(1);
+        let mut parsed = Self::default();
+        while let Some(arg) = args.next() {
+            let result = match arg.as_str() {
+                "--connect" => next_value(&mut args, &arg).map(|addr| parsed.connect = addr),
+                "--name" => next_value(&mut args, &arg).map(|name| parsed.name = name),
+                "--offline" => {
+                    parsed.offline = true;
+                    Ok(())
+                }
+                "--spectate" => {
+                    parsed.spectate = true;
+                    Ok(())
+                }
+                "--dev" => {
+                    parsed.dev = true;
+                    Ok(())
+                }
+                "--config" => next_value(&mut args, &arg).map(|path| parsed.config = Some(path)),
+                "--log-level" => {
+                    next_value(&mut args, &arg).map(|log_level| parsed.log_level = log_level)
+                }
+                "--help" | "-h" => exit_with_usage(USAGE, None),
+                _ => Err(format!("Unknown argument '{}'", arg)),
+            };
+            if let Err(err) = result {
+                exit_with_usage(USAGE, Some(err));
+            }
+        }
+        parsed
+    }
+}
@@ -1,12 +1,16 @@
-use std::{net::UdpSocket, time::SystemTime};
+use std::{
+    net::{SocketAddr, UdpSocket},
+    time::SystemTime,
+};
 
-use bevy::{prelude::*, window::PresentMode};
+use args::Args;
+use bevy::{ecs::entity::Entities, log::LogPlugin, prelude::*};
 use bevy_egui::{EguiContexts, EguiPlugin};
 use bevy_renet::{
     client_connected,
     renet::{
         transport::{ClientAuthentication, NetcodeClientTransport, NetcodeTransportError},
-        RenetClient,
+        ClientId, RenetClient,
     },
     transport::NetcodeClientPlugin,
     RenetClientPlugin,
@@ -14,21 +18,39 @@ use bevy_renet::{
 use bevy_xpbd_3d::{
     components::LinearVelocity,
     plugins::{PhysicsDebugPlugin, PhysicsPlugins},
+    resources::PhysicsTimestep,
 };
 use isotokyo::{
+    config::NetworkMode,
+    lockstep::LockstepSteps,
     networking::{
-        connection_config, ClientChannel, ClientLobby, MostRecentTick, NetworkMapping,
-        NetworkedEntities, PlayerCommand, PlayerInfo, ServerChannel, ServerMessages, PROTOCOL_ID,
+        connection_config, state_hash, ClientChannel, ClientLobby, Handshake, MostRecentTick,
+        NetworkFrame, NetworkMapping, Player, PlayerCommand, PlayerInfo, ServerChannel,
+        ServerMessages, StateHashes, Team, BUILD_VERSION, PROTOCOL_ID, PROTOCOL_VERSION,
+        STATE_HASH_INTERVAL,
     },
-    player::{client_spawn_players, PlayerInput, SpawnPlayer},
+    player::{client_spawn_players, Despawning, PlayerInput, SpawnPlayer},
+    settings::Settings,
+    ui::HitConfirmed,
+    weapons::{Deploying, Inventory, Weapon},
     *,
 };
 use renet_visualizer::{RenetClientVisualizer, RenetVisualizerStyle};
 
-fn new_renet_client() -> (RenetClient, NetcodeClientTransport) {
-    let client = RenetClient::new(connection_config());
+mod args;
+
+// Lockstep inputs sent in a single frame at most, so a long stall doesn't make the
+// game race ahead to catch up
+const MAX_LOCKSTEP_INPUTS: u32 = 4;
+
+fn new_renet_client(
+    server_addr: SocketAddr,
+    name: &str,
+    spectate: bool,
+    config: &config::Config,
+) -> (RenetClient, NetcodeClientTransport) {
+    let client = RenetClient::new(connection_config(&config.network));
 
-    let server_addr = "127.0.0.1:5000".parse().unwrap();
     let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
     let current_time = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
@@ -38,7 +60,7 @@ fn new_renet_client() -> (RenetClient, NetcodeClientTransport) {
         client_id,
         protocol_id: PROTOCOL_ID,
         server_addr,
-        user_data: None,
+        user_data: Some(Handshake::new(name, spectate).to_user_data()),
     };
 
     let transport = NetcodeClientTransport::new(current_time, authentication, socket).unwrap();
@@ -47,41 +69,91 @@ fn new_renet_client() -> (RenetClient, NetcodeClientTransport) {
 }
 
 fn main() {
-    let (client, transport) = new_renet_client();
-    App::new()
-        .insert_resource(ClearColor(Color::rgb(0.125, 0.125, 0.125)))
+    let args = Args::parse();
+    let settings = Settings::load();
+    let mut app = App::new();
+    app.insert_resource(ClearColor(Color::rgb(0.125, 0.125, 0.125)))
         .add_plugins((
             DefaultPlugins
-                .set(ImagePlugin::default_nearest())
+                .set(settings.graphics.texture_filtering.image_plugin())
+                .set(LogPlugin {
+                    level: args.log_level,
+                    ..default()
+                })
                 .set(WindowPlugin {
                     primary_window: Some(Window {
                         title: "Isotokyo".into(),
-                        resolution: (1280., 720.).into(),
-                        present_mode: PresentMode::Fifo,
+                        resolution: settings.graphics.resolution.into(),
+                        present_mode: settings.graphics.present_mode(),
                         ..default()
                     }),
                     ..default()
                 }),
             PhysicsPlugins::default(),
             // PhysicsDebugPlugin::default(),
-            RenetClientPlugin,
-            NetcodeClientPlugin,
             EguiPlugin,
-            config::ConfigPlugin,
+            args.config
+                .clone()
+                .map_or_else(config::ConfigPlugin::default, |path| config::ConfigPlugin {
+                    path,
+                }),
+            settings::SettingsPlugin,
             input::InputPlugin,
             sprites::Sprite3dPlugin,
             player::ClientPlayerPlugin,
             ui::UiPlugin,
-        ))
+        ));
+    if args.dev {
+        app.insert_resource(ui::DevMode);
+    }
+    if args.offline {
+        app.add_systems(Startup, spawn_offline_player).add_systems(
+            Update,
+            (
+                (triggers::check_water, player::player_move)
+                    .chain()
+                    .after(player::player_input)
+                    .after(client_spawn_players),
+                reload_map_on_key.before(load_map),
+            ),
+        );
+    } else {
+        let (client, transport) = new_renet_client(
+            args.connect,
+            &args.name,
+            args.spectate,
+            app.world.resource::<config::Config>(),
+        );
+        app.add_plugins((RenetClientPlugin, NetcodeClientPlugin))
+            .insert_resource(client)
+            .insert_resource(transport);
+        if args.spectate {
+            app.insert_resource(player::Spectator);
+        }
+        if app.world.resource::<config::Config>().network.mode == NetworkMode::Lockstep {
+            warn!("Lockstep networking is experimental, players may drift apart.");
+            app.add_plugins(lockstep::LockstepPlugin).add_systems(
+                Update,
+                (lockstep::freeze_players, lockstep::run_lockstep_steps)
+                    .chain()
+                    .after(client_sync_players)
+                    .after(client_spawn_players)
+                    .before(player::update_sequence),
+            );
+        }
+    }
+
+    app.insert_resource(settings)
         .insert_resource(ClientLobby::default())
-        .insert_resource(client)
-        .insert_resource(transport)
         .insert_resource(RenetClientVisualizer::<200>::new(
             RenetVisualizerStyle::default(),
         ))
         .insert_resource(NetworkMapping::default())
-        .insert_resource(MostRecentTick::default())
+        .insert_resource(StateHashes::default())
+        .init_resource::<CurrentMap>()
         .add_event::<PlayerCommand>()
+        .add_event::<LoadMap>()
+        .add_event::<ReloadMap>()
         .add_systems(Startup, (setup_camera, generate_map))
         .add_systems(
             Update,
@@ -94,12 +166,30 @@ fn main() {
                     .run_if(client_connected()),
                 (
                     client_spawn_players,
-                    (player::player_input, player::update_crosshair).chain(),
-                    player::update_sequence,
+                    (
+                        player::player_input,
+                        player::update_crosshair,
+                        weapons::client_fire_weapon,
+                    )
+                        .chain(),
+                    (
+                        player::smooth_networked_yaw,
+                        player::update_sequence,
+                        player::update_animation_speed,
+                    )
+                        .chain(),
                 )
                     .after(client_sync_players),
-                update_visualizer_system,
-                panic_on_error_system,
+                triggers::launch_players
+                    .after(client_sync_players)
+                    .run_if(not(lockstep::lockstep_enabled)),
+                load_map.after(client_sync_players),
+                weapons::update_deploying,
+                weapons::recover_recoil,
+                player::cycle_spectate_target,
+                player::fade_despawning_players,
+                (update_visualizer_system, panic_on_error_system)
+                    .run_if(resource_exists::<RenetClient>()),
                 bevy::window::close_on_esc,
             ),
         )
@@ -107,6 +197,33 @@ fn main() {
         .run();
 }
 
+// Without a server the local player is spawned right away and simulated here
+fn spawn_offline_player(config: Res<config::Config>, mut spawn_events: EventWriter<SpawnPlayer>) {
+    spawn_events.send(SpawnPlayer {
+        id: ClientId::from_raw(0),
+        entity: Entity::PLACEHOLDER,
+        position: Vec3::new(0.0, player::PLAYER_SPAWN_HEIGHT, 0.0),
+        yaw: config.map.spawn_yaw,
+        is_local: true,
+        loadout: config.default_loadout.clone(),
+        team: Team::Nsf,
+    });
+}
+
+// Disconnecting sends right away, after that the connection is dropped so the transport
+// stops reporting errors for it
+fn leave_server(
+    commands: &mut Commands,
+    transport: &mut NetcodeClientTransport,
+    asset_server: &AssetServer,
+    notice: &str,
+) {
+    transport.disconnect();
+    commands.remove_resource::<RenetClient>();
+    commands.remove_resource::<NetcodeClientTransport>();
+    ui::spawn_notice(commands, asset_server, notice);
+}
+
 // If any error is found we just panic
 fn panic_on_error_system(mut renet_error: EventReader<NetcodeTransportError>) {
     for e in renet_error.read() {
@@ -131,10 +248,28 @@ fn update_visualizer_system(
 }
 
 fn client_send_input(
+    time: Res<Time>,
+    config: Res<config::Config>,
+    timestep: Res<PhysicsTimestep>,
+    mut pending: Local<f32>,
     player_query: Query<&PlayerInput, With<player::LocalPlayer>>,
     mut client: ResMut<RenetClient>,
 ) {
-    if let Ok(player_input) = player_query.get_single() {
+    let Ok(player_input) = player_query.get_single() else {
+        return;
+    };
+    // Every lockstep input is a step, so they go out once per timestep instead of
+    // once per frame to keep the game running at the same speed for everyone
+    let count = if config.network.mode == NetworkMode::Lockstep {
+        let step = lockstep::step_seconds(&timestep);
+        *pending = (*pending + time.delta_seconds()).min(step * MAX_LOCKSTEP_INPUTS as f32);
+        let count = (*pending / step) as u32;
+        *pending -= count as f32 * step;
+        count
+    } else {
+        1
+    };
+    for _ in 0..count {
         let input_message = bincode::serialize(player_input).unwrap();
         client.send_message(ClientChannel::Input, input_message);
     }
@@ -150,65 +285,337 @@ fn client_send_player_commands(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn client_sync_players(
     mut commands: Commands,
     mut client: ResMut<RenetClient>,
-    transport: Res<NetcodeClientTransport>,
+    mut transport: ResMut<NetcodeClientTransport>,
+    asset_server: Res<AssetServer>,
+    config: Res<config::Config>,
     mut lobby: ResMut<ClientLobby>,
     mut network_mapping: ResMut<NetworkMapping>,
+    entities: &Entities,
+    mut most_recent_tick: ResMut<MostRecentTick>,
     mut spawn_events: EventWriter<SpawnPlayer>,
+    mut hit_events: EventWriter<HitConfirmed>,
+    mut lockstep_steps: Option<ResMut<LockstepSteps>>,
+    mut state_hashes: ResMut<StateHashes>,
+    players: Query<(&Player, &player::Stance, &Transform)>,
+    mut inventories: Query<&mut Inventory>,
+    weapons: Query<&Weapon>,
 ) {
     let client_id = transport.client_id();
     while let Some(message) = client.receive_message(ServerChannel::ServerMessages) {
-        let server_message = bincode::deserialize(&message).unwrap();
-        match server_message {
-            ServerMessages::PlayerCreate {
-                id,
-                translation,
-                entity,
-            } => {
-                println!("Player {} connected.", id);
-                spawn_events.send(SpawnPlayer {
+        let Ok(server_messages) = bincode::deserialize::<Vec<ServerMessages>>(&message) else {
+            error!("Unable to read a message from the server, it's likely running an incompatible version.");
+            leave_server(
+                &mut commands,
+                &mut transport,
+                &asset_server,
+                "Server running incompatible version",
+            );
+            return;
+        };
+        for server_message in server_messages {
+            match server_message {
+                ServerMessages::IncompatibleVersion {
+                    protocol_version,
+                    build_version,
+                } => {
+                    error!(
+                        "Server is running version {} (protocol {}), this is {} (protocol {}).",
+                        build_version, protocol_version, BUILD_VERSION, PROTOCOL_VERSION
+                    );
+                    leave_server(
+                        &mut commands,
+                        &mut transport,
+                        &asset_server,
+                        &format!(
+                            "Server running incompatible version {}, this is {}",
+                            build_version, BUILD_VERSION
+                        ),
+                    );
+                    return;
+                }
+                ServerMessages::PlayerCreate {
                     id,
+                    translation,
+                    yaw,
                     entity,
-                    position: translation.into(),
-                    is_local: client_id == id.raw(),
-                });
-            }
-            ServerMessages::PlayerRemove { id } => {
-                println!("Player {} disconnected.", id);
-                if let Some(PlayerInfo {
-                    server_entity,
-                    client_entity,
-                }) = lobby.players.remove(&id)
-                {
-                    commands.entity(client_entity).despawn_recursive();
-                    network_mapping.0.remove(&server_entity);
+                    loadout,
+                    team,
+                } => {
+                    info!("Player {} connected.", id);
+                    spawn_events.send(SpawnPlayer {
+                        id,
+                        entity,
+                        position: translation.into(),
+                        yaw,
+                        is_local: client_id == id.raw(),
+                        loadout,
+                        team,
+                    });
+                }
+                ServerMessages::PlayerRemove { id } => {
+                    info!("Player {} disconnected.", id);
+                    remove_player(&mut commands, &mut lobby, &config, id);
+                }
+                ServerMessages::PlayerDamage { id, remaining, .. } => {
+                    if let Some(player_info) = lobby.players.get(&id) {
+                        commands.entity(player_info.client_entity).add(
+                            move |mut entity: EntityWorldMut| {
+                                if let Some(mut health) = entity.get_mut::<player::Health>() {
+                                    health.current = remaining;
+                                }
+                            },
+                        );
+                    }
+                }
+                ServerMessages::PlayerDeath { id, killer } => {
+                    match killer {
+                        Some(killer) => info!("Player {} was killed by {}.", id, killer),
+                        None => info!("Player {} died.", id),
+                    }
+                    remove_player(&mut commands, &mut lobby, &config, id);
+                    // Nothing left to control, so watch the others instead
+                    if id.raw() == client_id {
+                        commands.insert_resource(player::Spectator);
+                        let notice = match killer {
+                            Some(killer) => format!("Killed by player {}", killer),
+                            None => "You died".into(),
+                        };
+                        ui::spawn_notice(&mut commands, &asset_server, &notice);
+                    }
+                }
+                ServerMessages::HitConfirm { target, damage } => {
+                    if let Some(target) = network_mapping.0.get(&target) {
+                        hit_events.send(HitConfirmed {
+                            target: *target,
+                            damage,
+                        });
+                    }
+                }
+                ServerMessages::StateHash { tick, hash } => {
+                    if state_hashes.server(tick, hash) == Some(false) {
+                        request_resync(&mut client, &mut state_hashes, tick);
+                    }
+                }
+                ServerMessages::LockstepStep { tick, inputs } => {
+                    if let Some(lockstep_steps) = lockstep_steps.as_mut() {
+                        lockstep_steps.0.push_back((tick, inputs));
+                    }
+                }
+                ServerMessages::LoadMap { name, seed } => {
+                    commands.add(move |world: &mut World| {
+                        world.send_event(LoadMap {
+                            name,
+                            seed: Some(seed),
+                        });
+                    });
+                }
+                ServerMessages::ServerShutdown => {
+                    info!("Server shut down.");
+                    leave_server(
+                        &mut commands,
+                        &mut transport,
+                        &asset_server,
+                        "Server shut down",
+                    );
+                    return;
                 }
             }
         }
     }
 
     while let Some(message) = client.receive_message(ServerChannel::NetworkedEntities) {
-        let networked_entities: NetworkedEntities = bincode::deserialize(&message).unwrap();
+        // Frames from an incompatible server can't be read, it tells us so separately
+        let Ok(frame) = bincode::deserialize::<NetworkFrame>(&message) else {
+            continue;
+        };
+        if !receive_tick(&mut most_recent_tick, frame.tick) {
+            continue;
+        }
+        let networked_entities = frame.entities;
+        let mut applied = Vec::new();
+        // The server hashes every player it sent, so the frame can only be checked when
+        // all of them were applied to a player that's already spawned here
+        let mut complete = true;
 
         for i in 0..networked_entities.entities.len() {
-            if let Some(entity) = network_mapping.0.get(&networked_entities.entities[i]) {
-                let translation = networked_entities.translations[i].into();
-                let rotation = Quat::from_array(networked_entities.rotations[i]);
-                let transform = Transform {
-                    translation,
-                    rotation,
-                    ..Default::default()
-                };
-                let velocity = LinearVelocity(Vec3::from_array(networked_entities.velocities[i]));
-                let is_grounded = player::IsGrounded(networked_entities.groundeds[i]);
-                commands
-                    .entity(*entity)
-                    .insert(transform)
-                    .insert(velocity)
-                    .insert(is_grounded);
+            let server_entity = networked_entities.entities[i];
+            let Some(entity) = mapped_entity(&mut network_mapping, entities, server_entity) else {
+                complete = false;
+                continue;
+            };
+            let translation = networked_entities.translations[i].into();
+            let current = players.get(entity).ok();
+            // Facing is eased toward the networked yaw by smooth_networked_yaw
+            let rotation = current.map_or(
+                Quat::from_rotation_y(networked_entities.yaws[i]),
+                |(_, _, transform)| transform.rotation,
+            );
+            let transform = Transform {
+                translation,
+                rotation,
+                ..Default::default()
+            };
+            let velocity = LinearVelocity(Vec3::from_array(networked_entities.velocities[i]));
+            match current {
+                Some((player, _, _)) => applied.push((player.id, translation, velocity.0)),
+                // Created this frame, its components aren't there to query yet
+                None => complete = false,
+            }
+            let is_grounded = player::IsGrounded(networked_entities.groundeds[i]);
+            let jump_count = player::JumpCount {
+                count: networked_entities.jumps[i] as u32,
+                ..default()
+            };
+            let in_water = player::InWater(networked_entities.in_water[i]);
+            let stance = player::Stance {
+                crouching: networked_entities.crouchings[i],
+                walking: networked_entities.walkings[i],
+            };
+            commands
+                .entity(entity)
+                .insert(transform)
+                .insert(player::NetworkedYaw(networked_entities.yaws[i]))
+                .insert(velocity)
+                .insert(is_grounded)
+                .insert(jump_count)
+                .insert(in_water)
+                .insert(stance);
+            // Remote hitboxes follow crouching, swapped only on change since the
+            // physics engine rebuilds the body for a new collider
+            if current.is_some_and(|(_, current, _)| current.crouching != stance.crouching) {
+                commands.entity(entity).insert(stance.collider());
+            }
+
+            let active = networked_entities.weapons[i] as usize;
+            if let Ok(mut inventory) = inventories.get_mut(entity) {
+                if inventory.active != active {
+                    inventory.active = active;
+                    if let Some(kind) = inventory.active_kind(&weapons) {
+                        commands
+                            .entity(entity)
+                            .insert(Deploying::new(kind, &config));
+                    }
+                }
             }
         }
+
+        if complete
+            && frame.tick.is_multiple_of(STATE_HASH_INTERVAL)
+            && state_hashes.applied(frame.tick, state_hash(applied.into_iter())) == Some(false)
+        {
+            request_resync(&mut client, &mut state_hashes, frame.tick);
+        }
+    }
+}
+
+// Records the tick of a received frame, unless it's older than one already received.
+// Frames are unreliable and may arrive out of order, so stale ones are dropped.
+fn receive_tick(most_recent_tick: &mut MostRecentTick, tick: u32) -> bool {
+    if most_recent_tick
+        .0
+        .is_some_and(|most_recent| tick <= most_recent)
+    {
+        return false;
+    }
+    most_recent_tick.0 = Some(tick);
+    true
+}
+
+// The client entity a server entity maps to. Mappings to entities despawned some other
+// way than PlayerRemove are dropped.
+fn mapped_entity(
+    network_mapping: &mut NetworkMapping,
+    entities: &Entities,
+    server_entity: Entity,
+) -> Option<Entity> {
+    let entity = *network_mapping.0.get(&server_entity)?;
+    if !entities.contains(entity) {
+        network_mapping.0.remove(&server_entity);
+        return None;
+    }
+    Some(entity)
+}
+
+// Fades the player out, the mapping goes once it's gone in case frames still mention it
+fn remove_player(
+    commands: &mut Commands,
+    lobby: &mut ClientLobby,
+    config: &config::Config,
+    id: ClientId,
+) {
+    if let Some(PlayerInfo {
+        server_entity,
+        client_entity,
+    }) = lobby.players.remove(&id)
+    {
+        commands.entity(client_entity).insert(Despawning {
+            timer: Timer::from_seconds(config.ui.despawn_fade.max(0.0), TimerMode::Once),
+            server_entity,
+        });
+    }
+}
+
+// The frame didn't come out the way the server sent it, like when players were
+// applied to the wrong entities, so have the server send everyone again to start over
+fn request_resync(client: &mut RenetClient, state_hashes: &mut StateHashes, tick: u32) {
+    warn!(
+        "Players differ from the server at tick {}, resyncing.",
+        tick
+    );
+    state_hashes.clear();
+    let message = bincode::serialize(&PlayerCommand::RequestResync).unwrap();
+    client.send_message(ClientChannel::Command, message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn received_frames_advance_the_tick() {
+        let mut most_recent_tick = MostRecentTick::default();
+        assert!(receive_tick(&mut most_recent_tick, 5));
+        assert_eq!(most_recent_tick.0, Some(5));
+        assert!(receive_tick(&mut most_recent_tick, 7));
+        assert_eq!(most_recent_tick.0, Some(7));
+    }
+
+    #[test]
+    fn stale_frames_keep_the_tick() {
+        let mut most_recent_tick = MostRecentTick(Some(7));
+        assert!(!receive_tick(&mut most_recent_tick, 6));
+        assert!(!receive_tick(&mut most_recent_tick, 7));
+        assert_eq!(most_recent_tick.0, Some(7));
+    }
+
+    #[test]
+    fn despawned_entities_are_unmapped() {
+        let mut world = World::new();
+        let alive = world.spawn_empty().id();
+        let despawned = world.spawn_empty().id();
+        world.despawn(despawned);
+
+        let mut network_mapping = NetworkMapping::default();
+        network_mapping.0.insert(Entity::from_raw(1), alive);
+        network_mapping.0.insert(Entity::from_raw(2), despawned);
+
+        let entities = world.entities();
+        assert_eq!(
+            mapped_entity(&mut network_mapping, entities, Entity::from_raw(1)),
+            Some(alive)
+        );
+        assert_eq!(
+            mapped_entity(&mut network_mapping, entities, Entity::from_raw(2)),
+            None
+        );
+        assert!(!network_mapping.0.contains_key(&Entity::from_raw(2)));
+        assert_eq!(
+            mapped_entity(&mut network_mapping, entities, Entity::from_raw(3)),
+            None
+        );
     }
 }
@@ -0,0 +1,64 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use bevy::log::Level;
+
+use isotokyo::args::{exit_with_usage, next_value};
+
+const USAGE: &str = "\
+Usage: server [OPTIONS]
+
+Options:
+    --bind <ADDR>          Address to listen on [default: 127.0.0.1:5000]
+    --max-players <COUNT>  Maximum number of connected players [default: from config]
+    --headless             Run without a window or renderer
+    --map <PATH>           Load the map from a RON file instead of the config
+    --log-level <LEVEL>    Minimum level of messages to log [default: info]
+    --help                 Print this message";
+
+pub struct Args {
+    pub bind: SocketAddr,
+    pub max_players: Option<usize>,
+    pub headless: bool,
+    pub map: Option<PathBuf>,
+    pub log_level: Level,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            bind: "127.0.0.1:5000".parse().unwrap(),
+            max_players: None,
+            headless: false,
+            map: None,
+            log_level: Level::INFO,
+        }
+    }
+}
+
+impl Args {
+    pub fn parse() -> Self {
+        let mut args = std::env::args().skip(1);
+        let mut parsed = Self::default();
+        while let Some(arg) = args.next() {
+            let result = match arg.as_str() {
+                "--bind" => next_value(&mut args, &arg).map(|bind| parsed.bind = bind),
+                "--max-players" => next_value(&mut args, &arg)
+                    .map(|max_players| parsed.max_players = Some(max_players)),
+                "--headless" => {
+                    parsed.headless = true;
+                    Ok(())
+                }
+                "--map" => next_value(&mut args, &arg).map(|map| parsed.map = Some(map)),
+                "--log-level" => {
+                    next_value(&mut args, &arg).map(|log_level| parsed.log_level = log_level)
+                }
+                "--help" | "-h" => exit_with_usage(USAGE, None),
+                _ => Err(format!("Unknown argument '{}'", arg)),
+            };
+            if let Err(err) = result {
+                exit_with_usage(USAGE, Some(err));
+            }
+        }
+        parsed
+    }
+}
@@ -1,11 +1,29 @@
-use std::{net::UdpSocket, time::SystemTime};
+use std::{
+    collections::VecDeque,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
 
-use bevy::{prelude::*, utils::HashMap, window::PresentMode};
+use args::Args;
+use bevy::{
+    app::AppExit,
+    app::ScheduleRunnerPlugin,
+    log::LogPlugin,
+    prelude::*,
+    render::{settings::WgpuSettings, RenderPlugin},
+    utils::{HashMap, HashSet},
+    window::{ExitCondition, PresentMode, PrimaryWindow, WindowCloseRequested},
+    winit::WinitPlugin,
+};
 use bevy_egui::{EguiContexts, EguiPlugin};
 use bevy_renet::{
     renet::{
         transport::{NetcodeServerTransport, ServerAuthentication, ServerConfig},
-        ClientId, RenetServer, ServerEvent,
+        ClientId, NetworkInfo, RenetServer, ServerEvent,
     },
     transport::NetcodeServerPlugin,
     RenetServerPlugin,
@@ -15,42 +33,137 @@ use bevy_xpbd_3d::{
     plugins::{PhysicsDebugPlugin, PhysicsPlugins},
 };
 use isotokyo::{
-    config, generate_map,
-    networking::NetworkedEntities,
-    player::{self, server_spawn_player},
+    config::{self, Config, MapConfig, NetworkMode},
+    generate_map, load_map,
+    lockstep::lockstep_enabled,
+    networking::{NetworkFrame, NetworkedEntities},
+    player::{self, server_spawn_player, FallDamage, Health, InputQueue},
+    sprites::Sprite3dPlugin,
+    stats::StatsStore,
+    triggers,
+    weapons::{self, FireWeapon, Inventory, Weapon, WeaponHit},
+    CurrentMap, LoadMap, MainCamera, ReloadMap,
 };
 use isotokyo::{
     networking::{
-        connection_config, ClientChannel, Player, PlayerCommand, ServerChannel, ServerMessages,
-        PROTOCOL_ID,
+        connection_config, networked_yaw, state_hash, ClientChannel, Handshake, Player,
+        PlayerCommand, ServerChannel, ServerMessages, Team, BUILD_VERSION, PROTOCOL_ID,
+        PROTOCOL_VERSION, STATE_HASH_HISTORY, STATE_HASH_INTERVAL,
     },
     player::PlayerInput,
 };
 use renet_visualizer::RenetServerVisualizer;
 
+mod args;
+
 #[derive(Debug, Default, Resource)]
 pub struct ServerLobby {
     pub players: HashMap<ClientId, Entity>,
+    // Watching without a player, so their inputs and commands go nowhere
+    pub spectators: HashSet<ClientId>,
+    // Died and watching until they leave, kept apart so they don't take up the room
+    // left for spectators
+    pub dead: HashSet<ClientId>,
+    // Running another version, only sent why and disconnected the frame after
+    pub refused: HashSet<ClientId>,
 }
 
+impl ServerLobby {
+    // Dead players still hold the netcode slot they joined with until they leave, so
+    // they count against the player cap or joins would eat into the spectators' room
+    fn player_slots(&self) -> usize {
+        self.players.len() + self.dead.len()
+    }
+
+    // Netcode only caps players and spectators together, so each gets its own room here
+    fn is_full(&self, spectator: bool, max_players: usize, max_spectators: usize) -> bool {
+        if spectator {
+            self.spectators.len() >= max_spectators
+        } else {
+            self.player_slots() >= max_players
+        }
+    }
+}
+
+#[derive(Debug, Resource)]
+struct MaxPlayers(usize);
+
 #[derive(Debug, Default, Resource)]
 struct NetworkTick(u32);
 
+// Counts gathered since the last metrics report
+#[derive(Debug, Default, Resource)]
+struct ServerMetrics {
+    ticks: u32,
+    elapsed: f32,
+    // Inputs a client sent faster than they could be stepped
+    dropped_inputs: u32,
+}
+
+// How long clients get to leave on their own after being told the server is going down
+const SHUTDOWN_GRACE_PERIOD: f32 = 1.0;
+
+// Set from the Ctrl-C handler, the timer runs once clients have been told
+#[derive(Default, Resource)]
+struct Shutdown {
+    requested: Arc<AtomicBool>,
+    timer: Option<Timer>,
+}
+
+// Reliable messages queued during the frame and sent as one batch per client, so a
+// burst like a full lobby joining at once goes out together instead of one by one.
+// Messages without a client go to everyone.
+#[derive(Default, Resource)]
+struct ServerMessageQueue(Vec<(Option<ClientId>, ServerMessages)>);
+
+impl ServerMessageQueue {
+    fn send(&mut self, client_id: ClientId, message: ServerMessages) {
+        self.0.push((Some(client_id), message));
+    }
+
+    fn broadcast(&mut self, message: ServerMessages) {
+        self.0.push((None, message));
+    }
+}
+
+// Lockstep inputs waiting on the other players, and the first hash reported for each
+// checked step to compare the rest against
+#[derive(Debug, Default, Resource)]
+struct LockstepRelay {
+    tick: u32,
+    inputs: HashMap<ClientId, VecDeque<PlayerInput>>,
+    hashes: HashMap<u32, (ClientId, u64)>,
+}
+
+// Counts down to the next map in the rotation
+#[derive(Resource)]
+struct MapRotation {
+    timer: Timer,
+    next: usize,
+}
+
 // Clients last received ticks
 #[derive(Debug, Default, Resource)]
 struct ClientTicks(HashMap<u64, Option<u32>>);
 
-fn new_renet_server() -> (RenetServer, NetcodeServerTransport) {
-    let server = RenetServer::new(connection_config());
+#[derive(Debug, Default, Resource)]
+struct ClientInputs(HashMap<ClientId, InputQueue>);
+
+fn new_renet_server(
+    public_addr: SocketAddr,
+    max_clients: usize,
+    config: &Config,
+) -> (RenetServer, NetcodeServerTransport) {
+    let server = RenetServer::new(connection_config(&config.network));
+    let max_clients = max_clients + config.max_spectators;
 
-    let public_addr = "127.0.0.1:5000".parse().unwrap();
     let socket = UdpSocket::bind(public_addr).unwrap();
     let current_time: std::time::Duration = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap();
     let server_config = ServerConfig {
         current_time,
-        max_clients: 64,
+        max_clients,
         protocol_id: PROTOCOL_ID,
         public_addresses: vec![public_addr],
         authentication: ServerAuthentication::Unsecure,
@@ -62,11 +175,39 @@ fn new_renet_server() -> (RenetServer, NetcodeServerTransport) {
 }
 
 fn main() {
-    let (client, transport) = new_renet_server();
-    App::new()
-        .add_plugins((
+    let args = Args::parse();
+    let mut app = App::new();
+    if args.headless {
+        // No window and no GPU, just tick the simulation at a fixed rate
+        app.add_plugins((
+            DefaultPlugins
+                .set(LogPlugin {
+                    level: args.log_level,
+                    ..default()
+                })
+                .set(WindowPlugin {
+                    primary_window: None,
+                    exit_condition: ExitCondition::DontExit,
+                    ..default()
+                })
+                .set(RenderPlugin {
+                    render_creation: WgpuSettings {
+                        backends: None,
+                        ..default()
+                    }
+                    .into(),
+                })
+                .disable::<WinitPlugin>(),
+            ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(1.0 / 60.0)),
+        ));
+    } else {
+        app.add_plugins(
             DefaultPlugins
                 .set(ImagePlugin::default_nearest())
+                .set(LogPlugin {
+                    level: args.log_level,
+                    ..default()
+                })
                 .set(WindowPlugin {
                     primary_window: Some(Window {
                         title: "Isotokyo Server".into(),
@@ -74,34 +215,124 @@ fn main() {
                         present_mode: PresentMode::Mailbox,
                         ..default()
                     }),
+                    // Closing the window shuts down like Ctrl-C does
+                    close_when_requested: false,
                     ..default()
                 }),
-            RenetServerPlugin,
-            NetcodeServerPlugin,
-            PhysicsPlugins::default(),
-            PhysicsDebugPlugin::default(),
-            EguiPlugin,
-            config::ConfigPlugin,
-            player::ServerPlayerPlugin,
-        ))
-        .insert_resource(ClearColor(Color::rgb(0.125, 0.125, 0.125)))
+        );
+    }
+    app.add_plugins((
+        RenetServerPlugin,
+        NetcodeServerPlugin,
+        PhysicsPlugins::default(),
+        config::ConfigPlugin::default(),
+        player::ServerPlayerPlugin,
+    ));
+    if !args.headless {
+        app.add_plugins((PhysicsDebugPlugin::default(), EguiPlugin, Sprite3dPlugin))
+            .add_systems(Startup, setup_simple_camera)
+            .add_systems(
+                Update,
+                (
+                    update_visualizer_system,
+                    reload_map_on_key.before(load_map),
+                    player::attach_player_visuals,
+                    (player::update_sequence, player::update_animation_speed).chain(),
+                ),
+            );
+    }
+    if let Some(path) = &args.map {
+        match MapConfig::load(path) {
+            Ok(map) => app.world.resource_mut::<Config>().map = map,
+            Err(err) => {
+                error!("Failed to load map '{}': {}", path.display(), err);
+                std::process::exit(1);
+            }
+        }
+    }
+    let max_players = args
+        .max_players
+        .unwrap_or(app.world.resource::<Config>().max_players);
+    let (server, transport) =
+        new_renet_server(args.bind, max_players, app.world.resource::<Config>());
+    if let Some(path) = app.world.resource::<Config>().stats_path.clone() {
+        app.insert_resource(StatsStore::load(&path));
+    }
+
+    let map_rotation = MapRotation {
+        timer: Timer::from_seconds(
+            app.world.resource::<Config>().round_time.max(0.0),
+            TimerMode::Repeating,
+        ),
+        next: 0,
+    };
+
+    let shutdown = Shutdown::default();
+    let requested = shutdown.requested.clone();
+    if let Err(err) = ctrlc::set_handler(move || requested.store(true, Ordering::SeqCst)) {
+        warn!(
+            "Unable to handle Ctrl-C, clients won't be told when the server stops: {}",
+            err
+        );
+    }
+
+    app.insert_resource(ClearColor(Color::rgb(0.125, 0.125, 0.125)))
         .insert_resource(ServerLobby::default())
+        .insert_resource(MaxPlayers(max_players))
         .insert_resource(NetworkTick(0))
         .insert_resource(ClientTicks::default())
-        .insert_resource(client)
+        .insert_resource(ClientInputs::default())
+        .insert_resource(shutdown)
+        .insert_resource(ServerMetrics::default())
+        .insert_resource(ServerMessageQueue::default())
+        .insert_resource(LockstepRelay::default())
+        .insert_resource(map_rotation)
+        .init_resource::<CurrentMap>()
+        .insert_resource(server)
         .insert_resource(transport)
         .insert_resource(RenetServerVisualizer::<200>::default())
-        .add_systems(Startup, (generate_map, setup_simple_camera))
+        .add_event::<FireWeapon>()
+        .add_event::<WeaponHit>()
+        .add_event::<LoadMap>()
+        .add_event::<ReloadMap>()
+        .add_systems(Startup, generate_map)
         .add_systems(
             Update,
             (
                 (
+                    disconnect_refused,
                     server_update_system,
+                    server_receive_messages,
+                    weapons::switch_weapons,
+                    weapons::server_fire_weapons,
+                    triggers::check_water,
                     player::player_move,
-                    server_network_sync,
+                    triggers::teleport_players,
+                    triggers::launch_players,
+                    // Lockstep clients simulate movement themselves
+                    server_network_sync.run_if(not(lockstep_enabled)),
                 )
                     .chain(),
-                update_visualizer_system,
+                server_confirm_hits.after(weapons::server_fire_weapons),
+                apply_damage
+                    .after(weapons::server_fire_weapons)
+                    .after(player::apply_fall_damage),
+                player::apply_fall_damage.after(player::player_move),
+                weapons::update_deploying,
+                triggers::update_teleport_cooldowns,
+                update_window_title,
+                report_metrics,
+                track_player_stats
+                    .after(server_update_system)
+                    .run_if(resource_exists::<StatsStore>()),
+                shutdown_server,
+                (rotate_maps, load_map, announce_map).chain(),
+                send_server_messages
+                    .after(server_network_sync)
+                    .after(announce_map)
+                    .after(server_confirm_hits)
+                    .after(apply_damage)
+                    .after(shutdown_server),
             ),
         )
         .run();
@@ -114,77 +345,638 @@ fn server_update_system(
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
     mut lobby: ResMut<ServerLobby>,
+    max_players: Res<MaxPlayers>,
     mut server: ResMut<RenetServer>,
+    mut messages: ResMut<ServerMessageQueue>,
+    transport: Res<NetcodeServerTransport>,
     mut visualizer: ResMut<RenetServerVisualizer<200>>,
-    players: Query<(Entity, &Player, &Transform)>,
+    mut client_ticks: ResMut<ClientTicks>,
+    config: Res<Config>,
+    current_map: Res<CurrentMap>,
+    players: Query<(Entity, &Player, &Transform, &Inventory)>,
+    weapons: Query<&Weapon>,
 ) {
+    // Players spawned this frame aren't in the query yet, so they're counted as they join
+    let mut jinrai = players
+        .iter()
+        .filter(|(_, player, _, _)| player.team == Team::Jinrai)
+        .count();
+    let mut nsf = players.iter().count() - jinrai;
     for event in server_events.read() {
         match event {
             ServerEvent::ClientConnected { client_id } => {
-                println!("Player {} connected.", client_id);
+                let handshake = transport.user_data(*client_id).map_or_else(
+                    || Handshake::new("", false),
+                    |user_data| Handshake::from_user_data(&user_data),
+                );
+                let name = handshake.name.clone();
+                // The client leaves once it's told, instead of failing on messages it
+                // can't read
+                if let Some(rejection) = handshake.rejection() {
+                    warn!(
+                        "Player {} ({}) refused, running version {} (protocol {}) against {} (protocol {}).",
+                        client_id,
+                        name,
+                        handshake.build_version,
+                        handshake.protocol_version,
+                        BUILD_VERSION,
+                        PROTOCOL_VERSION
+                    );
+                    messages.send(*client_id, rejection);
+                    lobby.refused.insert(*client_id);
+                    continue;
+                }
+                let full = lobby.is_full(handshake.spectator, max_players.0, config.max_spectators);
+                if handshake.spectator {
+                    if full {
+                        warn!(
+                            "Spectator {} ({}) refused, no room to watch ({}/{}).",
+                            client_id,
+                            name,
+                            lobby.spectators.len(),
+                            config.max_spectators
+                        );
+                        server.disconnect(*client_id);
+                        continue;
+                    }
+                    info!(
+                        "Spectator {} ({}) connected ({}/{}).",
+                        client_id,
+                        name,
+                        lobby.spectators.len() + 1,
+                        config.max_spectators
+                    );
+                    lobby.spectators.insert(*client_id);
+                } else {
+                    if full {
+                        warn!(
+                            "Player {} ({}) refused, the server is full ({}/{}).",
+                            client_id,
+                            name,
+                            lobby.player_slots(),
+                            max_players.0
+                        );
+                        server.disconnect(*client_id);
+                        continue;
+                    }
+                    info!(
+                        "Player {} ({}) connected ({}/{}).",
+                        client_id,
+                        name,
+                        lobby.player_slots() + 1,
+                        max_players.0
+                    );
+                }
                 visualizer.add_client(*client_id);
 
+                if let Some(name) = &current_map.0 {
+                    messages.send(
+                        *client_id,
+                        ServerMessages::LoadMap {
+                            name: name.clone(),
+                            seed: config.map.seed,
+                        },
+                    );
+                }
+
                 // Initialize other players for this new client
-                for (entity, player, transform) in players.iter() {
-                    let translation: [f32; 3] = transform.translation.into();
-                    let message = bincode::serialize(&ServerMessages::PlayerCreate {
-                        id: player.id,
-                        entity,
-                        translation,
-                    })
-                    .unwrap();
-                    server.send_message(*client_id, ServerChannel::ServerMessages, message);
+                send_players(&mut messages, *client_id, &players, &weapons);
+
+                if handshake.spectator {
+                    continue;
                 }
 
+                // Join the smaller team, Jinrai when they're even
+                let team = if jinrai <= nsf {
+                    jinrai += 1;
+                    Team::Jinrai
+                } else {
+                    nsf += 1;
+                    Team::Nsf
+                };
+
                 // Spawn new player
-                let transform = Transform::from_xyz(0.0, 0.51, 0.0);
+                let transform = spawn_transform(&config);
                 let player_entity = server_spawn_player(
                     &mut commands,
+                    &config,
                     &mut materials,
                     &mut meshes,
                     *client_id,
+                    team,
                     transform,
+                    &config.default_loadout,
                 );
 
                 lobby.players.insert(*client_id, player_entity);
 
                 let translation: [f32; 3] = transform.translation.into();
-                let message = bincode::serialize(&ServerMessages::PlayerCreate {
+                messages.broadcast(ServerMessages::PlayerCreate {
                     id: *client_id,
                     entity: player_entity,
                     translation,
-                })
-                .unwrap();
-                server.broadcast_message(ServerChannel::ServerMessages, message);
+                    yaw: config.map.spawn_yaw,
+                    loadout: config.default_loadout.clone(),
+                    team,
+                });
             }
             ServerEvent::ClientDisconnected { client_id, reason } => {
-                println!("Player {} disconnected: {}", client_id, reason);
+                info!("Player {} disconnected: {}", client_id, reason);
                 visualizer.remove_client(*client_id);
+                client_ticks.0.remove(&client_id.raw());
+                lobby.spectators.remove(client_id);
+                lobby.dead.remove(client_id);
+                lobby.refused.remove(client_id);
+                // Refused clients never got a player
                 if let Some(player_entity) = lobby.players.remove(client_id) {
-                    commands.entity(player_entity).despawn();
-                }
+                    commands.entity(player_entity).despawn_recursive();
 
-                let message =
-                    bincode::serialize(&ServerMessages::PlayerRemove { id: *client_id }).unwrap();
-                server.broadcast_message(ServerChannel::ServerMessages, message);
+                    messages.broadcast(ServerMessages::PlayerRemove { id: *client_id });
+                }
             }
         }
     }
+}
 
+#[allow(clippy::too_many_arguments)]
+fn server_receive_messages(
+    mut commands: Commands,
+    mut server: ResMut<RenetServer>,
+    mut messages: ResMut<ServerMessageQueue>,
+    lobby: Res<ServerLobby>,
+    mut client_ticks: ResMut<ClientTicks>,
+    mut client_inputs: ResMut<ClientInputs>,
+    mut metrics: ResMut<ServerMetrics>,
+    mut relay: ResMut<LockstepRelay>,
+    config: Res<Config>,
+    mut fire_events: EventWriter<FireWeapon>,
+    players: Query<(Entity, &Player, &Transform, &Inventory)>,
+    weapons: Query<&Weapon>,
+) {
+    let lockstep = config.network.mode == NetworkMode::Lockstep;
     for client_id in server.clients_id() {
+        // Refused clients may be running anything, so nothing they send can be trusted
+        if lobby.refused.contains(&client_id) {
+            continue;
+        }
         while let Some(message) = server.receive_message(client_id, ClientChannel::Command) {
-            let command: PlayerCommand = bincode::deserialize(&message).unwrap();
+            let Ok(command) = bincode::deserialize::<PlayerCommand>(&message) else {
+                warn!("Ignoring a malformed command from client {}.", client_id);
+                continue;
+            };
             match command {
-                _ => (),
+                PlayerCommand::BasicAttack { cast_at } => {
+                    if let Some(player_entity) = lobby.players.get(&client_id) {
+                        fire_events.send(FireWeapon {
+                            entity: *player_entity,
+                            cast_at,
+                        });
+                    }
+                }
+                PlayerCommand::StateHash { tick, hash } => {
+                    check_state_hash(&mut relay, client_id, tick, hash);
+                }
+                PlayerCommand::RequestResync => {
+                    info!(
+                        "Sending every player to {} again after a desync.",
+                        client_id
+                    );
+                    send_players(&mut messages, client_id, &players, &weapons);
+                }
             }
         }
+        let player_entity = lobby.players.get(&client_id);
+        let queue = client_inputs.0.entry(client_id).or_default();
         while let Some(message) = server.receive_message(client_id, ClientChannel::Input) {
-            let input: PlayerInput = bincode::deserialize(&message).unwrap();
-            if let Some(player_entity) = lobby.players.get(&client_id) {
+            let Ok(input) = bincode::deserialize::<PlayerInput>(&message) else {
+                warn!("Ignoring a malformed input from client {}.", client_id);
+                continue;
+            };
+            client_ticks
+                .0
+                .insert(client_id.raw(), input.most_recent_tick);
+            let is_player = player_entity.is_some();
+            if route_input(client_id, is_player, input, lockstep, queue, &mut relay) {
+                metrics.dropped_inputs += 1;
+            }
+        }
+        // Movement steps once per tick with only a few of the queued inputs, so
+        // sending them faster than the tick rate doesn't move a player any faster
+        if let Some(player_entity) = player_entity {
+            if let Some(input) = queue.next_tick() {
                 commands.entity(*player_entity).insert(input);
             }
         }
     }
+    client_inputs
+        .0
+        .retain(|client_id, _| lobby.players.contains_key(client_id));
+
+    if lockstep {
+        relay_lockstep_steps(&lobby, &mut relay, &mut messages);
+    }
+}
+
+// Queues the input for the player's next tick, or for the relay in lockstep where every
+// input is a step of its own and none can be merged away. Spectators and the dead have
+// nothing to move. Returns whether an older queued input had to be dropped.
+fn route_input(
+    client_id: ClientId,
+    is_player: bool,
+    input: PlayerInput,
+    lockstep: bool,
+    queue: &mut InputQueue,
+    relay: &mut LockstepRelay,
+) -> bool {
+    if !is_player {
+        return false;
+    }
+    if lockstep {
+        relay.inputs.entry(client_id).or_default().push_back(input);
+        return false;
+    }
+    queue.push(input)
+}
+
+// Players simulating the same steps should end up with the same hash, any difference
+// means they've drifted apart and won't come back together on their own
+fn check_state_hash(relay: &mut LockstepRelay, client_id: ClientId, tick: u32, hash: u64) {
+    match relay.hashes.get(&tick) {
+        Some((first_id, first_hash)) if *first_hash != hash => {
+            warn!(
+                "Lockstep desync at step {}, player {} disagrees with player {}.",
+                tick, client_id, first_id
+            );
+        }
+        Some(_) => {}
+        None => {
+            relay.hashes.insert(tick, (client_id, hash));
+        }
+    }
+}
+
+// Sends out a step for every input received from all players, in client id order so
+// everyone applies them the same way
+fn relay_lockstep_steps(
+    lobby: &ServerLobby,
+    relay: &mut LockstepRelay,
+    messages: &mut ServerMessageQueue,
+) {
+    relay.inputs.retain(|id, _| lobby.players.contains_key(id));
+    while !lobby.players.is_empty()
+        && lobby.players.keys().all(|id| {
+            relay
+                .inputs
+                .get(id)
+                .is_some_and(|inputs| !inputs.is_empty())
+        })
+    {
+        let mut inputs: Vec<_> = relay
+            .inputs
+            .iter_mut()
+            .filter_map(|(id, inputs)| inputs.pop_front().map(|input| (*id, input)))
+            .collect();
+        inputs.sort_unstable_by_key(|(id, _)| id.raw());
+        relay.tick += 1;
+        messages.broadcast(ServerMessages::LockstepStep {
+            tick: relay.tick,
+            inputs,
+        });
+    }
+    let tick = relay.tick;
+    relay
+        .hashes
+        .retain(|hash_tick, _| hash_tick + STATE_HASH_HISTORY > tick);
+}
+
+fn spawn_transform(config: &Config) -> Transform {
+    Transform::from_xyz(0.0, player::PLAYER_SPAWN_HEIGHT, 0.0)
+        .with_rotation(Quat::from_rotation_y(config.map.spawn_yaw))
+}
+
+// Rebuilds the map for editing it while the server runs, clients follow when it's from
+// a map file
+fn reload_map_on_key(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut reload_events: EventWriter<ReloadMap>,
+) {
+    if keyboard_input.just_pressed(KeyCode::F11) {
+        reload_events.send(ReloadMap);
+    }
+}
+
+fn rotate_maps(
+    time: Res<Time>,
+    config: Res<Config>,
+    mut rotation: ResMut<MapRotation>,
+    mut load_events: EventWriter<LoadMap>,
+) {
+    if config.map_rotation.is_empty() || config.round_time <= 0.0 {
+        return;
+    }
+    if !rotation.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    let name = config.map_rotation[rotation.next % config.map_rotation.len()].clone();
+    rotation.next += 1;
+    load_events.send(LoadMap { name, seed: None });
+}
+
+// Once the next map is in, clients are told to load the same one and everyone starts
+// over at the spawn
+fn announce_map(
+    config: Res<Config>,
+    current_map: Res<CurrentMap>,
+    mut messages: ResMut<ServerMessageQueue>,
+    mut players: Query<(&mut Transform, &mut LinearVelocity), With<Player>>,
+) {
+    if !current_map.is_changed() {
+        return;
+    }
+    let Some(name) = &current_map.0 else {
+        return;
+    };
+    messages.broadcast(ServerMessages::LoadMap {
+        name: name.clone(),
+        seed: config.map.seed,
+    });
+    for (mut transform, mut velocity) in players.iter_mut() {
+        *transform = spawn_transform(&config);
+        velocity.0 = Vec3::ZERO;
+    }
+}
+
+fn send_players(
+    messages: &mut ServerMessageQueue,
+    client_id: ClientId,
+    players: &Query<(Entity, &Player, &Transform, &Inventory)>,
+    weapons: &Query<&Weapon>,
+) {
+    for (entity, player, transform, inventory) in players.iter() {
+        let translation: [f32; 3] = transform.translation.into();
+        let yaw = networked_yaw(transform.rotation);
+        messages.send(
+            client_id,
+            ServerMessages::PlayerCreate {
+                id: player.id,
+                entity,
+                translation,
+                yaw,
+                loadout: inventory.loadout(weapons),
+                team: player.team,
+            },
+        );
+    }
+}
+
+fn track_player_stats(
+    time: Res<Time>,
+    mut server_events: EventReader<ServerEvent>,
+    transport: Res<NetcodeServerTransport>,
+    lobby: Res<ServerLobby>,
+    mut stats: ResMut<StatsStore>,
+) {
+    for event in server_events.read() {
+        match event {
+            ServerEvent::ClientConnected { client_id } => {
+                // Refused clients never got a player
+                if !lobby.players.contains_key(client_id) {
+                    continue;
+                }
+                let name = transport
+                    .user_data(*client_id)
+                    .map(|user_data| Handshake::from_user_data(&user_data).name)
+                    .unwrap_or_default();
+                let player_stats = stats.connect(*client_id, &name, time.elapsed_seconds_f64());
+                info!(
+                    "Player {} has {} kills, {} deaths and {:.0} minutes played.",
+                    name,
+                    player_stats.kills,
+                    player_stats.deaths,
+                    player_stats.playtime / 60.0
+                );
+            }
+            ServerEvent::ClientDisconnected { client_id, .. } => {
+                stats.disconnect(*client_id, time.elapsed_seconds_f64());
+            }
+        }
+    }
+}
+
+fn update_window_title(
+    lobby: Res<ServerLobby>,
+    max_players: Res<MaxPlayers>,
+    mut windows: Query<&mut Window, With<PrimaryWindow>>,
+) {
+    if !lobby.is_changed() {
+        return;
+    }
+    for mut window in windows.iter_mut() {
+        window.title = format!(
+            "Isotokyo Server ({}/{})",
+            lobby.player_slots(),
+            max_players.0
+        );
+    }
+}
+
+fn server_confirm_hits(
+    mut messages: ResMut<ServerMessageQueue>,
+    config: Res<Config>,
+    mut hit_events: EventReader<WeaponHit>,
+    players: Query<&Player>,
+) {
+    for hit in hit_events.read() {
+        if let (Ok(attacker), Some(weapon)) =
+            (players.get(hit.attacker), config.weapons.get(&hit.kind))
+        {
+            messages.send(
+                attacker.id,
+                ServerMessages::HitConfirm {
+                    target: hit.target,
+                    damage: weapon.damage,
+                },
+            );
+        }
+    }
+}
+
+// Players out of health are removed from the game but stay connected, watching like
+// spectators until they leave
+#[allow(clippy::too_many_arguments)]
+fn apply_damage(
+    mut commands: Commands,
+    config: Res<Config>,
+    mut lobby: ResMut<ServerLobby>,
+    mut messages: ResMut<ServerMessageQueue>,
+    mut stats: Option<ResMut<StatsStore>>,
+    mut hit_events: EventReader<WeaponHit>,
+    mut fall_damage: EventReader<FallDamage>,
+    mut players: Query<(&Player, &mut Health)>,
+) {
+    let hits = hit_events.read().filter_map(|hit| {
+        config
+            .weapons
+            .get(&hit.kind)
+            .map(|weapon| (hit.target, weapon.damage, Some(hit.attacker)))
+    });
+    let falls = fall_damage
+        .read()
+        .map(|fall| (fall.target, fall.damage, None));
+    for (target, amount, attacker) in hits.chain(falls) {
+        let killer = attacker
+            .and_then(|attacker| players.get(attacker).ok())
+            .map(|(player, _)| player.id);
+        let Ok((player, mut health)) = players.get_mut(target) else {
+            continue;
+        };
+        // Already died to an earlier hit this frame
+        if health.current <= 0.0 {
+            continue;
+        }
+        let id = player.id;
+        health.current = (health.current - amount).max(0.0);
+        messages.broadcast(ServerMessages::PlayerDamage {
+            id,
+            amount,
+            remaining: health.current,
+        });
+        if health.current > 0.0 {
+            continue;
+        }
+
+        match killer {
+            Some(killer) => info!("Player {} was killed by {}.", id, killer),
+            None => info!("Player {} died.", id),
+        }
+        messages.broadcast(ServerMessages::PlayerDeath { id, killer });
+        commands.entity(target).despawn_recursive();
+        lobby.players.remove(&id);
+        lobby.dead.insert(id);
+        if let Some(stats) = stats.as_mut() {
+            if let Some(player_stats) = stats.get_mut(id) {
+                player_stats.deaths += 1;
+            }
+            if let Some(player_stats) = killer
+                .filter(|killer| *killer != id)
+                .and_then(|killer| stats.get_mut(killer))
+            {
+                player_stats.kills += 1;
+            }
+        }
+    }
+}
+
+// Runs before new clients are handled, so anyone refused last frame has had the batch
+// telling them why sent already
+fn disconnect_refused(mut server: ResMut<RenetServer>, lobby: Res<ServerLobby>) {
+    for client_id in &lobby.refused {
+        server.disconnect(*client_id);
+    }
+}
+
+fn send_server_messages(
+    mut server: ResMut<RenetServer>,
+    lobby: Res<ServerLobby>,
+    mut messages: ResMut<ServerMessageQueue>,
+) {
+    if messages.0.is_empty() {
+        return;
+    }
+    for client_id in server.clients_id() {
+        let refused = lobby.refused.contains(&client_id);
+        let batch: Vec<&ServerMessages> = messages
+            .0
+            .iter()
+            .filter(|(recipient, _)| match recipient {
+                Some(recipient) => *recipient == client_id,
+                None => !refused,
+            })
+            .map(|(_, message)| message)
+            .collect();
+        if batch.is_empty() {
+            continue;
+        }
+        let message = bincode::serialize(&batch).unwrap();
+        server.send_message(client_id, ServerChannel::ServerMessages, message);
+    }
+    messages.0.clear();
+}
+
+// Periodic summary for operators, as key=value pairs so it's easy to grep and parse
+fn report_metrics(
+    time: Res<Time>,
+    config: Res<Config>,
+    server: Res<RenetServer>,
+    mut metrics: ResMut<ServerMetrics>,
+    entities: Query<(), ()>,
+    players: Query<(), With<Player>>,
+) {
+    if config.metrics_interval <= 0.0 {
+        return;
+    }
+    metrics.ticks += 1;
+    metrics.elapsed += time.delta_seconds();
+    if metrics.elapsed < config.metrics_interval {
+        return;
+    }
+
+    let network_infos: Vec<_> = server
+        .clients_id()
+        .into_iter()
+        .filter_map(|client_id| server.network_info(client_id).ok())
+        .collect();
+    let client_count = network_infos.len().max(1) as f64;
+    let average = |value: fn(&NetworkInfo) -> f64| {
+        network_infos.iter().map(value).sum::<f64>() / client_count
+    };
+    info!(
+        target: "metrics",
+        "players={} ticks_per_second={:.1} sent_per_client={:.0}B/s received_per_client={:.0}B/s dropped_inputs={} entities={} player_entities={}",
+        server.connected_clients(),
+        metrics.ticks as f32 / metrics.elapsed,
+        average(|info| info.bytes_sent_per_second),
+        average(|info| info.bytes_received_per_second),
+        metrics.dropped_inputs,
+        entities.iter().count(),
+        players.iter().count(),
+    );
+    *metrics = ServerMetrics::default();
+}
+
+#[allow(clippy::too_many_arguments)]
+fn shutdown_server(
+    time: Res<Time>,
+    mut shutdown: ResMut<Shutdown>,
+    mut close_requests: EventReader<WindowCloseRequested>,
+    mut server: ResMut<RenetServer>,
+    mut transport: ResMut<NetcodeServerTransport>,
+    mut messages: ResMut<ServerMessageQueue>,
+    stats: Option<ResMut<StatsStore>>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let close_requested = close_requests.read().count() > 0;
+    let Some(timer) = shutdown.timer.as_mut() else {
+        if close_requested || shutdown.requested.load(Ordering::SeqCst) {
+            info!(
+                "Shutting down, {} players connected.",
+                server.connected_clients()
+            );
+            messages.broadcast(ServerMessages::ServerShutdown);
+            shutdown.timer = Some(Timer::from_seconds(SHUTDOWN_GRACE_PERIOD, TimerMode::Once));
+        }
+        return;
+    };
+
+    if timer.tick(time.delta()).finished() || server.connected_clients() == 0 {
+        // Kick whoever is left, sent right away since the app won't update again
+        transport.disconnect_all(&mut server);
+        if let Some(mut stats) = stats {
+            stats.disconnect_all(time.elapsed_seconds_f64());
+        }
+        info!("Server stopped.");
+        exit.send(AppExit);
+    }
 }
 
 fn update_visualizer_system(
@@ -199,27 +991,180 @@ fn update_visualizer_system(
 #[allow(clippy::type_complexity)]
 fn server_network_sync(
     mut server: ResMut<RenetServer>,
-    query: Query<(Entity, &Transform, &LinearVelocity, &player::IsGrounded), With<Player>>,
+    lobby: Res<ServerLobby>,
+    mut messages: ResMut<ServerMessageQueue>,
+    mut tick: ResMut<NetworkTick>,
+    query: Query<
+        (
+            Entity,
+            &Player,
+            &Transform,
+            &LinearVelocity,
+            &player::IsGrounded,
+            &player::JumpCount,
+            &player::InWater,
+            &Inventory,
+            &player::Stance,
+        ),
+        With<Player>,
+    >,
 ) {
     let mut networked_entities = NetworkedEntities::default();
-    for (entity, transform, velocity, is_grounded) in query.iter() {
+    for (entity, _, transform, velocity, is_grounded, jump_count, in_water, inventory, stance) in
+        query.iter()
+    {
         networked_entities.entities.push(entity);
         networked_entities
             .translations
             .push(transform.translation.into());
-        networked_entities.rotations.push(transform.rotation.into());
+        networked_entities
+            .yaws
+            .push(networked_yaw(transform.rotation));
         networked_entities.velocities.push(velocity.to_array());
         networked_entities.groundeds.push(is_grounded.0);
+        networked_entities
+            .jumps
+            .push(jump_count.count.min(u8::MAX as u32) as u8);
+        networked_entities.in_water.push(in_water.0);
+        networked_entities.weapons.push(inventory.active as u8);
+        networked_entities.crouchings.push(stance.crouching);
+        networked_entities.walkings.push(stance.walking);
     }
 
-    let sync_message = bincode::serialize(&networked_entities).unwrap();
-    server.broadcast_message(ServerChannel::NetworkedEntities, sync_message);
+    tick.0 += 1;
+    // Lets clients check they applied the frame the same way, sent reliably so it's
+    // there whenever the frame makes it
+    if tick.0.is_multiple_of(STATE_HASH_INTERVAL) {
+        let hash = state_hash(query.iter().map(|(_, player, transform, velocity, ..)| {
+            (player.id, transform.translation, velocity.0)
+        }));
+        messages.broadcast(ServerMessages::StateHash { tick: tick.0, hash });
+    }
+    let frame = NetworkFrame {
+        tick: tick.0,
+        entities: networked_entities,
+    };
+    let sync_message = bincode::serialize(&frame).unwrap();
+    for client_id in server.clients_id() {
+        if !lobby.refused.contains(&client_id) {
+            server.send_message(
+                client_id,
+                ServerChannel::NetworkedEntities,
+                sync_message.clone(),
+            );
+        }
+    }
 }
 
 pub fn setup_simple_camera(mut commands: Commands) {
     // camera
-    commands.spawn(Camera3dBundle {
-        transform: Transform::from_xyz(10.0, 10.0, 10.0).looking_at(Vec3::ZERO, Vec3::Y),
-        ..Default::default()
-    });
+    commands
+        .spawn(Camera3dBundle {
+            transform: Transform::from_xyz(10.0, 10.0, 10.0).looking_at(Vec3::ZERO, Vec3::Y),
+            ..Default::default()
+        })
+        .insert(MainCamera);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use isotokyo::config::FallDamageConfig;
+    use isotokyo::player::Landed;
+
+    #[test]
+    fn joining_past_the_player_limit_is_refused() {
+        let mut lobby = ServerLobby::default();
+        lobby
+            .players
+            .insert(ClientId::from_raw(1), Entity::PLACEHOLDER);
+        assert!(!lobby.is_full(false, 2, 1));
+        // Dead players keep their slot until they leave
+        lobby.dead.insert(ClientId::from_raw(2));
+        assert!(lobby.is_full(false, 2, 1));
+        // Spectators have their own room
+        assert!(!lobby.is_full(true, 2, 1));
+        lobby.spectators.insert(ClientId::from_raw(3));
+        assert!(lobby.is_full(true, 2, 1));
+        lobby.dead.remove(&ClientId::from_raw(2));
+        assert!(!lobby.is_full(false, 2, 1));
+    }
+
+    #[test]
+    fn players_spawn_facing_the_map_yaw() {
+        let mut config = Config::default();
+        config.map.spawn_yaw = std::f32::consts::FRAC_PI_2;
+        let transform = spawn_transform(&config);
+        assert!(transform.forward().abs_diff_eq(Vec3::NEG_X, 1e-6));
+        // And what the clients are told to face
+        assert!((networked_yaw(transform.rotation) - config.map.spawn_yaw).abs() < 1e-6);
+    }
+
+    #[test]
+    fn spectators_cant_move_anything() {
+        let spectator = ClientId::from_raw(1);
+        let input = PlayerInput::default();
+        let mut queue = InputQueue::default();
+        let mut relay = LockstepRelay::default();
+        for lockstep in [false, true] {
+            assert!(!route_input(
+                spectator, false, input, lockstep, &mut queue, &mut relay
+            ));
+        }
+        assert!(queue.is_empty());
+        assert!(relay.inputs.is_empty());
+
+        // Players do get theirs through
+        let player = ClientId::from_raw(2);
+        route_input(player, true, input, false, &mut queue, &mut relay);
+        assert_eq!(queue.len(), 1);
+        route_input(player, true, input, true, &mut queue, &mut relay);
+        assert_eq!(relay.inputs[&player].len(), 1);
+    }
+
+    #[test]
+    fn hard_landings_take_health() {
+        let mut world = World::new();
+        world.insert_resource(Config {
+            fall_damage: Some(FallDamageConfig {
+                min_speed: 8.0,
+                damage_per_speed: 10.0,
+            }),
+            ..default()
+        });
+        world.init_resource::<ServerLobby>();
+        world.init_resource::<ServerMessageQueue>();
+        world.init_resource::<Events<Landed>>();
+        world.init_resource::<Events<FallDamage>>();
+        world.init_resource::<Events<WeaponHit>>();
+        let mut spawn = |id| {
+            world
+                .spawn((
+                    Player {
+                        id: ClientId::from_raw(id),
+                        team: Team::Jinrai,
+                    },
+                    Health::new(100.0),
+                ))
+                .id()
+        };
+        let hard = spawn(1);
+        let soft = spawn(2);
+        world.send_event(Landed {
+            entity: hard,
+            speed: 10.0,
+        });
+        world.send_event(Landed {
+            entity: soft,
+            speed: 6.0,
+        });
+
+        Schedule::default()
+            .add_systems((player::apply_fall_damage, apply_damage).chain())
+            .run(&mut world);
+
+        // 2 over the threshold at 10 damage each
+        assert_eq!(world.get::<Health>(hard).unwrap().current, 80.0);
+        assert_eq!(world.get::<Health>(soft).unwrap().current, 100.0);
+    }
 }
@@ -0,0 +1,28 @@
+use std::{fmt::Display, str::FromStr};
+
+// Takes the value following a command line flag and parses it
+pub fn next_value<T>(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let value = args
+        .next()
+        .ok_or_else(|| format!("Missing value for '{}'", flag))?;
+    value
+        .parse()
+        .map_err(|err| format!("Invalid value '{}' for '{}': {}", value, flag, err))
+}
+
+pub fn exit_with_usage(usage: &str, err: Option<String>) -> ! {
+    match err {
+        Some(err) => {
+            eprintln!("{}\n\n{}", err, usage);
+            std::process::exit(2);
+        }
+        None => {
+            println!("{}", usage);
+            std::process::exit(0);
+        }
+    }
+}
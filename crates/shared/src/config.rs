@@ -1,98 +1,906 @@
+use std::path::{Path, PathBuf};
+
 use bevy::{prelude::*, utils::HashMap};
-use bevy_xpbd_3d::resources::Gravity;
+use bevy_xpbd_3d::resources::{Gravity, PhysicsTimestep, SubstepCount};
 use serde::{Deserialize, Serialize};
 
-use crate::input::InputAction;
+use crate::networking::Team;
+use crate::weapons::WeaponKind;
 
 const CONFIG_PATH: &str = "config/config.ron";
+// Where maps are looked up by name, as <name>.ron
+const MAPS_DIR: &str = "maps";
 
-pub struct ConfigPlugin;
+pub struct ConfigPlugin {
+    pub path: PathBuf,
+}
 
-impl Plugin for ConfigPlugin {
-    fn build(&self, app: &mut App) {
-        app.add_systems(Startup, read_config);
+impl Default for ConfigPlugin {
+    fn default() -> Self {
+        Self {
+            path: CONFIG_PATH.into(),
+        }
     }
 }
 
-fn read_config(mut commands: Commands) {
-    let config = Config::new();
-    commands.insert_resource(Gravity(Vec3::NEG_Y * config.physics.gravity));
-    commands.insert_resource(config);
+impl Plugin for ConfigPlugin {
+    fn build(&self, app: &mut App) {
+        // Read eagerly so startup systems like map generation can rely on the config
+        let mut config = Config::load(&self.path);
+        config.apply_physics_preset();
+        config.physics.validate();
+        let timestep = if config.simulation.timestep > 0.0 {
+            config.simulation.timestep
+        } else {
+            warn!(
+                "Invalid physics timestep {}, using the default",
+                config.simulation.timestep
+            );
+            SimulationConfig::default().timestep
+        };
+        app.insert_resource(Gravity(
+            config.map.gravity_direction() * config.physics.gravity(),
+        ))
+        .insert_resource(PhysicsTimestep::Fixed(timestep))
+        .insert_resource(SubstepCount(config.simulation.substeps.max(1)))
+        .insert_resource(config);
+    }
 }
 
+// Fields missing from an older config file are filled in from the defaults instead of
+// failing to parse
 #[derive(Serialize, Deserialize, Resource)]
+#[serde(default)]
 pub struct Config {
-    pub key_bindings: HashMap<KeyCode, Vec<InputAction>>,
+    // Replaces the movement values in physics with a named preset when set
+    pub physics_preset: Option<PhysicsPreset>,
     pub physics: PhysicsConfig,
+    pub simulation: SimulationConfig,
+    pub network: NetworkConfig,
+    pub weapons: HashMap<WeaponKind, WeaponConfig>,
+    pub default_loadout: Vec<WeaponKind>,
+    pub max_players: usize,
+    // Clients watching without a player, on top of max_players
+    pub max_spectators: usize,
+    // Seconds between the server's metrics log lines, 0 turns them off
+    pub metrics_interval: f32,
+    // Where the server keeps player stats between sessions, none are kept when unset
+    pub stats_path: Option<PathBuf>,
+    // Health players spawn with
+    pub max_health: f32,
+    // No fall damage when unset
+    pub fall_damage: Option<FallDamageConfig>,
+    pub ui: UiConfig,
+    pub map: MapConfig,
+    // Names of map files the server cycles through, moving on every round_time
+    // seconds. The map above is played until the first change, and an empty rotation
+    // stays on it.
+    pub map_rotation: Vec<String>,
+    pub round_time: f32,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct PhysicsConfig {
     pub ground_speed: f32,
+    // Ground speed while crouched or walking, crouching wins when both are held
+    pub crouch_speed: f32,
+    pub walk_speed: f32,
     pub air_speed: f32,
     pub ground_accel: f32,
     pub air_accel: f32,
     pub ground_friction: f32,
     pub air_friction: f32,
+    pub friction_model: FrictionModel,
+    // Jumps peak at jump_height. With jump_apex_time set they also take that long to get
+    // there, and gravity is derived from the two instead of taken from gravity.
     pub gravity: f32,
     pub jump_height: f32,
+    pub jump_apex_time: Option<f32>,
+    pub jump_mode: JumpMode,
+    // Jumps allowed before landing again, counting the one off the ground. Air jumps
+    // peak at air_jump_height above where they start, or jump_height when unset.
+    pub max_jumps: u32,
+    pub air_jump_height: Option<f32>,
+    // Seconds after a jump before the next one, so one press can't jump twice
+    pub jump_cooldown: f32,
+    // Movement while in water. Drag is the fraction of speed lost per second, and
+    // buoyancy above 1 floats players up against gravity.
+    pub swim_speed: f32,
+    pub swim_accel: f32,
+    pub water_drag: f32,
+    pub water_buoyancy: f32,
+    // Whether players block each other or can walk through one another
+    pub player_collision: bool,
+    // Assist that stops grounded players at ledges instead of walking off, jumping
+    // still gets them down
+    pub edge_stop: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FrictionModel {
+    // Speed drops by a fixed amount per second, scaled up when moving fast. Stops are
+    // short and definite, with a clear point where the player comes to rest.
+    QuakeLinear,
+    // Speed drops by a fixed fraction per second. Fast movement bleeds off quickly,
+    // then the player glides the last bit to a stop.
+    Exponential,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JumpMode {
+    // One jump per press of the jump key
+    Tap,
+    // Jump again on every landing for as long as the key is held
+    Hold,
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub enum PhysicsPreset {
+    Quake,
+    Casual,
+}
+
+impl Default for PhysicsConfig {
+    fn default() -> Self {
+        Self::quake()
+    }
+}
+
+impl PhysicsConfig {
+    pub fn preset(preset: PhysicsPreset) -> Self {
+        match preset {
+            PhysicsPreset::Quake => Self::quake(),
+            PhysicsPreset::Casual => Self::casual(),
+        }
+    }
+
+    // Puts back the defaults for values movement can't work with, like negative speeds
+    // or gravity that has the jump take the square root of a negative number
+    pub fn validate(&mut self) {
+        let defaults = Self::quake();
+        for (name, value, default) in [
+            (
+                "ground_speed",
+                &mut self.ground_speed,
+                defaults.ground_speed,
+            ),
+            (
+                "crouch_speed",
+                &mut self.crouch_speed,
+                defaults.crouch_speed,
+            ),
+            ("walk_speed", &mut self.walk_speed, defaults.walk_speed),
+            ("air_speed", &mut self.air_speed, defaults.air_speed),
+            (
+                "ground_accel",
+                &mut self.ground_accel,
+                defaults.ground_accel,
+            ),
+            ("air_accel", &mut self.air_accel, defaults.air_accel),
+            (
+                "ground_friction",
+                &mut self.ground_friction,
+                defaults.ground_friction,
+            ),
+            (
+                "air_friction",
+                &mut self.air_friction,
+                defaults.air_friction,
+            ),
+            ("jump_height", &mut self.jump_height, defaults.jump_height),
+            (
+                "jump_cooldown",
+                &mut self.jump_cooldown,
+                defaults.jump_cooldown,
+            ),
+            ("swim_speed", &mut self.swim_speed, defaults.swim_speed),
+            ("swim_accel", &mut self.swim_accel, defaults.swim_accel),
+            ("water_drag", &mut self.water_drag, defaults.water_drag),
+            (
+                "water_buoyancy",
+                &mut self.water_buoyancy,
+                defaults.water_buoyancy,
+            ),
+        ] {
+            if !(value.is_finite() && *value >= 0.0) {
+                warn!("Invalid physics {} {}, using {}", name, value, default);
+                *value = default;
+            }
+        }
+        if !(self.gravity.is_finite() && self.gravity > 0.0) {
+            warn!(
+                "Invalid physics gravity {}, using {}",
+                self.gravity, defaults.gravity
+            );
+            self.gravity = defaults.gravity;
+        }
+        // Crouching and walking are the slow ways to move
+        for (name, value) in [
+            ("crouch_speed", &mut self.crouch_speed),
+            ("walk_speed", &mut self.walk_speed),
+        ] {
+            if *value > self.ground_speed {
+                warn!(
+                    "Physics {} {} is faster than ground_speed, using {}",
+                    name, value, self.ground_speed
+                );
+                *value = self.ground_speed;
+            }
+        }
+        // No jumps at all would leave players stuck on the ground without a word
+        if self.max_jumps == 0 {
+            warn!("Invalid physics max_jumps 0, using {}", defaults.max_jumps);
+            self.max_jumps = defaults.max_jumps;
+        }
+        if let Some(apex_time) = self
+            .jump_apex_time
+            .filter(|apex_time| !(apex_time.is_finite() && *apex_time > 0.0))
+        {
+            warn!(
+                "Invalid physics jump_apex_time {}, using gravity instead",
+                apex_time
+            );
+            self.jump_apex_time = None;
+        }
+        if let Some(height) = self
+            .air_jump_height
+            .filter(|height| !(height.is_finite() && *height >= 0.0))
+        {
+            warn!(
+                "Invalid physics air_jump_height {}, using jump_height instead",
+                height
+            );
+            self.air_jump_height = None;
+        }
+    }
+
+    pub fn gravity(&self) -> f32 {
+        match self.jump_apex_time {
+            Some(apex_time) if apex_time > 0.0 => 2.0 * self.jump_height / (apex_time * apex_time),
+            _ => self.gravity,
+        }
+    }
+
+    pub fn jump_velocity(&self, gravity: f32, delta_time: f32) -> f32 {
+        takeoff_velocity(self.jump_height, gravity, delta_time)
+    }
+
+    pub fn air_jump_velocity(&self, gravity: f32, delta_time: f32) -> f32 {
+        let height = self.air_jump_height.unwrap_or(self.jump_height);
+        takeoff_velocity(height, gravity, delta_time)
+    }
+
+    // Slippery air with little control, speed is built up by strafing
+    pub fn quake() -> Self {
+        Self {
+            ground_speed: 3.0,
+            crouch_speed: 1.2,
+            walk_speed: 1.5,
+            air_speed: 0.5,
+            ground_accel: 10.0,
+            air_accel: 1.0,
+            ground_friction: 5.0,
+            air_friction: 0.0,
+            friction_model: FrictionModel::QuakeLinear,
+            gravity: 12.0,
+            jump_height: 0.5,
+            jump_apex_time: None,
+            jump_mode: JumpMode::Tap,
+            max_jumps: 1,
+            air_jump_height: None,
+            jump_cooldown: 0.1,
+            swim_speed: 1.5,
+            swim_accel: 8.0,
+            water_drag: 2.0,
+            water_buoyancy: 1.5,
+            player_collision: true,
+            edge_stop: false,
+        }
+    }
+
+    // Snappier starts and stops, and full control in the air
+    pub fn casual() -> Self {
+        Self {
+            ground_speed: 3.0,
+            crouch_speed: 1.2,
+            walk_speed: 1.5,
+            air_speed: 3.0,
+            ground_accel: 16.0,
+            air_accel: 4.0,
+            ground_friction: 8.0,
+            air_friction: 0.5,
+            friction_model: FrictionModel::QuakeLinear,
+            gravity: 12.0,
+            jump_height: 0.5,
+            jump_apex_time: None,
+            jump_mode: JumpMode::Tap,
+            max_jumps: 1,
+            air_jump_height: None,
+            jump_cooldown: 0.1,
+            swim_speed: 1.5,
+            swim_accel: 8.0,
+            water_drag: 2.0,
+            water_buoyancy: 1.5,
+            player_collision: true,
+            edge_stop: false,
+        }
+    }
+}
+
+// Takeoff speed that peaks at height when gravity is applied every step of delta_time
+// before moving, which loses about half a step's worth of height compared to a
+// continuous arc
+fn takeoff_velocity(height: f32, gravity: f32, delta_time: f32) -> f32 {
+    let half_step = 0.5 * gravity * delta_time;
+    half_step + (half_step * half_step + 2.0 * gravity * height).sqrt()
+}
+
+// How the physics engine steps the world. Every step is split into substeps, and more
+// of them keep fast bodies from passing through thin colliders, at the cost of CPU.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct SimulationConfig {
+    // Seconds per physics step
+    pub timestep: f32,
+    pub substeps: u32,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            timestep: 1.0 / 60.0,
+            substeps: 12,
+        }
+    }
+}
+
+// Channel settings for the connection. Renet needs the same channels on both ends, so
+// the client and server configs have to agree on these.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkConfig {
+    // Position updates. Unreliable drops lost snapshots and moves on to the next one,
+    // which keeps latency low. Reliable resends them, smoothing over lossy links at
+    // the cost of stalls while waiting on a resend.
+    pub entity_updates: Delivery,
+    // Seconds before an unacknowledged reliable message is sent again. Shorter resends
+    // recover from loss sooner but spend more bandwidth on duplicates.
+    pub resend_time: f32,
+    // Upper bound on what the server sends each client per tick
+    pub available_bytes_per_tick: u64,
+    // How players are kept in sync. Experimental, see NetworkMode.
+    pub mode: NetworkMode,
+    // How quickly other players turn toward the facing the server sent, higher is
+    // snappier. 0 turns them right away.
+    pub rotation_smoothing: f32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Delivery {
+    Unreliable,
+    Reliable,
+}
+
+// Snapshot has the server simulate everyone and send out where they are. Lockstep is
+// an experimental mode for LAN play where the server only relays inputs and every
+// client simulates all players itself, which needs everyone to join before play
+// starts and a link without much latency, since each step waits on the slowest client.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NetworkMode {
+    Snapshot,
+    Lockstep,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            entity_updates: Delivery::Unreliable,
+            resend_time: 0.2,
+            available_bytes_per_tick: 1024 * 1024,
+            mode: NetworkMode::Snapshot,
+            rotation_smoothing: 20.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WeaponConfig {
+    pub damage: f32,
+    pub deploy_time: f32,
+    pub fire_interval: f32,
+    pub range: f32,
+    // Spread is the half-angle of the shot cone in radians
+    pub spread: f32,
+    pub move_spread: f32,
+    // Both kinds of spread are scaled by this while crouching
+    #[serde(default = "no_scale")]
+    pub crouch_spread: f32,
+    // Recoil is measured in screen pixels
+    pub recoil: f32,
+    pub max_recoil: f32,
+    pub recoil_recovery: f32,
+    // Frame of the attack animation the hit lands on, lasting attack_frame_time seconds
+    // each. Frame 0 hits as soon as the weapon fires.
+    #[serde(default)]
+    pub attack_frame: u8,
+    #[serde(default)]
+    pub attack_frame_time: f32,
+}
+
+fn no_scale() -> f32 {
+    1.0
+}
+
+impl WeaponConfig {
+    // Seconds between firing and the hit landing
+    pub fn attack_delay(&self) -> f32 {
+        self.attack_frame as f32 * self.attack_frame_time.max(0.0)
+    }
+}
+
+// Landing faster than min_speed deals damage_per_speed for every unit per second over it
+#[derive(Serialize, Deserialize)]
+pub struct FallDamageConfig {
+    pub min_speed: f32,
+    pub damage_per_speed: f32,
+}
+
+impl FallDamageConfig {
+    pub fn damage(&self, speed: f32) -> f32 {
+        (speed - self.min_speed).max(0.0) * self.damage_per_speed
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MapConfig {
+    // Width of the square playfield in tiles
+    pub size: u32,
+    pub seed: u64,
+    // Fixed height for aiming, instead of following the floor under the player
+    #[serde(default)]
+    pub aim_height: Option<f32>,
+    // Which way is down, for maps that have players walking on walls
+    #[serde(default = "down")]
+    pub gravity_direction: (f32, f32, f32),
+    // Way players face when they spawn, in radians counterclockwise from facing -Z
+    #[serde(default)]
+    pub spawn_yaw: f32,
+    // Maps without some kind of feature can leave it out
+    #[serde(default)]
+    pub props: Vec<PropConfig>,
+    #[serde(default)]
+    pub teleporters: Vec<TeleporterConfig>,
+    #[serde(default)]
+    pub jump_pads: Vec<JumpPadConfig>,
+    #[serde(default)]
+    pub water: Vec<WaterConfig>,
+    #[serde(default)]
+    pub surfaces: Vec<SurfaceConfig>,
+    #[serde(default)]
+    pub safe_zones: Vec<SafeZoneConfig>,
+}
+
+fn down() -> (f32, f32, f32) {
+    (0.0, -1.0, 0.0)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PropConfig {
+    pub mesh: PropMesh,
+    pub count: u32,
+    pub scale: (f32, f32),
+    pub collider: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TeleporterConfig {
+    pub position: (f32, f32, f32),
+    pub size: (f32, f32, f32),
+    pub target: (f32, f32, f32),
+    // Whether players come out moving or at a standstill
+    pub keep_velocity: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct JumpPadConfig {
+    pub position: (f32, f32, f32),
+    pub size: (f32, f32, f32),
+    // Velocity the player is launched with
+    pub impulse: (f32, f32, f32),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WaterConfig {
+    pub position: (f32, f32, f32),
+    pub size: (f32, f32, f32),
+}
+
+// A team's base, where nobody from the other team can deal or take damage so players
+// can't be camped as they spawn. Optionally the team's own players are safe there too.
+#[derive(Serialize, Deserialize)]
+pub struct SafeZoneConfig {
+    pub position: (f32, f32, f32),
+    pub size: (f32, f32, f32),
+    pub team: Team,
+    pub protect_team: bool,
+}
+
+// Flat patch of ground with its own friction, like ice or mud
+#[derive(Serialize, Deserialize)]
+pub struct SurfaceConfig {
+    pub position: (f32, f32, f32),
+    pub size: (f32, f32, f32),
+    pub friction: f32,
+    pub color: (f32, f32, f32),
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum PropMesh {
+    // Camera facing quad with a blob shadow, standing on the ground
+    Billboard { texture: String, size: (f32, f32) },
+    Cube { color: (f32, f32, f32) },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct UiConfig {
+    pub hit_markers: bool,
+    pub damage_numbers: bool,
+    // Seconds players take to fade out after leaving
+    pub despawn_fade: f32,
+}
+
+impl Default for UiConfig {
+    fn default() -> Self {
+        Self {
+            hit_markers: true,
+            damage_numbers: true,
+            despawn_fade: 0.2,
+        }
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
-            key_bindings: HashMap::from_iter(vec![
-                (KeyCode::W, vec![InputAction::Forward]),
-                (KeyCode::S, vec![InputAction::Back]),
-                (KeyCode::A, vec![InputAction::Left]),
-                (KeyCode::D, vec![InputAction::Right]),
-                (KeyCode::Space, vec![InputAction::Jump]),
+            physics_preset: None,
+            physics: PhysicsConfig::default(),
+            simulation: SimulationConfig::default(),
+            network: NetworkConfig::default(),
+            weapons: HashMap::from_iter(vec![
+                (
+                    WeaponKind::Knife,
+                    WeaponConfig {
+                        damage: 50.0,
+                        deploy_time: 0.3,
+                        fire_interval: 0.5,
+                        range: 1.0,
+                        spread: 0.0,
+                        move_spread: 0.0,
+                        crouch_spread: 1.0,
+                        recoil: 0.0,
+                        max_recoil: 0.0,
+                        recoil_recovery: 0.0,
+                        attack_frame: 2,
+                        attack_frame_time: 0.08,
+                    },
+                ),
+                (
+                    WeaponKind::Pistol,
+                    WeaponConfig {
+                        damage: 20.0,
+                        deploy_time: 0.4,
+                        fire_interval: 0.2,
+                        range: 30.0,
+                        spread: 0.02,
+                        move_spread: 0.06,
+                        crouch_spread: 0.7,
+                        recoil: 16.0,
+                        max_recoil: 64.0,
+                        recoil_recovery: 160.0,
+                        attack_frame: 0,
+                        attack_frame_time: 0.0,
+                    },
+                ),
+                (
+                    WeaponKind::Smg,
+                    WeaponConfig {
+                        damage: 15.0,
+                        deploy_time: 0.6,
+                        fire_interval: 0.08,
+                        range: 25.0,
+                        spread: 0.04,
+                        move_spread: 0.08,
+                        crouch_spread: 0.7,
+                        recoil: 6.0,
+                        max_recoil: 96.0,
+                        recoil_recovery: 200.0,
+                        attack_frame: 0,
+                        attack_frame_time: 0.0,
+                    },
+                ),
+                (
+                    WeaponKind::Rifle,
+                    WeaponConfig {
+                        damage: 25.0,
+                        deploy_time: 0.8,
+                        fire_interval: 0.12,
+                        range: 40.0,
+                        spread: 0.01,
+                        move_spread: 0.1,
+                        crouch_spread: 0.5,
+                        recoil: 10.0,
+                        max_recoil: 128.0,
+                        recoil_recovery: 160.0,
+                        attack_frame: 0,
+                        attack_frame_time: 0.0,
+                    },
+                ),
             ]),
-            physics: PhysicsConfig {
-                ground_speed: 3.0,
-                air_speed: 0.5,
-                ground_accel: 10.0,
-                air_accel: 1.0,
-                ground_friction: 5.0,
-                air_friction: 0.0,
-                gravity: 12.0,
-                jump_height: 0.5,
+            default_loadout: vec![WeaponKind::Rifle, WeaponKind::Pistol, WeaponKind::Knife],
+            max_players: 16,
+            max_spectators: 4,
+            metrics_interval: 60.0,
+            stats_path: None,
+            max_health: 100.0,
+            fall_damage: None,
+            ui: UiConfig::default(),
+            map: MapConfig {
+                size: 64,
+                seed: 1234567890,
+                aim_height: None,
+                gravity_direction: down(),
+                spawn_yaw: 0.0,
+                props: vec![
+                    PropConfig {
+                        mesh: PropMesh::Billboard {
+                            texture: "textures/props/sakura1.png".into(),
+                            size: (1.5, 2.0),
+                        },
+                        count: 128,
+                        scale: (1.0, 1.0),
+                        collider: false,
+                    },
+                    PropConfig {
+                        mesh: PropMesh::Cube {
+                            color: (0.8, 0.7, 0.6),
+                        },
+                        count: 32,
+                        scale: (1.0, 1.0),
+                        collider: true,
+                    },
+                ],
+                teleporters: vec![
+                    TeleporterConfig {
+                        position: (-28.0, 0.5, -28.0),
+                        size: (1.0, 1.0, 1.0),
+                        target: (26.0, 0.51, 26.0),
+                        keep_velocity: false,
+                    },
+                    TeleporterConfig {
+                        position: (28.0, 0.5, 28.0),
+                        size: (1.0, 1.0, 1.0),
+                        target: (-26.0, 0.51, -26.0),
+                        keep_velocity: false,
+                    },
+                ],
+                jump_pads: vec![JumpPadConfig {
+                    position: (0.0, 0.1, 8.0),
+                    size: (1.0, 0.2, 1.0),
+                    impulse: (0.0, 8.0, 4.0),
+                }],
+                water: vec![WaterConfig {
+                    position: (0.0, 0.75, -10.0),
+                    size: (6.0, 1.5, 6.0),
+                }],
+                surfaces: vec![
+                    SurfaceConfig {
+                        position: (-8.0, 0.01, 0.0),
+                        size: (4.0, 0.02, 4.0),
+                        friction: 0.5,
+                        color: (0.7, 0.85, 1.0),
+                    },
+                    SurfaceConfig {
+                        position: (8.0, 0.01, 0.0),
+                        size: (4.0, 0.02, 4.0),
+                        friction: 15.0,
+                        color: (0.35, 0.25, 0.15),
+                    },
+                ],
+                safe_zones: Vec::new(),
             },
+            map_rotation: Vec::new(),
+            round_time: 600.0,
         }
     }
 }
 
 impl Config {
-    pub fn new() -> Self {
-        match std::fs::read_to_string(CONFIG_PATH) {
+    pub fn load(path: &Path) -> Self {
+        match std::fs::read_to_string(path) {
             Ok(config) => ron::from_str(&config).unwrap_or_else(|err| {
-                println!(
-                    "Failed to parse config! Backing up and writing a new one.\n{}",
+                warn!(
+                    "Failed to parse config, backing up and writing a new one: {}",
                     err
                 );
-                std::fs::copy(CONFIG_PATH, "config/config.old.ron").unwrap_or_else(|err| {
-                    println!("Unable to backup old config!\n{}", err);
+                std::fs::copy(path, path.with_extension("old.ron")).unwrap_or_else(|err| {
+                    error!("Unable to backup old config: {}", err);
                     0
                 });
-                Self::write_default()
+                Self::write_default(path)
             }),
-            _ => Self::write_default(),
+            _ => Self::write_default(path),
         }
     }
 
-    pub fn write(&self) -> Result<(), Box<dyn std::error::Error>> {
+    // Player collision and the edge stop assist aren't part of the movement feel, so
+    // they're kept
+    pub fn apply_physics_preset(&mut self) {
+        if let Some(preset) = self.physics_preset {
+            self.physics = PhysicsConfig {
+                player_collision: self.physics.player_collision,
+                edge_stop: self.physics.edge_stop,
+                ..PhysicsConfig::preset(preset)
+            };
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
         let pretty = ron::ser::PrettyConfig::new().depth_limit(2);
         let config_str = ron::ser::to_string_pretty(self, pretty)?;
-        std::fs::create_dir_all("config/")?;
-        std::fs::write(CONFIG_PATH, config_str)?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, config_str)?;
         Ok(())
     }
 
-    fn write_default() -> Self {
+    fn write_default(path: &Path) -> Self {
         let config = Self::default();
-        config.write().unwrap_or_else(|err| {
-            println!("Failed to write config to '{}'!\n{}", CONFIG_PATH, err)
+        config.write(path).unwrap_or_else(|err| {
+            error!("Failed to write config to '{}': {}", path.display(), err)
         });
         config
     }
 }
+
+impl MapConfig {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(ron::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    // Names come from the server, so they're kept to plain file names in the maps
+    // directory
+    pub fn load_named(name: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!("invalid map name '{}'", name).into());
+        }
+        Self::load(&Path::new(MAPS_DIR).join(name).with_extension("ron"))
+    }
+
+    pub fn gravity_direction(&self) -> Vec3 {
+        let direction = Vec3::from(self.gravity_direction).normalize_or_zero();
+        if direction != Vec3::ZERO {
+            direction
+        } else {
+            warn!(
+                "Invalid gravity direction {:?}, falling straight down instead",
+                self.gravity_direction
+            );
+            Vec3::NEG_Y
+        }
+    }
+
+    // Moves a point horizontally onto the ground, whose tiles are centered on whole units
+    pub fn clamp_to_bounds(&self, point: Vec3) -> Vec3 {
+        let half_size = (self.size / 2) as f32;
+        let min = -half_size - 0.5;
+        let max = (half_size - 0.5).max(min);
+        Vec3::new(point.x.clamp(min, max), point.y, point.z.clamp(min, max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn points_are_clamped_to_the_map() {
+        let map = Config::default().map;
+        assert_eq!(map.size, 64);
+        let inside = Vec3::new(3.0, 1.0, -7.0);
+        assert_eq!(map.clamp_to_bounds(inside), inside);
+        assert_eq!(
+            map.clamp_to_bounds(Vec3::new(100.0, 2.0, -100.0)),
+            Vec3::new(31.5, 2.0, -32.5)
+        );
+    }
+
+    #[test]
+    fn invalid_physics_values_fall_back_to_defaults() {
+        let defaults = PhysicsConfig::quake();
+        let mut physics = PhysicsConfig::quake();
+        physics.ground_speed = f32::NAN;
+        physics.air_accel = -1.0;
+        physics.gravity = 0.0;
+        physics.jump_apex_time = Some(f32::INFINITY);
+        physics.air_jump_height = Some(-2.0);
+        physics.max_jumps = 0;
+        physics.crouch_speed = -1.0;
+        physics.walk_speed = 10.0;
+        physics.ground_friction = 8.0;
+        physics.validate();
+        assert_eq!(physics.ground_speed, defaults.ground_speed);
+        assert_eq!(physics.air_accel, defaults.air_accel);
+        assert_eq!(physics.gravity, defaults.gravity);
+        assert_eq!(physics.jump_apex_time, None);
+        assert_eq!(physics.air_jump_height, None);
+        assert_eq!(physics.max_jumps, defaults.max_jumps);
+        assert_eq!(physics.crouch_speed, defaults.crouch_speed);
+        assert_eq!(physics.walk_speed, defaults.ground_speed);
+        // Valid values are kept
+        assert_eq!(physics.ground_friction, 8.0);
+    }
+
+    #[test]
+    fn presets_are_valid() {
+        for preset in [PhysicsPreset::Quake, PhysicsPreset::Casual] {
+            let mut physics = PhysicsConfig::preset(preset);
+            physics.validate();
+            assert_eq!(physics, PhysicsConfig::preset(preset), "{:?}", preset);
+        }
+    }
+
+    #[test]
+    fn presets_differ_in_air_control_and_grip() {
+        let quake = PhysicsConfig::quake();
+        let casual = PhysicsConfig::casual();
+        assert!(casual.air_speed > quake.air_speed);
+        assert!(casual.air_accel > quake.air_accel);
+        assert!(casual.ground_accel > quake.ground_accel);
+        assert!(casual.ground_friction > quake.ground_friction);
+        assert!(casual.air_friction > quake.air_friction);
+        // Only the feel changes, not how far or how fast players can go
+        assert_eq!(casual.ground_speed, quake.ground_speed);
+        assert_eq!(casual.jump_height, quake.jump_height);
+    }
+
+    #[test]
+    fn missing_config_fields_use_defaults() {
+        let config: Config =
+            ron::from_str("(max_players: 8, physics: (ground_speed: 4.0), ui: ())").unwrap();
+        assert_eq!(config.max_players, 8);
+        assert_eq!(config.max_spectators, 4);
+        assert_eq!(config.physics.ground_speed, 4.0);
+        assert_eq!(config.physics.max_jumps, 1);
+        assert!(config.ui.hit_markers);
+        assert_eq!(config.weapons.len(), 4);
+
+        let map: MapConfig = ron::from_str("(size: 32, seed: 1)").unwrap();
+        assert_eq!(map.gravity_direction, (0.0, -1.0, 0.0));
+        assert!(map.teleporters.is_empty() && map.safe_zones.is_empty());
+    }
+
+    #[test]
+    fn map_names_cant_leave_the_maps_folder() {
+        for name in [
+            "",
+            "../config",
+            "maps/arena",
+            "/etc/passwd",
+            "arena.ron",
+            "a b",
+        ] {
+            let err = MapConfig::load_named(name).err().unwrap();
+            assert!(err.to_string().starts_with("invalid map name"), "{}", name);
+        }
+        // Fine as a name, it just isn't there
+        let err = MapConfig::load_named("no_such-map2").err().unwrap();
+        assert!(!err.to_string().starts_with("invalid map name"));
+    }
+}
@@ -1,17 +1,30 @@
 use bevy::{
-    input::{keyboard::KeyboardInput, ButtonState},
+    input::{
+        keyboard::KeyboardInput,
+        mouse::{MouseButtonInput, MouseWheel},
+        ButtonState, InputSystem,
+    },
     prelude::*,
+    utils::HashMap,
 };
 use serde::{Deserialize, Serialize};
 
-use crate::config::Config;
+use crate::settings::Settings;
 
 pub struct InputPlugin;
 
 impl Plugin for InputPlugin {
     fn build(&self, app: &mut App) {
-        app.init_resource::<Input<InputAction>>()
-            .add_systems(PreUpdate, keyboard_input_system);
+        app.init_resource::<Input<InputAction>>().add_systems(
+            PreUpdate,
+            (
+                keyboard_input_system,
+                mouse_button_input_system,
+                mouse_wheel_input_system,
+            )
+                .chain()
+                .after(InputSystem),
+        );
     }
 }
 
@@ -22,31 +35,218 @@ pub enum InputAction {
     Left,
     Right,
     Jump,
+    Crouch,
+    Walk,
+    Attack,
+    NextWeapon,
+    PrevWeapon,
+    Slot1,
+    Slot2,
+    Slot3,
+    ToggleHud,
+    ToggleFps,
+    ToggleSpeed,
+    ToggleMaxSpeed,
+    ResetMaxSpeed,
+    ToggleVelocity,
+    ToggleSpeedRecording,
+    ToggleProjection,
+    ToggleVsync,
+    ToggleStats,
+    ReloadMap,
+    SpectateNext,
+    FaceForward,
+    FaceBack,
+    FaceLeft,
+    FaceRight,
+}
+
+// Either key of the pair counts
+#[derive(PartialEq, Eq, Hash, Copy, Clone, Serialize, Deserialize)]
+pub enum Modifier {
+    Shift,
+    Control,
+    Alt,
+}
+
+impl Modifier {
+    fn pressed(&self, keys: &Input<KeyCode>) -> bool {
+        match self {
+            Modifier::Shift => keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]),
+            Modifier::Control => keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]),
+            Modifier::Alt => keys.any_pressed([KeyCode::AltLeft, KeyCode::AltRight]),
+        }
+    }
+}
+
+// Key that does something else while modifiers are held, like Shift+W
+#[derive(Serialize, Deserialize)]
+pub struct Chord {
+    pub modifiers: Vec<Modifier>,
+    pub key: KeyCode,
+    pub actions: Vec<InputAction>,
+}
+
+#[derive(PartialEq, Eq, Hash, Copy, Clone, Serialize, Deserialize)]
+pub enum WheelDirection {
+    Up,
+    Down,
+}
+
+// Chord with the most modifiers held wins, then the plain binding
+fn bound_actions<'a>(
+    settings: &'a Settings,
+    keys: &Input<KeyCode>,
+    key_code: KeyCode,
+) -> Option<&'a Vec<InputAction>> {
+    settings
+        .chord_bindings
+        .iter()
+        .filter(|chord| {
+            chord.key == key_code
+                && chord
+                    .modifiers
+                    .iter()
+                    .all(|modifier| modifier.pressed(keys))
+        })
+        .max_by_key(|chord| chord.modifiers.len())
+        .map(|chord| &chord.actions)
+        .or_else(|| settings.key_bindings.get(&key_code))
 }
 
 fn keyboard_input_system(
     mut input: ResMut<Input<InputAction>>,
     mut keyboard_input_events: EventReader<KeyboardInput>,
-    config: Res<Config>,
+    keys: Res<Input<KeyCode>>,
+    settings: Res<Settings>,
+    // What each held key pressed, so letting go of the modifier first still releases it
+    mut held: Local<HashMap<KeyCode, Vec<InputAction>>>,
 ) {
     input.clear();
     for event in keyboard_input_events.read() {
-        if let KeyboardInput {
-            key_code: Some(key_code),
-            state,
-            ..
-        } = event
-        {
-            let actions = config.key_bindings.get(key_code);
-            match (state, actions) {
-                (ButtonState::Pressed, Some(actions)) => {
-                    actions.iter().for_each(|action| input.press(*action))
+        let Some(key_code) = event.key_code else {
+            continue;
+        };
+        match event.state {
+            ButtonState::Pressed => {
+                if held.contains_key(&key_code) {
+                    continue;
                 }
-                (ButtonState::Released, Some(actions)) => {
-                    actions.iter().for_each(|action| input.release(*action))
+                if let Some(actions) = bound_actions(&settings, &keys, key_code) {
+                    actions.iter().for_each(|action| input.press(*action));
+                    held.insert(key_code, actions.clone());
                 }
-                _ => (),
             }
+            ButtonState::Released => {
+                if let Some(actions) = held.remove(&key_code) {
+                    actions.iter().for_each(|action| input.release(*action));
+                }
+            }
+        }
+    }
+}
+
+fn mouse_button_input_system(
+    mut input: ResMut<Input<InputAction>>,
+    mut mouse_button_events: EventReader<MouseButtonInput>,
+    settings: Res<Settings>,
+) {
+    for event in mouse_button_events.read() {
+        let Some(actions) = settings.mouse_bindings.get(&event.button) else {
+            continue;
+        };
+        match event.state {
+            ButtonState::Pressed => actions.iter().for_each(|action| input.press(*action)),
+            ButtonState::Released => actions.iter().for_each(|action| input.release(*action)),
+        }
+    }
+}
+
+fn mouse_wheel_input_system(
+    mut input: ResMut<Input<InputAction>>,
+    mut mouse_wheel_events: EventReader<MouseWheel>,
+    settings: Res<Settings>,
+) {
+    for event in mouse_wheel_events.read() {
+        let direction = if event.y > 0.0 {
+            WheelDirection::Up
+        } else if event.y < 0.0 {
+            WheelDirection::Down
+        } else {
+            continue;
+        };
+        let Some(actions) = settings.wheel_bindings.get(&direction) else {
+            continue;
+        };
+        // Scrolling has no held state, so tap the actions for a single frame
+        for action in actions {
+            input.press(*action);
+            input.release(*action);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mouse_bindings_press_their_actions() {
+        let mut world = World::new();
+        let mut settings = Settings::default();
+        settings
+            .mouse_bindings
+            .insert(MouseButton::Right, vec![InputAction::Slot2]);
+        world.insert_resource(settings);
+        world.init_resource::<Input<InputAction>>();
+        world.init_resource::<Events<MouseButtonInput>>();
+        let mut schedule = Schedule::default();
+        schedule.add_systems(mouse_button_input_system);
+        let mut click = |world: &mut World, state| {
+            world.send_event(MouseButtonInput {
+                button: MouseButton::Right,
+                state,
+                window: Entity::PLACEHOLDER,
+            });
+            schedule.run(world);
+        };
+
+        click(&mut world, ButtonState::Pressed);
+        let input = world.resource::<Input<InputAction>>();
+        assert!(input.just_pressed(InputAction::Slot2));
+        assert!(!input.pressed(InputAction::Attack));
+        click(&mut world, ButtonState::Released);
+        let input = world.resource::<Input<InputAction>>();
+        assert!(!input.pressed(InputAction::Slot2));
+    }
+
+    #[test]
+    fn chords_override_the_plain_key() {
+        let settings = Settings {
+            chord_bindings: vec![
+                Chord {
+                    modifiers: vec![Modifier::Shift],
+                    key: KeyCode::W,
+                    actions: vec![InputAction::Walk],
+                },
+                Chord {
+                    modifiers: vec![Modifier::Shift, Modifier::Control],
+                    key: KeyCode::W,
+                    actions: vec![InputAction::Crouch],
+                },
+            ],
+            ..default()
+        };
+        let mut keys = Input::<KeyCode>::default();
+        let actions = |keys: &Input<KeyCode>| bound_actions(&settings, keys, KeyCode::W).cloned();
+
+        assert!(actions(&keys) == Some(vec![InputAction::Forward]));
+        keys.press(KeyCode::ShiftRight);
+        assert!(actions(&keys) == Some(vec![InputAction::Walk]));
+        // The chord with the most modifiers held wins
+        keys.press(KeyCode::ControlLeft);
+        assert!(actions(&keys) == Some(vec![InputAction::Crouch]));
+        // Other keys aren't affected by the modifiers
+        assert!(bound_actions(&settings, &keys, KeyCode::S) == Some(&vec![InputAction::Back]));
+    }
+}
@@ -1,45 +1,155 @@
+pub mod args;
 pub mod config;
 pub mod input;
+pub mod lockstep;
 pub mod networking;
 pub mod physics;
 pub mod player;
+pub mod pool;
+pub mod settings;
 pub mod sprites;
+pub mod stats;
+pub mod triggers;
 pub mod ui;
+pub mod weapons;
 
 use bevy::{
     prelude::{shape::Plane, *},
     render::camera::ScalingMode,
 };
 use bevy_xpbd_3d::components::{Collider, CollisionLayers, RigidBody};
-use physics::Layer;
+use bevy_xpbd_3d::resources::Gravity;
+use config::{Config, MapConfig, PropConfig, PropMesh, SurfaceConfig};
+use input::InputAction;
+use physics::{Layer, SurfaceFriction};
 use rand::{Rng, SeedableRng};
+use settings::Settings;
 use sprites::*;
 
-const MAP_SIZE: i32 = 64;
-
 #[derive(Component)]
 pub struct MainCamera;
 
-pub fn setup_camera(mut commands: Commands) {
+// Everything making up the map, torn down when another map is loaded. Only on the top
+// level entities since children go with them.
+#[derive(Component)]
+pub struct MapEntity;
+
+// Replaces the map with the map file of that name, using the seed instead of the file's
+// own when given. The server sends the seed so clients lay out props the same way.
+#[derive(Event)]
+pub struct LoadMap {
+    pub name: String,
+    pub seed: Option<u64>,
+}
+
+// Builds the current map again, reading its file again first if it came from one so
+// edits to it show up
+#[derive(Event)]
+pub struct ReloadMap;
+
+// Name of the map file loaded in place of the map in the config, if any
+#[derive(Resource, Default)]
+pub struct CurrentMap(pub Option<String>);
+
+pub fn setup_camera(mut commands: Commands, settings: Res<Settings>) {
     // Set up the camera
     let mut camera = Camera3dBundle {
         projection: OrthographicProjection {
             scaling_mode: ScalingMode::WindowSize(1.0),
-            scale: 1.0 / 64.0,
+            scale: 1.0 / settings.camera.zoom,
             ..default()
         }
         .into(),
         ..default()
     };
-    camera.transform = Transform::from_xyz(5.0, 5.0, 5.0).looking_at(Vec3::ZERO, Vec3::Y);
+    camera.transform = settings.camera.follow(&Transform::IDENTITY, Vec3::ZERO);
     commands.spawn(camera).insert(MainCamera);
 }
 
 pub fn generate_map(
     mut commands: Commands,
+    config: Res<Config>,
     asset_server: Res<AssetServer>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    spawn_map(
+        &mut commands,
+        &config.map,
+        &asset_server,
+        &mut meshes,
+        &mut materials,
+    );
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn load_map(
+    mut commands: Commands,
+    mut load_events: EventReader<LoadMap>,
+    mut reload_events: EventReader<ReloadMap>,
+    mut config: ResMut<Config>,
+    mut current_map: ResMut<CurrentMap>,
+    mut gravity: ResMut<Gravity>,
+    asset_server: Res<AssetServer>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    map_entities: Query<Entity, With<MapEntity>>,
+) {
+    let reload = reload_events.read().count() > 0;
+    // Only the last one matters when several come at once
+    let load = match load_events.read().last() {
+        Some(load) => Some((load.name.clone(), load.seed)),
+        None if reload => current_map.0.clone().map(|name| (name, None)),
+        None => return,
+    };
+    if let Some((name, seed)) = load {
+        let mut map = match MapConfig::load_named(&name) {
+            Ok(map) => map,
+            Err(err) => {
+                error!("Failed to load map '{}': {}", name, err);
+                return;
+            }
+        };
+        if let Some(seed) = seed {
+            map.seed = seed;
+        }
+        info!("Loading map '{}'.", name);
+        config.map = map;
+        current_map.0 = Some(name);
+    } else {
+        info!("Rebuilding the map from the config.");
+    }
+
+    for entity in map_entities.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    gravity.0 = config.map.gravity_direction() * config.physics.gravity();
+    spawn_map(
+        &mut commands,
+        &config.map,
+        &asset_server,
+        &mut meshes,
+        &mut materials,
+    );
+}
+
+// For editing maps offline, a connected client would end up on a different map than
+// the server
+pub fn reload_map_on_key(
+    input: Res<Input<InputAction>>,
+    mut reload_events: EventWriter<ReloadMap>,
+) {
+    if input.just_pressed(InputAction::ReloadMap) {
+        reload_events.send(ReloadMap);
+    }
+}
+
+fn spawn_map(
+    commands: &mut Commands,
+    map: &MapConfig,
+    asset_server: &AssetServer,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
 ) {
     let texture_handle = asset_server.load("textures/tiles/grass1.png");
     let material_handle = materials.add(StandardMaterial {
@@ -54,14 +164,17 @@ pub fn generate_map(
     let mesh_handle = meshes.add(Mesh::from(Plane::from_size(1.0)));
 
     // Plane
-    for x in -MAP_SIZE / 2..MAP_SIZE / 2 {
-        for y in -MAP_SIZE / 2..MAP_SIZE / 2 {
-            commands.spawn(PbrBundle {
-                mesh: mesh_handle.clone(),
-                material: material_handle.clone(),
-                transform: Transform::from_xyz(x as f32, 0.0, y as f32),
-                ..default()
-            });
+    let size = map.size as i32;
+    for x in -size / 2..size / 2 {
+        for y in -size / 2..size / 2 {
+            commands
+                .spawn(PbrBundle {
+                    mesh: mesh_handle.clone(),
+                    material: material_handle.clone(),
+                    transform: Transform::from_xyz(x as f32, 0.0, y as f32),
+                    ..default()
+                })
+                .insert(MapEntity);
         }
     }
 
@@ -69,11 +182,12 @@ pub fn generate_map(
     commands
         .spawn(TransformBundle::from(Transform::from_xyz(-0.5, -0.1, -0.5)))
         .insert(RigidBody::Static)
-        .insert(Collider::cuboid(MAP_SIZE as f32, 0.2, MAP_SIZE as f32))
+        .insert(Collider::cuboid(size as f32, 0.2, size as f32))
         .insert(CollisionLayers::new(
             [Layer::Ground],
             [Layer::Enemy, Layer::Player],
-        ));
+        ))
+        .insert(MapEntity);
 
     // Light
     commands.insert_resource(AmbientLight {
@@ -82,85 +196,301 @@ pub fn generate_map(
     });
 
     // // directional 'sun' light
-    commands.spawn(DirectionalLightBundle {
-        directional_light: DirectionalLight {
-            illuminance: 5000.0,
+    commands
+        .spawn(DirectionalLightBundle {
+            directional_light: DirectionalLight {
+                illuminance: 5000.0,
+                ..default()
+            },
+            transform: Transform::from_xyz(5.0, 5.0, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
             ..default()
-        },
-        transform: Transform::from_xyz(5.0, 5.0, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
-        ..default()
-    });
+        })
+        .insert(MapEntity);
 
     // Props
-    let mut rng = rand::rngs::StdRng::seed_from_u64(1234567890);
+    let mut rng = rand::rngs::StdRng::seed_from_u64(map.seed);
+    for prop in map.props.iter() {
+        spawn_props(
+            commands,
+            asset_server,
+            meshes,
+            materials,
+            &mut rng,
+            size,
+            prop,
+        );
+    }
 
-    let texture_handle = asset_server.load("textures/props/sakura1.png");
-    let material_handle = materials.add(StandardMaterial {
-        base_color_texture: Some(texture_handle),
-        alpha_mode: AlphaMode::Blend,
-        reflectance: 0.0,
-        metallic: 0.0,
-        perceptual_roughness: 1.0,
-        ..default()
-    });
-    let mesh_handle = meshes.add(Mesh::from(shape::Quad {
-        size: Vec2::new(1.5, 2.0),
-        ..default()
-    }));
-    let plane_handle = meshes.add(Mesh::from(Plane::from_size(1.0)));
-    for _ in 0..128 {
-        let x = rng.gen::<f32>() * MAP_SIZE as f32 - (MAP_SIZE / 2) as f32;
-        let z = rng.gen::<f32>() * MAP_SIZE as f32 - (MAP_SIZE / 2) as f32;
-        // Tree
-        commands
-            .spawn(SpatialBundle {
-                transform: Transform::from_xyz(x, 1.0, z),
-                ..default()
-            })
-            .with_children(|parent| {
-                parent
-                    .spawn(PbrBundle {
-                        mesh: mesh_handle.clone(),
-                        material: material_handle.clone(),
-                        ..default()
-                    })
-                    .insert(Billboard);
-                parent
-                    .spawn(PbrBundle {
-                        mesh: plane_handle.clone(),
-                        material: materials.add(StandardMaterial {
-                            base_color: Color::BLACK,
-                            base_color_texture: Some(
-                                asset_server.load("textures/fx/blob_shadow.png"),
-                            ),
-                            alpha_mode: AlphaMode::Blend,
-                            unlit: true,
-                            ..default()
-                        }),
-                        transform: Transform::from_xyz(0.0, -1.0, 0.0),
-                        ..default()
-                    })
-                    .insert(BlobShadow);
-            });
-    }
-
-    let mesh_handle = meshes.add(Mesh::from(shape::Cube { size: 1.0 }));
-    let material_handle = materials.add(Color::rgb(0.8, 0.7, 0.6).into());
-    for _ in 0..32 {
-        let x = rng.gen::<f32>() * MAP_SIZE as f32 - (MAP_SIZE / 2) as f32;
-        let z = rng.gen::<f32>() * MAP_SIZE as f32 - (MAP_SIZE / 2) as f32;
+    spawn_surfaces(commands, meshes, materials, &map.surfaces);
+
+    triggers::spawn_teleporters(commands, meshes, materials, &map.teleporters);
+    triggers::spawn_jump_pads(commands, meshes, materials, &map.jump_pads);
+    triggers::spawn_water(commands, meshes, materials, &map.water);
+    triggers::spawn_safe_zones(commands, &map.safe_zones);
+}
+
+fn spawn_surfaces(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    surfaces: &[SurfaceConfig],
+) {
+    for surface in surfaces {
+        let size = Vec3::from(surface.size);
+        let (r, g, b) = surface.color;
         commands
             .spawn(PbrBundle {
-                mesh: mesh_handle.clone(),
-                material: material_handle.clone(),
-                transform: Transform::from_xyz(x, 0.5, z),
+                mesh: meshes.add(Mesh::from(shape::Box::new(size.x, size.y, size.z))),
+                material: materials.add(StandardMaterial {
+                    base_color: Color::rgb(r, g, b),
+                    perceptual_roughness: 1.0,
+                    ..default()
+                }),
+                transform: Transform::from_translation(surface.position.into()),
                 ..default()
             })
             .insert(RigidBody::Static)
-            .insert(Collider::cuboid(1.0, 1.0, 1.0))
+            .insert(Collider::cuboid(size.x, size.y, size.z))
             .insert(CollisionLayers::new(
                 [Layer::Ground],
                 [Layer::Enemy, Layer::Player],
-            ));
+            ))
+            .insert(SurfaceFriction(surface.friction))
+            .insert(MapEntity);
+    }
+}
+
+// Upper bound on props per entry to keep a typo in the config from stalling startup
+const MAX_PROP_COUNT: u32 = 4096;
+
+// How many of a prop entry to spawn, none when its scale range is invalid
+fn prop_count(prop: &PropConfig) -> u32 {
+    let (min_scale, max_scale) = prop.scale;
+    if !(min_scale > 0.0 && min_scale <= max_scale) {
+        warn!(
+            "Skipping props with invalid scale range {:?}, expected 0 < min <= max",
+            prop.scale
+        );
+        return 0;
+    }
+    if prop.count > MAX_PROP_COUNT {
+        warn!(
+            "Prop count {} exceeds the maximum of {}, clamping",
+            prop.count, MAX_PROP_COUNT
+        );
+    }
+    prop.count.min(MAX_PROP_COUNT)
+}
+
+fn spawn_props(
+    commands: &mut Commands,
+    asset_server: &AssetServer,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    rng: &mut impl Rng,
+    size: i32,
+    prop: &PropConfig,
+) {
+    let count = prop_count(prop);
+    if count == 0 {
+        return;
+    }
+    let (min_scale, max_scale) = prop.scale;
+
+    let material_handle = match &prop.mesh {
+        PropMesh::Billboard { texture, .. } => materials.add(StandardMaterial {
+            base_color_texture: Some(asset_server.load(texture)),
+            alpha_mode: AlphaMode::Blend,
+            reflectance: 0.0,
+            metallic: 0.0,
+            perceptual_roughness: 1.0,
+            ..default()
+        }),
+        PropMesh::Cube { color } => materials.add(Color::rgb(color.0, color.1, color.2).into()),
+    };
+    let plane_handle = meshes.add(Mesh::from(Plane::from_size(1.0)));
+
+    for _ in 0..count {
+        let x = rng.gen::<f32>() * size as f32 - (size / 2) as f32;
+        let z = rng.gen::<f32>() * size as f32 - (size / 2) as f32;
+        // Only draw a scale when there's a range, so fixed sizes keep the same layout
+        let scale = if min_scale < max_scale {
+            rng.gen_range(min_scale..=max_scale)
+        } else {
+            min_scale
+        };
+
+        let mut entity = match &prop.mesh {
+            PropMesh::Billboard { size, .. } => {
+                let size = Vec2::new(size.0, size.1) * scale;
+                let mut entity = commands.spawn(SpatialBundle {
+                    transform: Transform::from_xyz(x, size.y / 2.0, z),
+                    ..default()
+                });
+                entity.with_children(|parent| {
+                    parent
+                        .spawn(PbrBundle {
+                            mesh: meshes.add(Mesh::from(shape::Quad { size, ..default() })),
+                            material: material_handle.clone(),
+                            ..default()
+                        })
+                        .insert(Billboard)
+                        .insert(ContactShadow);
+                    parent
+                        .spawn(PbrBundle {
+                            mesh: plane_handle.clone(),
+                            material: materials.add(StandardMaterial {
+                                base_color: Color::BLACK,
+                                base_color_texture: Some(
+                                    asset_server.load("textures/fx/blob_shadow.png"),
+                                ),
+                                alpha_mode: AlphaMode::Blend,
+                                unlit: true,
+                                ..default()
+                            }),
+                            transform: Transform::from_xyz(0.0, -size.y / 2.0, 0.0),
+                            ..default()
+                        })
+                        .insert(BlobShadow);
+                });
+                if prop.collider {
+                    entity.insert(Collider::cylinder(size.y, size.x / 4.0));
+                }
+                entity
+            }
+            PropMesh::Cube { .. } => {
+                let mut entity = commands.spawn(PbrBundle {
+                    mesh: meshes.add(Mesh::from(shape::Cube { size: scale })),
+                    material: material_handle.clone(),
+                    transform: Transform::from_xyz(x, scale / 2.0, z),
+                    ..default()
+                });
+                if prop.collider {
+                    entity.insert(Collider::cuboid(scale, scale, scale));
+                }
+                entity
+            }
+        };
+        entity.insert(MapEntity);
+        if prop.collider {
+            entity
+                .insert(RigidBody::Static)
+                .insert(CollisionLayers::new(
+                    [Layer::Ground],
+                    [Layer::Enemy, Layer::Player],
+                ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map_app() -> App {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, AssetPlugin::default()))
+            .init_asset::<Mesh>()
+            .init_asset::<Image>()
+            .init_asset::<StandardMaterial>()
+            .init_resource::<Config>()
+            .init_resource::<CurrentMap>()
+            .init_resource::<Gravity>()
+            .add_event::<LoadMap>()
+            .add_event::<ReloadMap>()
+            .add_systems(Update, load_map);
+        app
+    }
+
+    fn map_entities(app: &mut App) -> Vec<Entity> {
+        app.world
+            .query_filtered::<Entity, With<MapEntity>>()
+            .iter(&app.world)
+            .collect()
+    }
+
+    fn crates(count: u32, scale: (f32, f32)) -> PropConfig {
+        PropConfig {
+            mesh: PropMesh::Cube {
+                color: (0.5, 0.3, 0.1),
+            },
+            count,
+            scale,
+            collider: true,
+        }
+    }
+
+    #[test]
+    fn prop_count_matches_config() {
+        assert_eq!(prop_count(&crates(32, (1.0, 1.0))), 32);
+        assert_eq!(prop_count(&crates(128, (0.5, 2.0))), 128);
+    }
+
+    #[test]
+    fn prop_count_is_clamped() {
+        assert_eq!(prop_count(&crates(u32::MAX, (1.0, 1.0))), MAX_PROP_COUNT);
+    }
+
+    #[test]
+    fn invalid_scale_skips_props() {
+        assert_eq!(prop_count(&crates(32, (0.0, 1.0))), 0);
+        assert_eq!(prop_count(&crates(32, (-1.0, 1.0))), 0);
+        assert_eq!(prop_count(&crates(32, (2.0, 1.0))), 0);
+        assert_eq!(prop_count(&crates(32, (f32::NAN, 1.0))), 0);
+    }
+
+    #[test]
+    fn loading_a_map_replaces_the_old_one() {
+        let name = "load_map_test";
+        let path = std::path::Path::new("maps")
+            .join(name)
+            .with_extension("ron");
+        std::fs::create_dir_all("maps").unwrap();
+        std::fs::write(&path, "(size: 2, seed: 1)").unwrap();
+
+        let mut app = map_app();
+        app.world.send_event(ReloadMap);
+        app.update();
+        let old_map = map_entities(&mut app);
+        assert!(!old_map.is_empty());
+
+        app.world.send_event(LoadMap {
+            name: name.to_string(),
+            seed: Some(7),
+        });
+        app.update();
+        std::fs::remove_file(&path).unwrap();
+        let _ = std::fs::remove_dir("maps");
+
+        assert!(old_map
+            .iter()
+            .all(|&entity| app.world.get_entity(entity).is_none()));
+        // Four tiles, the ground collider and the light
+        assert_eq!(map_entities(&mut app).len(), 6);
+        let config = app.world.resource::<Config>();
+        assert_eq!((config.map.size, config.map.seed), (2, 7));
+        assert_eq!(app.world.resource::<CurrentMap>().0.as_deref(), Some(name));
+    }
+
+    #[test]
+    fn reloading_leaves_nothing_behind() {
+        let mut app = map_app();
+        let player = app.world.spawn(TransformBundle::default()).id();
+        app.world.send_event(ReloadMap);
+        app.update();
+        // Counting every entity catches children left behind, like the props' shadows
+        let entity_count = app.world.entities().len();
+
+        for _ in 0..2 {
+            let old_map = map_entities(&mut app);
+            app.world.send_event(ReloadMap);
+            app.update();
+            assert!(old_map
+                .iter()
+                .all(|&entity| app.world.get_entity(entity).is_none()));
+            assert_eq!(map_entities(&mut app).len(), old_map.len());
+            assert_eq!(app.world.entities().len(), entity_count);
+        }
+        assert!(app.world.get_entity(player).is_some());
     }
 }
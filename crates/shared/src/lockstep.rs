@@ -0,0 +1,125 @@
+// Experimental lockstep networking, see NetworkMode. The server relays everyone's
+// inputs once it has one from each player and clients step all players with them at
+// the same fixed rate, so they only stay in sync as long as movement is deterministic.
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use bevy::ecs::schedule::ScheduleLabel;
+use bevy::prelude::*;
+use bevy_renet::renet::ClientId;
+use bevy_xpbd_3d::components::{LinearVelocity, Position, RigidBody};
+use bevy_xpbd_3d::plugins::spatial_query::SpatialQuery;
+use bevy_xpbd_3d::resources::PhysicsTimestep;
+
+use crate::config::{Config, NetworkMode};
+use crate::networking::{state_hash, ClientLobby, Player, PlayerCommand, STATE_HASH_INTERVAL};
+use crate::player::{self, PlayerInput};
+use crate::triggers;
+
+// Everything that moves players, run once per step
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LockstepStep;
+
+// Steps received from the server and not yet simulated
+#[derive(Debug, Default, Resource)]
+pub struct LockstepSteps(pub VecDeque<(u32, Vec<(ClientId, PlayerInput)>)>);
+
+// Stands in for Time while stepping, advancing by exactly one timestep per step
+#[derive(Default, Resource)]
+struct LockstepClock(Time);
+
+pub struct LockstepPlugin;
+
+impl Plugin for LockstepPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LockstepSteps>()
+            .init_resource::<LockstepClock>()
+            .add_schedule(Schedule::new(LockstepStep))
+            .add_systems(
+                LockstepStep,
+                (
+                    triggers::check_water,
+                    player::player_move,
+                    triggers::teleport_players,
+                    triggers::launch_players,
+                    integrate_players,
+                    update_spatial_query,
+                )
+                    .chain(),
+            );
+    }
+}
+
+pub fn lockstep_enabled(config: Res<Config>) -> bool {
+    config.network.mode == NetworkMode::Lockstep
+}
+
+// Seconds simulated per step, the physics timestep so movement feels the same
+pub fn step_seconds(timestep: &PhysicsTimestep) -> f32 {
+    match timestep {
+        PhysicsTimestep::Fixed(dt) | PhysicsTimestep::FixedOnce(dt) => *dt,
+        PhysicsTimestep::Variable { max_dt } => *max_dt,
+    }
+}
+
+// Physics steps on its own clock, so players are made static and moved by the steps
+// instead
+pub fn freeze_players(mut players: Query<&mut RigidBody, Added<Player>>) {
+    for mut rigid_body in players.iter_mut() {
+        *rigid_body = RigidBody::Static;
+    }
+}
+
+fn integrate_players(
+    time: Res<Time>,
+    mut players: Query<(&LinearVelocity, &mut Transform, Option<&mut Position>)>,
+) {
+    for (velocity, mut transform, position) in players.iter_mut() {
+        transform.translation += velocity.0 * time.delta_seconds();
+        // Physics only copies it over on its own step, after all of this frame's steps
+        if let Some(mut position) = position {
+            position.0 = transform.translation;
+        }
+    }
+}
+
+// Several steps can run in one frame, and each has to sweep and check triggers against
+// where the previous one left the players
+fn update_spatial_query(mut spatial_query: SpatialQuery) {
+    spatial_query.update_pipeline();
+}
+
+pub fn run_lockstep_steps(world: &mut World) {
+    let step = Duration::from_secs_f32(step_seconds(world.resource::<PhysicsTimestep>()));
+    while let Some((tick, inputs)) = world.resource_mut::<LockstepSteps>().0.pop_front() {
+        let lobby = world.resource::<ClientLobby>();
+        let inputs: Vec<_> = inputs
+            .into_iter()
+            .filter_map(|(id, input)| {
+                lobby
+                    .players
+                    .get(&id)
+                    .map(|info| (info.client_entity, input))
+            })
+            .collect();
+        for (entity, input) in inputs {
+            if let Some(mut entity) = world.get_entity_mut(entity) {
+                entity.insert(input);
+            }
+        }
+
+        world.resource_mut::<LockstepClock>().0.advance_by(step);
+        let clock = world.resource::<LockstepClock>().0;
+        let time = std::mem::replace(&mut *world.resource_mut::<Time>(), clock);
+        world.run_schedule(LockstepStep);
+        *world.resource_mut::<Time>() = time;
+
+        if tick.is_multiple_of(STATE_HASH_INTERVAL) {
+            let mut players = world.query::<(&Player, &Transform, &LinearVelocity)>();
+            let hash = state_hash(players.iter(world).map(|(player, transform, velocity)| {
+                (player.id, transform.translation, velocity.0)
+            }));
+            world.send_event(PlayerCommand::StateHash { tick, hash });
+        }
+    }
+}
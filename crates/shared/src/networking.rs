@@ -1,17 +1,172 @@
 use bevy::{prelude::*, utils::HashMap};
 use bevy_renet::renet::{
-    ChannelConfig, ConnectionConfig, SendType,
-    transport::NETCODE_KEY_BYTES, ClientId,
+    transport::{NETCODE_KEY_BYTES, NETCODE_USER_DATA_BYTES},
+    ChannelConfig, ClientId, ConnectionConfig, SendType,
 };
 use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::time::Duration;
 
+use crate::config::{Delivery, NetworkConfig};
+use crate::player::PlayerInput;
+use crate::weapons::WeaponKind;
+
 pub const PRIVATE_KEY: &[u8; NETCODE_KEY_BYTES] = b"an example very very secret key."; // 32-bytes
-pub const PROTOCOL_ID: u64 = 7;
+
+// Netcode drops connections with a different protocol id without a word, so this stays
+// put and the versions are compared by the game itself using PROTOCOL_VERSION
+pub const PROTOCOL_ID: u64 = 18;
+// Bump whenever the messages change
+pub const PROTOCOL_VERSION: u32 = 27;
+// Shown alongside the protocol version when builds don't match, to tell them apart
+pub const BUILD_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Layout of the user data sent when connecting: protocol version, whether to join as a
+// spectator, build version and then the player name, the strings zero padded and cut
+// off to fit
+const USER_DATA_SPECTATOR: usize = 4;
+const USER_DATA_BUILD_START: usize = 5;
+const USER_DATA_NAME_START: usize = 37;
+
+pub struct Handshake {
+    pub protocol_version: u32,
+    pub spectator: bool,
+    pub build_version: String,
+    pub name: String,
+}
+
+impl Handshake {
+    pub fn new(name: &str, spectator: bool) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            spectator,
+            build_version: BUILD_VERSION.into(),
+            name: name.into(),
+        }
+    }
+
+    pub fn is_compatible(&self) -> bool {
+        self.protocol_version == PROTOCOL_VERSION
+    }
+
+    // What the server sends back before refusing a client it can't talk to
+    pub fn rejection(&self) -> Option<ServerMessages> {
+        (!self.is_compatible()).then(|| ServerMessages::IncompatibleVersion {
+            protocol_version: PROTOCOL_VERSION,
+            build_version: BUILD_VERSION.into(),
+        })
+    }
+
+    pub fn to_user_data(&self) -> [u8; NETCODE_USER_DATA_BYTES] {
+        let mut user_data = [0; NETCODE_USER_DATA_BYTES];
+        user_data[..USER_DATA_SPECTATOR].copy_from_slice(&self.protocol_version.to_le_bytes());
+        user_data[USER_DATA_SPECTATOR] = self.spectator as u8;
+        write_str(
+            &mut user_data[USER_DATA_BUILD_START..USER_DATA_NAME_START],
+            &self.build_version,
+        );
+        write_str(&mut user_data[USER_DATA_NAME_START..], &self.name);
+        user_data
+    }
+
+    pub fn from_user_data(user_data: &[u8; NETCODE_USER_DATA_BYTES]) -> Self {
+        let mut protocol_version = [0; USER_DATA_SPECTATOR];
+        protocol_version.copy_from_slice(&user_data[..USER_DATA_SPECTATOR]);
+        Self {
+            protocol_version: u32::from_le_bytes(protocol_version),
+            spectator: user_data[USER_DATA_SPECTATOR] != 0,
+            build_version: read_str(&user_data[USER_DATA_BUILD_START..USER_DATA_NAME_START]),
+            name: read_str(&user_data[USER_DATA_NAME_START..]),
+        }
+    }
+}
+
+fn write_str(dest: &mut [u8], value: &str) {
+    let mut len = value.len().min(dest.len());
+    while !value.is_char_boundary(len) {
+        len -= 1;
+    }
+    dest[..len].copy_from_slice(&value.as_bytes()[..len]);
+}
+
+fn read_str(src: &[u8]) -> String {
+    let len = src.iter().position(|byte| *byte == 0).unwrap_or(src.len());
+    String::from_utf8_lossy(&src[..len]).into_owned()
+}
+
+// Ticks between state hashes, checked against each other to catch desyncs
+pub const STATE_HASH_INTERVAL: u32 = 60;
+// Hashes are kept for this many ticks waiting on the other side's, frames can be lost
+pub const STATE_HASH_HISTORY: u32 = STATE_HASH_INTERVAL * 10;
+
+// Positions and velocities rounded to a millimeter so float noise in the last few bits
+// doesn't count as a desync
+pub fn state_hash(players: impl Iterator<Item = (ClientId, Vec3, Vec3)>) -> u64 {
+    let quantize = |value: Vec3| (value * 1000.0).round().as_ivec3().to_array();
+    let mut state: Vec<_> = players
+        .map(|(id, translation, velocity)| (id.raw(), quantize(translation), quantize(velocity)))
+        .collect();
+    state.sort_unstable_by_key(|(id, ..)| *id);
+    let mut hasher = DefaultHasher::new();
+    state.hash(&mut hasher);
+    hasher.finish()
+}
+
+// The client's hashes of the frames it applied and the server's for the same ticks,
+// compared once both have arrived
+#[derive(Debug, Default, Resource)]
+pub struct StateHashes {
+    applied: HashMap<u32, u64>,
+    server: HashMap<u32, u64>,
+}
+
+impl StateHashes {
+    // Both return whether the hashes matched, once there's one of each for the tick
+    pub fn applied(&mut self, tick: u32, hash: u64) -> Option<bool> {
+        self.prune(tick);
+        match self.server.remove(&tick) {
+            Some(server_hash) => Some(server_hash == hash),
+            None => {
+                self.applied.insert(tick, hash);
+                None
+            }
+        }
+    }
+
+    pub fn server(&mut self, tick: u32, hash: u64) -> Option<bool> {
+        self.prune(tick);
+        match self.applied.remove(&tick) {
+            Some(applied_hash) => Some(applied_hash == hash),
+            None => {
+                self.server.insert(tick, hash);
+                None
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.applied.clear();
+        self.server.clear();
+    }
+
+    fn prune(&mut self, tick: u32) {
+        let oldest = tick.saturating_sub(STATE_HASH_HISTORY);
+        self.applied.retain(|hash_tick, _| *hash_tick >= oldest);
+        self.server.retain(|hash_tick, _| *hash_tick >= oldest);
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Team {
+    Jinrai,
+    Nsf,
+}
 
 #[derive(Debug, Component)]
 pub struct Player {
     pub id: ClientId,
+    pub team: Team,
 }
 
 #[derive(Debug, Default, Resource)]
@@ -20,6 +175,10 @@ pub struct MostRecentTick(pub Option<u32>);
 #[derive(Debug, Serialize, Deserialize, Component, Event)]
 pub enum PlayerCommand {
     BasicAttack { cast_at: Vec3 },
+    // Lockstep only, a hash of every player's state after the given step
+    StateHash { tick: u32, hash: u64 },
+    // Asks for every player to be sent again after a desync
+    RequestResync,
 }
 
 pub enum ClientChannel {
@@ -32,25 +191,78 @@ pub enum ServerChannel {
     NetworkedEntities,
 }
 
+// Sent in batches, as a Vec of everything the server had for the client that frame
 #[derive(Debug, Serialize, Deserialize, Component)]
 pub enum ServerMessages {
+    // Has to stay first and unchanged so any build can read it
+    IncompatibleVersion {
+        protocol_version: u32,
+        build_version: String,
+    },
     PlayerCreate {
         entity: Entity,
         id: ClientId,
         translation: [f32; 3],
+        yaw: f32,
+        loadout: Vec<WeaponKind>,
+        team: Team,
     },
     PlayerRemove {
         id: ClientId,
     },
+    PlayerDamage {
+        id: ClientId,
+        amount: f32,
+        remaining: f32,
+    },
+    // Sent instead of PlayerRemove when a player runs out of health, they stay connected
+    // and watch like a spectator. No killer for deaths like falls.
+    PlayerDeath {
+        id: ClientId,
+        killer: Option<ClientId>,
+    },
+    HitConfirm {
+        target: Entity,
+        damage: f32,
+    },
+    // Sent before the server exits so clients can leave instead of timing out
+    ServerShutdown,
+    // Hash of the players in the frame with the same tick
+    StateHash {
+        tick: u32,
+        hash: u64,
+    },
+    // Lockstep only, everyone's input for the next step
+    LockstepStep {
+        tick: u32,
+        inputs: Vec<(ClientId, PlayerInput)>,
+    },
+    // The server moved on to another map, see LoadMap
+    LoadMap {
+        name: String,
+        seed: u64,
+    },
+}
+
+// Players only turn around the up axis, so that's all that goes over the wire
+pub fn networked_yaw(rotation: Quat) -> f32 {
+    rotation.to_euler(EulerRot::YXZ).0
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct NetworkedEntities {
     pub entities: Vec<Entity>,
     pub translations: Vec<[f32; 3]>,
-    pub rotations: Vec<[f32; 4]>,
+    // Players only ever turn around the up axis, towards where they aim
+    pub yaws: Vec<f32>,
     pub velocities: Vec<[f32; 3]>,
     pub groundeds: Vec<bool>,
+    pub jumps: Vec<u8>,
+    pub in_water: Vec<bool>,
+    pub weapons: Vec<u8>,
+    // Crouching changes the hitbox, walking only the speed
+    pub crouchings: Vec<bool>,
+    pub walkings: Vec<bool>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -99,33 +311,35 @@ impl From<ServerChannel> for u8 {
 }
 
 impl ServerChannel {
-    pub fn channels_config() -> Vec<ChannelConfig> {
+    pub fn channels_config(config: &NetworkConfig) -> Vec<ChannelConfig> {
+        let resend_time = Duration::from_secs_f32(config.resend_time.max(0.0));
         vec![
             ChannelConfig {
                 channel_id: Self::NetworkedEntities.into(),
                 max_memory_usage_bytes: 10 * 1024 * 1024,
-                send_type: SendType::Unreliable,
+                send_type: match config.entity_updates {
+                    Delivery::Unreliable => SendType::Unreliable,
+                    // Stale frames are dropped on arrival, so order doesn't matter
+                    Delivery::Reliable => SendType::ReliableUnordered { resend_time },
+                },
             },
             ChannelConfig {
                 channel_id: Self::ServerMessages.into(),
                 max_memory_usage_bytes: 10 * 1024 * 1024,
-                send_type: SendType::ReliableOrdered {
-                    resend_time: Duration::from_millis(200),
-                },
+                send_type: SendType::ReliableOrdered { resend_time },
             },
         ]
     }
 }
 
-pub fn connection_config() -> ConnectionConfig {
+pub fn connection_config(config: &NetworkConfig) -> ConnectionConfig {
     ConnectionConfig {
-        available_bytes_per_tick: 1024 * 1024,
+        available_bytes_per_tick: config.available_bytes_per_tick,
         client_channels_config: ClientChannel::channels_config(),
-        server_channels_config: ServerChannel::channels_config(),
+        server_channels_config: ServerChannel::channels_config(config),
     }
 }
 
-
 #[derive(Default, Resource)]
 pub struct NetworkMapping(pub HashMap<Entity, Entity>);
 
@@ -139,3 +353,171 @@ pub struct PlayerInfo {
 pub struct ClientLobby {
     pub players: HashMap<ClientId, PlayerInfo>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn players(z: f32) -> Vec<(ClientId, Vec3, Vec3)> {
+        vec![
+            (ClientId::from_raw(1), Vec3::new(1.0, 0.0, 2.0), Vec3::X),
+            (ClientId::from_raw(2), Vec3::new(-3.0, 1.0, z), Vec3::ZERO),
+        ]
+    }
+
+    #[test]
+    fn handshake_round_trips() {
+        let handshake = Handshake::from_user_data(&Handshake::new("Kagami", true).to_user_data());
+        assert_eq!(handshake.protocol_version, PROTOCOL_VERSION);
+        assert!(handshake.spectator);
+        assert_eq!(handshake.build_version, BUILD_VERSION);
+        assert_eq!(handshake.name, "Kagami");
+        assert!(handshake.is_compatible());
+        assert!(handshake.rejection().is_none());
+    }
+
+    #[test]
+    fn mismatched_protocol_is_rejected() {
+        let mut user_data = Handshake::new("Kagami", false).to_user_data();
+        user_data[..USER_DATA_SPECTATOR].copy_from_slice(&(PROTOCOL_VERSION - 1).to_le_bytes());
+        let handshake = Handshake::from_user_data(&user_data);
+        assert!(!handshake.is_compatible());
+
+        // The reply has to be readable by the other build, whatever else changed
+        let reply = bincode::serialize(&vec![handshake.rejection().unwrap()]).unwrap();
+        let received: Vec<ServerMessages> = bincode::deserialize(&reply).unwrap();
+        match received.as_slice() {
+            [ServerMessages::IncompatibleVersion {
+                protocol_version,
+                build_version,
+            }] => {
+                assert_eq!(*protocol_version, PROTOCOL_VERSION);
+                assert_eq!(build_version, BUILD_VERSION);
+            }
+            other => panic!("expected an IncompatibleVersion reply, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn long_names_are_cut_at_a_char_boundary() {
+        let mut dest = [0; 4];
+        write_str(&mut dest, "aéé");
+        assert_eq!(read_str(&dest), "aé");
+
+        let name = "é".repeat(NETCODE_USER_DATA_BYTES);
+        let handshake = Handshake::from_user_data(&Handshake::new(&name, false).to_user_data());
+        let room = NETCODE_USER_DATA_BYTES - USER_DATA_NAME_START;
+        assert_eq!(handshake.name, "é".repeat(room / 2));
+    }
+
+    #[test]
+    fn server_messages_round_trip() {
+        let batch = vec![
+            ServerMessages::PlayerCreate {
+                entity: Entity::from_raw(3),
+                id: ClientId::from_raw(1),
+                translation: [1.0, 2.0, 3.0],
+                yaw: 0.5,
+                loadout: vec![WeaponKind::Rifle, WeaponKind::Knife],
+                team: Team::Jinrai,
+            },
+            ServerMessages::PlayerDeath {
+                id: ClientId::from_raw(1),
+                killer: None,
+            },
+            ServerMessages::StateHash { tick: 60, hash: 42 },
+        ];
+        let message = bincode::serialize(&batch).unwrap();
+        let received: Vec<ServerMessages> = bincode::deserialize(&message).unwrap();
+        assert_eq!(format!("{:?}", received), format!("{:?}", batch));
+    }
+
+    #[test]
+    fn resend_time_is_applied_to_the_channels() {
+        let config = NetworkConfig {
+            entity_updates: Delivery::Reliable,
+            resend_time: 0.5,
+            ..default()
+        };
+        let resend_time = Duration::from_millis(500);
+        let channels = ServerChannel::channels_config(&config);
+        assert!(matches!(
+            channels[0].send_type,
+            SendType::ReliableUnordered { resend_time: time } if time == resend_time
+        ));
+        assert!(matches!(
+            channels[1].send_type,
+            SendType::ReliableOrdered { resend_time: time } if time == resend_time
+        ));
+
+        // Unreliable updates have nothing to resend
+        let channels = ServerChannel::channels_config(&NetworkConfig::default());
+        assert!(matches!(channels[0].send_type, SendType::Unreliable));
+        assert!(matches!(
+            channels[1].send_type,
+            SendType::ReliableOrdered { resend_time: time } if time == Duration::from_secs_f32(0.2)
+        ));
+    }
+
+    #[test]
+    fn network_frames_round_trip() {
+        let frame = NetworkFrame {
+            tick: 120,
+            entities: NetworkedEntities {
+                entities: vec![Entity::from_raw(3), Entity::from_raw(5)],
+                translations: vec![[1.0, 2.0, 3.0], [-4.0, 0.5, 6.0]],
+                yaws: vec![0.25, -1.5],
+                velocities: vec![[0.0, -2.0, 1.0], [3.0, 0.0, 0.0]],
+                groundeds: vec![false, true],
+                jumps: vec![1, 0],
+                in_water: vec![false, true],
+                weapons: vec![0, 2],
+                crouchings: vec![true, false],
+                walkings: vec![false, true],
+            },
+        };
+        let message = bincode::serialize(&frame).unwrap();
+        let received: NetworkFrame = bincode::deserialize(&message).unwrap();
+        assert_eq!(received.tick, 120);
+        assert_eq!(format!("{:?}", received), format!("{:?}", frame));
+    }
+
+    #[test]
+    fn same_state_hashes_match() {
+        let mut hashes = StateHashes::default();
+        assert_eq!(
+            hashes.applied(60, state_hash(players(0.5).into_iter())),
+            None
+        );
+        // Neither the order of players nor noise under a millimeter counts
+        let server = state_hash(players(0.5001).into_iter().rev());
+        assert_eq!(hashes.server(60, server), Some(true));
+    }
+
+    #[test]
+    fn diverged_state_hashes_mismatch() {
+        let mut hashes = StateHashes::default();
+        assert_eq!(
+            hashes.server(60, state_hash(players(0.5).into_iter())),
+            None
+        );
+        let applied = state_hash(players(0.6).into_iter());
+        assert_eq!(hashes.applied(60, applied), Some(false));
+    }
+
+    #[test]
+    fn old_state_hashes_are_pruned() {
+        let mut hashes = StateHashes::default();
+        assert_eq!(hashes.applied(60, 1), None);
+        // The server's hash for 60 arrives only after the history has moved past it
+        let later = 60 + STATE_HASH_HISTORY + STATE_HASH_INTERVAL;
+        assert_eq!(hashes.applied(later, 2), None);
+        assert_eq!(hashes.server(60, 1), None);
+        assert_eq!(hashes.server(later, 2), Some(true));
+        assert!(hashes.applied.is_empty());
+        assert!(hashes
+            .server
+            .keys()
+            .all(|tick| *tick >= later - STATE_HASH_HISTORY));
+    }
+}
@@ -1,3 +1,4 @@
+use bevy::prelude::*;
 use bevy_xpbd_3d::prelude::*;
 
 #[derive(PhysicsLayer)]
@@ -5,4 +6,10 @@ pub enum Layer {
     Player,
     Enemy,
     Ground,
+    Trigger,
 }
+
+// Replaces the configured ground friction for players standing on this entity, for
+// slippery or sticky patches of ground
+#[derive(Component)]
+pub struct SurfaceFriction(pub f32);
@@ -1,16 +1,26 @@
-use crate::config::Config;
+use crate::config::{Config, FrictionModel, JumpMode, MapConfig, PhysicsConfig};
 use crate::input::*;
 use crate::networking::ClientLobby;
 use crate::networking::MostRecentTick;
 use crate::networking::NetworkMapping;
 use crate::networking::Player;
 use crate::networking::PlayerInfo;
-use crate::physics::Layer;
+use crate::networking::Team;
+use crate::physics::{Layer, SurfaceFriction};
+use crate::settings::{CameraMode, ControlScheme, CrosshairMode, Settings};
 use crate::sprites::*;
+use crate::weapons;
+use crate::weapons::Deploying;
+use crate::weapons::Inventory;
+use crate::weapons::Recoil;
+use crate::weapons::WeaponKind;
 use crate::MainCamera;
+use bevy::ecs::system::EntityCommands;
+use bevy::input::mouse::MouseMotion;
 use bevy::prelude::shape::Icosphere;
 use bevy::prelude::shape::Plane;
 use bevy::prelude::*;
+use bevy::utils::HashMap;
 use bevy::window::PrimaryWindow;
 use bevy_renet::renet::ClientId;
 use bevy_xpbd_3d::components::CoefficientCombine;
@@ -25,12 +35,16 @@ use bevy_xpbd_3d::plugins::spatial_query::SpatialQuery;
 use bevy_xpbd_3d::plugins::spatial_query::SpatialQueryFilter;
 use bevy_xpbd_3d::resources::Gravity;
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 
 pub struct ClientPlayerPlugin;
 
 impl Plugin for ClientPlayerPlugin {
     fn build(&self, app: &mut App) {
         app.add_event::<SpawnPlayer>()
+            .add_event::<Landed>()
+            .init_resource::<Spectate>()
+            .init_resource::<MostRecentTick>()
             .add_systems(Startup, setup_player);
     }
 }
@@ -38,7 +52,9 @@ impl Plugin for ClientPlayerPlugin {
 pub struct ServerPlayerPlugin;
 
 impl Plugin for ServerPlayerPlugin {
-    fn build(&self, _app: &mut App) {}
+    fn build(&self, app: &mut App) {
+        app.add_event::<Landed>().add_event::<FallDamage>();
+    }
 }
 
 #[derive(Resource)]
@@ -82,51 +98,267 @@ pub struct SpawnPlayer {
     pub id: ClientId,
     pub entity: Entity,
     pub position: Vec3,
+    pub yaw: f32,
     pub is_local: bool,
+    pub loadout: Vec<WeaponKind>,
+    pub team: Team,
 }
 
+// Canonical player dimensions, used by the collider, the debug capsule, the ground check
+// and the blob shadow. The origin sits halfway up the capsule.
+pub const PLAYER_RADIUS: f32 = 0.25;
+// Distance between the centers of the capsule's end caps
+pub const PLAYER_CAPSULE_LENGTH: f32 = 0.5;
+pub const PLAYER_HEIGHT: f32 = PLAYER_CAPSULE_LENGTH + 2.0 * PLAYER_RADIUS;
+pub const PLAYER_HALF_HEIGHT: f32 = PLAYER_HEIGHT / 2.0;
+// Crouching shrinks the capsule down to a ball, keeping the feet where they were
+pub const PLAYER_CROUCH_CAPSULE_LENGTH: f32 = 0.0;
+const CROUCH_HEIGHT_DIFFERENCE: f32 = PLAYER_CAPSULE_LENGTH - PLAYER_CROUCH_CAPSULE_LENGTH;
+// Height above the ground players spawn at, so they don't start out overlapping it
+pub const PLAYER_SPAWN_HEIGHT: f32 = PLAYER_HALF_HEIGHT + 0.01;
+// How far the camera sits from the point it follows, along the angle in the settings
+pub const CAMERA_DISTANCE: f32 = 10.392305;
+// How far below the feet the ground still counts as underfoot
+const GROUND_CHECK_DISTANCE: f32 = 0.1;
+
 #[derive(Component)]
 pub struct LocalPlayer;
 
+// Player that left and is fading out before being removed. The server entity stays
+// mapped until then, so it can't be confused with anything else in the meantime.
+#[derive(Component)]
+pub struct Despawning {
+    pub timer: Timer,
+    pub server_entity: Entity,
+}
+
+// Other player the camera follows instead of our own, if any
+#[derive(Resource, Default)]
+pub struct Spectate(pub Option<Entity>);
+
+// Present when we joined to watch and have no player of our own
+#[derive(Resource)]
+pub struct Spectator;
+
 #[derive(Component)]
 pub struct IsGrounded(pub bool);
 
+// Downward speed going into the last move while airborne, which is how hard the
+// player hits the ground when they land
+#[derive(Component, Default)]
+pub struct FallSpeed(pub f32);
+
+#[derive(Event)]
+pub struct Landed {
+    pub entity: Entity,
+    pub speed: f32,
+}
+
+#[derive(Event)]
+pub struct FallDamage {
+    pub target: Entity,
+    pub damage: f32,
+}
+
+// Whether the player's center is inside a water volume, which switches them to swimming
+#[derive(Component)]
+pub struct InWater(pub bool);
+
+// Only the server deals damage, clients are told what's left
+#[derive(Debug, Component, Clone, Copy)]
+pub struct Health {
+    pub current: f32,
+    pub max: f32,
+}
+
+impl Health {
+    pub fn new(max: f32) -> Self {
+        Self { current: max, max }
+    }
+}
+
+#[derive(Component, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stance {
+    pub crouching: bool,
+    pub walking: bool,
+}
+
+impl Stance {
+    // Stance the input asks for. Crouch swims down in water instead.
+    fn from_input(input: &PlayerInput, in_water: bool) -> Self {
+        Self {
+            crouching: input.crouch && !in_water,
+            walking: input.walk,
+        }
+    }
+
+    fn capsule_length(&self) -> f32 {
+        if self.crouching {
+            PLAYER_CROUCH_CAPSULE_LENGTH
+        } else {
+            PLAYER_CAPSULE_LENGTH
+        }
+    }
+
+    pub fn half_height(&self) -> f32 {
+        if self.crouching {
+            PLAYER_HALF_HEIGHT - CROUCH_HEIGHT_DIFFERENCE / 2.0
+        } else {
+            PLAYER_HALF_HEIGHT
+        }
+    }
+
+    // Doubles as the hitbox, so it has to match on the server for hits to land right
+    pub fn collider(&self) -> Collider {
+        let capsule = self.capsule();
+        Collider::capsule(capsule.depth, capsule.radius)
+    }
+
+    // The collider's shape, also drawn as the server's debug view of the player
+    fn capsule(&self) -> shape::Capsule {
+        shape::Capsule {
+            depth: self.capsule_length(),
+            radius: PLAYER_RADIUS,
+            ..default()
+        }
+    }
+
+    fn speed(&self, config: &Config) -> f32 {
+        if self.crouching {
+            config.physics.crouch_speed
+        } else if self.walking {
+            config.physics.walk_speed
+        } else {
+            config.physics.ground_speed
+        }
+    }
+}
+
+// Jumps taken since last standing on the ground. A press jumps once and is used up,
+// after which it takes letting go of jump (or holding it in Hold mode, on the ground)
+// and the cooldown running out to jump again:
+//
+//   ready --press--> jumped --released--> ready
+//                      \--landed, held in Hold mode--> ready
+//
+// with the cooldown gating every jump out of ready.
+#[derive(Component, Default)]
+pub struct JumpCount {
+    pub count: u32,
+    // Whether jump was let go since the last jump. Air jumps always need a fresh press,
+    // so holding jump doesn't spend them all at once.
+    pub released: bool,
+    // Seconds left before jumping again
+    pub cooldown: f32,
+}
+
+impl JumpCount {
+    // Catches up on the ground state and input since the last step
+    fn update(&mut self, is_grounded: bool, released: bool, delta_time: f32) {
+        if is_grounded {
+            self.count = 0;
+        } else if self.count == 0 {
+            // Walking off a ledge uses up the ground jump
+            self.count = 1;
+        }
+        if released {
+            self.released = true;
+        }
+        self.cooldown = (self.cooldown - delta_time).max(0.0);
+    }
+
+    fn can_jump(&self, is_grounded: bool, physics: &PhysicsConfig) -> bool {
+        let can_ground_jump = is_grounded && (self.released || physics.jump_mode == JumpMode::Hold);
+        let can_air_jump = self.released && self.count < physics.max_jumps;
+        self.cooldown <= 0.0 && (can_ground_jump || can_air_jump)
+    }
+
+    fn jump(&mut self, physics: &PhysicsConfig) {
+        self.count += 1;
+        self.released = false;
+        self.cooldown = physics.jump_cooldown;
+    }
+}
+
+pub fn player_collision_layers(config: &Config) -> CollisionLayers {
+    let layers = CollisionLayers::new([Layer::Player], [Layer::Enemy, Layer::Ground]);
+    if config.physics.player_collision {
+        layers.add_mask(Layer::Player)
+    } else {
+        layers
+    }
+}
+
+// Identity and physics shared by both ends, so the server and client bodies can't drift
+// apart. The kinematic body is moved by player_move rather than the solver, and on the
+// client the synced velocity carries it between snapshots.
+pub fn player_bundle(config: &Config, id: ClientId, team: Team) -> impl Bundle {
+    (
+        Player { id, team },
+        RigidBody::Kinematic,
+        LockedAxes::ROTATION_LOCKED,
+        Stance::default().collider(),
+        player_collision_layers(config),
+        Friction::new(0.0).with_combine_rule(CoefficientCombine::Min),
+        Restitution::new(0.0).with_combine_rule(CoefficientCombine::Min),
+        IsGrounded(true),
+        JumpCount::default(),
+        FallSpeed::default(),
+        InWater(false),
+        Stance::default(),
+        Health::new(config.max_health),
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn server_spawn_player(
     commands: &mut Commands,
+    config: &Config,
     materials: &mut ResMut<Assets<StandardMaterial>>,
     meshes: &mut ResMut<Assets<Mesh>>,
     client_id: ClientId,
+    team: Team,
     transform: Transform,
+    loadout: &[WeaponKind],
 ) -> Entity {
-    commands
-        .spawn(PbrBundle {
-            mesh: meshes.add(Mesh::from(shape::Capsule {
-                depth: 0.5,
-                radius: 0.25,
-                ..default()
-            })),
-            material: materials.add(Color::rgb(0.8, 0.7, 0.6).into()),
-            transform,
-            ..Default::default()
-        })
-        .insert(RigidBody::Dynamic)
+    let mut player = commands.spawn(PbrBundle {
+        mesh: meshes.add(Mesh::from(Stance::default().capsule())),
+        material: materials.add(Color::rgb(0.8, 0.7, 0.6).into()),
+        transform,
+        ..Default::default()
+    });
+    player
+        .insert(player_bundle(config, client_id, team))
         // .insert(TransformInterpolation::default())
-        .insert(LockedAxes::ROTATION_LOCKED)
-        .insert(Collider::capsule(0.5, 0.25))
-        .insert(CollisionLayers::new(
-            [Layer::Player],
-            [Layer::Enemy, Layer::Ground],
-        ))
-        .insert(Friction::new(0.0).with_combine_rule(CoefficientCombine::Min))
-        .insert(Restitution::new(0.0).with_combine_rule(CoefficientCombine::Min))
-        .insert(PlayerInput::default())
-        .insert(IsGrounded(true))
-        .insert(Player { id: client_id })
-        .id()
+        .insert(PlayerInput::default());
+    let inventory = Inventory::spawn(&mut player, loadout);
+    player.insert(inventory).id()
 }
 
+// A create can come again for a player we already have, so keep the one entity and
+// follow the server's if it changed. Returns the player's entity when it did.
+fn remap_player(
+    lobby: &mut ClientLobby,
+    network_mapping: &mut NetworkMapping,
+    id: ClientId,
+    server_entity: Entity,
+) -> Option<Entity> {
+    let player_info = lobby.players.get_mut(&id)?;
+    if player_info.server_entity == server_entity {
+        return None;
+    }
+    network_mapping.0.remove(&player_info.server_entity);
+    network_mapping
+        .0
+        .insert(server_entity, player_info.client_entity);
+    player_info.server_entity = server_entity;
+    Some(player_info.client_entity)
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn client_spawn_players(
     mut commands: Commands,
+    config: Res<Config>,
     asset_server: Res<AssetServer>,
     mut materials: ResMut<Assets<StandardMaterial>>,
     mut meshes: ResMut<Assets<Mesh>>,
@@ -135,69 +367,41 @@ pub fn client_spawn_players(
     mut spawn_events: EventReader<SpawnPlayer>,
 ) {
     for spawn in spawn_events.read() {
-        // Player
-        let material_handle = materials.add(StandardMaterial {
-            alpha_mode: AlphaMode::Blend,
-            reflectance: 0.0,
-            metallic: 0.0,
-            perceptual_roughness: 1.0,
-            ..default()
-        });
-        let mesh_handle = meshes.add(Mesh::from(shape::Quad {
-            size: Vec2::new(1.0, 1.0),
-            ..default()
-        }));
+        if lobby.players.contains_key(&spawn.id) {
+            if let Some(client_entity) =
+                remap_player(&mut lobby, &mut network_mapping, spawn.id, spawn.entity)
+            {
+                commands.entity(client_entity).insert(
+                    Transform::from_translation(spawn.position)
+                        .with_rotation(Quat::from_rotation_y(spawn.yaw)),
+                );
+            }
+            continue;
+        }
 
+        // Player
         let mut player = commands.spawn(SpatialBundle {
-            transform: Transform::from_translation(spawn.position),
+            transform: Transform::from_translation(spawn.position)
+                .with_rotation(Quat::from_rotation_y(spawn.yaw)),
             ..default()
         });
-        player
-            .insert(Player { id: spawn.id })
-            // .insert(RigidBody::Dynamic)
-            .insert(Collider::capsule(0.5, 0.25))
-            .insert(CollisionLayers::new(
-                [Layer::Player],
-                [Layer::Enemy, Layer::Ground],
-            ))
-            .insert(LockedAxes::ROTATION_LOCKED)
-            .insert(Friction::new(0.0).with_combine_rule(CoefficientCombine::Min))
-            .insert(Restitution::new(0.0).with_combine_rule(CoefficientCombine::Min))
-            .insert(IsGrounded(true))
-            .with_children(|parent| {
-                // Sprite
-                parent
-                    .spawn(PbrBundle {
-                        mesh: mesh_handle,
-                        material: material_handle,
-                        ..default()
-                    })
-                    .insert(Billboard)
-                    .insert(Animator::new(asset_server.load("animations/nsf.anim")))
-                    .insert(Sequence::None);
-                // Blob shadow
-                parent
-                    .spawn(PbrBundle {
-                        mesh: meshes.add(Mesh::from(Plane::from_size(1.0))),
-                        material: materials.add(StandardMaterial {
-                            base_color: Color::BLACK,
-                            base_color_texture: Some(
-                                asset_server.load("textures/fx/blob_shadow.png"),
-                            ),
-                            alpha_mode: AlphaMode::Blend,
-                            unlit: true,
-                            ..default()
-                        }),
-                        transform: Transform::from_xyz(0.0, -0.5, 0.0),
-                        ..default()
-                    })
-                    .insert(BlobShadow);
-            });
+        player.insert(player_bundle(&config, spawn.id, spawn.team));
+        spawn_player_visuals(
+            &mut player,
+            spawn.team,
+            &asset_server,
+            &mut meshes,
+            &mut materials,
+        );
+
+        let inventory = Inventory::spawn(&mut player, &spawn.loadout);
+        player.insert(inventory);
 
         if spawn.is_local {
             player
                 .insert(LocalPlayer)
                 .insert(PlayerInput::default())
+                .insert(Recoil::default())
                 .with_children(|parent| {
                     // Light
                     parent.spawn(PointLightBundle {
@@ -220,6 +424,157 @@ pub fn client_spawn_players(
     }
 }
 
+pub fn fade_despawning_players(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut network_mapping: ResMut<NetworkMapping>,
+    mut query: Query<(Entity, &mut Despawning, &Children)>,
+    material_query: Query<&Handle<StandardMaterial>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    for (entity, mut despawning, children) in query.iter_mut() {
+        if despawning.timer.tick(time.delta()).finished() {
+            commands.entity(entity).despawn_recursive();
+            network_mapping.0.remove(&despawning.server_entity);
+            continue;
+        }
+        let alpha = despawning.timer.percent_left();
+        for child in children.iter() {
+            if let Some(material) = material_query
+                .get(*child)
+                .ok()
+                .and_then(|handle| materials.get_mut(handle))
+            {
+                material.base_color.set_a(alpha);
+            }
+        }
+    }
+}
+
+// Facing last received from the server, which the player turns toward
+#[derive(Component)]
+pub struct NetworkedYaw(pub f32);
+
+// Moves from one yaw to another the short way around, so facing turns through the wrap
+// at ±π instead of spinning back the long way
+pub fn lerp_yaw(from: f32, to: f32, t: f32) -> f32 {
+    let delta =
+        (to - from + std::f32::consts::PI).rem_euclid(std::f32::consts::TAU) - std::f32::consts::PI;
+    from + delta * t
+}
+
+pub fn smooth_networked_yaw(
+    time: Res<Time>,
+    config: Res<Config>,
+    mut query: Query<(&NetworkedYaw, &mut Transform)>,
+) {
+    let smoothing = config.network.rotation_smoothing;
+    let t = if smoothing > 0.0 {
+        1.0 - (-smoothing * time.delta_seconds()).exp()
+    } else {
+        1.0
+    };
+    for (target, mut transform) in query.iter_mut() {
+        let (yaw, _, _) = transform.rotation.to_euler(EulerRot::YXZ);
+        transform.rotation = Quat::from_rotation_y(lerp_yaw(yaw, target.0, t));
+    }
+}
+
+fn team_animation(team: Team) -> &'static str {
+    match team {
+        Team::Jinrai => "animations/jinrai.anim",
+        Team::Nsf => "animations/nsf.anim",
+    }
+}
+
+// Adds the animated billboard sprite and blob shadow to a player
+pub fn spawn_player_visuals(
+    player: &mut EntityCommands,
+    team: Team,
+    asset_server: &AssetServer,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+) {
+    let material_handle = materials.add(StandardMaterial {
+        alpha_mode: AlphaMode::Blend,
+        reflectance: 0.0,
+        metallic: 0.0,
+        perceptual_roughness: 1.0,
+        ..default()
+    });
+    let mesh_handle = meshes.add(Mesh::from(shape::Quad {
+        size: Vec2::new(1.0, 1.0),
+        ..default()
+    }));
+    let shadow_material_handle = materials.add(StandardMaterial {
+        base_color: Color::BLACK,
+        base_color_texture: Some(asset_server.load("textures/fx/blob_shadow.png")),
+        alpha_mode: AlphaMode::Blend,
+        unlit: true,
+        ..default()
+    });
+    let shadow_mesh_handle = meshes.add(Mesh::from(Plane::from_size(1.0)));
+
+    player.with_children(|parent| {
+        // Next frame faded in on top of the sprite, hidden unless frame blending is on
+        let overlay = parent
+            .spawn(PbrBundle {
+                mesh: meshes.add(Mesh::from(shape::Quad {
+                    size: Vec2::new(1.0, 1.0),
+                    ..default()
+                })),
+                material: materials.add(frame_blend_material()),
+                visibility: Visibility::Hidden,
+                ..default()
+            })
+            .insert(Billboard)
+            .insert(FrameBlendOverlay)
+            .id();
+        // Sprite
+        parent
+            .spawn(PbrBundle {
+                mesh: mesh_handle,
+                material: material_handle,
+                ..default()
+            })
+            .insert(Billboard)
+            .insert(ContactShadow)
+            .insert(FrameBlend { overlay })
+            .insert(Animator::new(asset_server.load(team_animation(team))))
+            .insert(Sequence::None)
+            .insert(SequenceDebounce::default())
+            .insert(AnimationSpeedScale::default());
+        // Blob shadow
+        parent
+            .spawn(PbrBundle {
+                mesh: shadow_mesh_handle,
+                material: shadow_material_handle,
+                transform: Transform::from_xyz(0.0, -PLAYER_HALF_HEIGHT, 0.0),
+                ..default()
+            })
+            .insert(BlobShadow);
+    });
+}
+
+// Gives players on the server the same sprite the clients see, for the debug window
+pub fn attach_player_visuals(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    query: Query<(Entity, &Player), Added<Player>>,
+) {
+    for (entity, player) in query.iter() {
+        spawn_player_visuals(
+            &mut commands.entity(entity),
+            player.team,
+            &asset_server,
+            &mut meshes,
+            &mut materials,
+        );
+    }
+}
+
 #[derive(Component)]
 pub struct Crosshair;
 
@@ -228,19 +583,134 @@ pub struct PlayerInput {
     forward: f32,
     right: f32,
     jump: bool,
+    crouch: bool,
+    walk: bool,
     aim_ray: Ray,
+    pub weapon: u8,
     pub most_recent_tick: Option<u32>,
 }
 
+impl PlayerInput {
+    // Folds a newer input into this one. The newer one wins, except that a jump
+    // pressed in either is kept so it isn't lost when inputs arrive together.
+    pub fn merge(&mut self, newer: PlayerInput) {
+        let jump = self.jump || newer.jump;
+        *self = newer;
+        self.jump = jump;
+    }
+}
+
+// Inputs one client sent that the server hasn't stepped yet
+pub const MAX_QUEUED_INPUTS: usize = 8;
+// Inputs stepped per server tick, a little over one so a client running slightly ahead
+// of the server can catch up instead of falling further behind
+pub const MAX_INPUTS_PER_TICK: usize = 2;
+
+// A client's inputs waiting for the server. Movement is stepped once per tick with a
+// bounded number of them, so sending inputs faster than the tick rate neither moves a
+// player faster nor builds up a backlog that would play out later.
+#[derive(Debug, Default)]
+pub struct InputQueue {
+    inputs: VecDeque<PlayerInput>,
+    // Ticks that got an input from this client
+    pub processed_ticks: u32,
+}
+
+impl InputQueue {
+    // Whether the oldest input had to be dropped to make room
+    pub fn push(&mut self, input: PlayerInput) -> bool {
+        let full = self.inputs.len() >= MAX_QUEUED_INPUTS;
+        if full {
+            // Keep its jump in the next one so the press isn't lost
+            if let (Some(mut oldest), Some(next)) =
+                (self.inputs.pop_front(), self.inputs.front_mut())
+            {
+                oldest.merge(*next);
+                *next = oldest;
+            }
+        }
+        self.inputs.push_back(input);
+        full
+    }
+
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    // The input to step this tick, merged from at most MAX_INPUTS_PER_TICK of the
+    // oldest ones still waiting
+    pub fn next_tick(&mut self) -> Option<PlayerInput> {
+        let count = self.inputs.len().min(MAX_INPUTS_PER_TICK);
+        let mut inputs = self.inputs.drain(..count);
+        let mut merged = inputs.next()?;
+        for input in inputs {
+            merged.merge(input);
+        }
+        self.processed_ticks += 1;
+        Some(merged)
+    }
+}
+
+// How far ahead of the player the crosshair sits when facing with the keyboard
+const KEYBOARD_AIM_DISTANCE: f32 = 4.0;
+
+// Movement keys as a direction on the ground, relative to the screen
+fn screen_direction(camera_transform: &GlobalTransform, forward: f32, right: f32) -> Vec3 {
+    let mut screen_forward =
+        (camera_transform.forward() * Vec3::new(1.0, 0.0, 1.0)).normalize_or_zero();
+    // Looking straight down, up on the screen is forward
+    if screen_forward == Vec3::ZERO {
+        screen_forward = (camera_transform.up() * Vec3::new(1.0, 0.0, 1.0)).normalize_or_zero();
+    }
+    let screen_right = screen_forward.cross(Vec3::Y);
+    (screen_forward * forward + screen_right * right).normalize_or_zero()
+}
+
+// Aim ray pointing straight down at the ground ahead of the player
+fn facing_ray(position: Vec3, facing: Vec3) -> Ray {
+    Ray {
+        origin: position + facing * KEYBOARD_AIM_DISTANCE + Vec3::Y * 10.0,
+        direction: Vec3::NEG_Y,
+    }
+}
+
+// Radians the player turns per pixel of mouse movement over the shoulder
+const SHOULDER_TURN_SPEED: f32 = 0.004;
+
+// Aim ray through a point on the screen, in logical pixels from the top left like
+// Window::cursor_position. Anything that needs to know what's under the cursor should
+// go through this so it lines up with where the player aims.
+pub fn screen_ray(
+    camera: &Camera,
+    camera_transform: &GlobalTransform,
+    screen_pos: Vec2,
+) -> Option<Ray> {
+    camera.viewport_to_world(camera_transform, screen_pos)
+}
+
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
 pub fn player_input(
     input: Res<Input<InputAction>>,
+    settings: Res<Settings>,
     primary_window: Query<&Window, With<PrimaryWindow>>,
-    mut player_query: Query<&mut PlayerInput>,
+    mut player_query: Query<(
+        &mut PlayerInput,
+        &Transform,
+        Option<&Inventory>,
+        Option<&Recoil>,
+    )>,
     most_recent_tick: Res<MostRecentTick>,
-    _mouse_button_input: Res<Input<MouseButton>>,
     cam_query: Query<(&Camera, &GlobalTransform), With<MainCamera>>,
+    mut mouse_motion: EventReader<MouseMotion>,
+    mut facing: Local<Vec3>,
+    mut warned: Local<bool>,
 ) {
-    if let Ok(mut player_input) = player_query.get_single_mut() {
+    let turn: f32 = mouse_motion.read().map(|motion| motion.delta.x).sum();
+    if let Ok((mut player_input, transform, inventory, recoil)) = player_query.get_single_mut() {
         player_input.most_recent_tick = most_recent_tick.0;
 
         player_input.forward = 0.0;
@@ -257,31 +727,154 @@ pub fn player_input(
         if input.pressed(InputAction::Left) {
             player_input.right += -1.0;
         }
-        player_input.jump = (player_input.jump || input.just_pressed(InputAction::Jump))
-            && !input.just_released(InputAction::Jump);
+        // Held state, which player_move turns into taps or repeats depending on the
+        // jump mode. A press and release within one frame still counts.
+        player_input.jump =
+            input.pressed(InputAction::Jump) || input.just_pressed(InputAction::Jump);
+        player_input.crouch = input.pressed(InputAction::Crouch);
+        player_input.walk = input.pressed(InputAction::Walk);
 
-        let (camera, camera_transform) = cam_query.single();
-        if let Some(cursor_pos) = primary_window.single().cursor_position() {
-            if let Some(ray) = camera.viewport_to_world(camera_transform, cursor_pos) {
-                player_input.aim_ray = ray;
+        if let Some(inventory) = inventory {
+            player_input.weapon =
+                weapons::select_slot(&input, player_input.weapon, inventory.weapons.len());
+        }
+
+        let (camera, camera_transform) = match cam_query.get_single() {
+            Ok(camera) => {
+                *warned = false;
+                camera
+            }
+            Err(err) => {
+                if !*warned {
+                    warn!("Unable to aim without a main camera: {}", err);
+                    *warned = true;
+                }
+                return;
+            }
+        };
+
+        // The camera turns with the player, so aiming at the cursor would chase itself.
+        // The mouse turns the player instead and they aim at the ground ahead.
+        if settings.camera.mode == CameraMode::OverShoulder {
+            if *facing == Vec3::ZERO {
+                *facing = transform.forward() * Vec3::new(1.0, 0.0, 1.0);
+            }
+            *facing =
+                (Quat::from_rotation_y(-turn * SHOULDER_TURN_SPEED) * *facing).normalize_or_zero();
+            if *facing != Vec3::ZERO {
+                player_input.aim_ray = facing_ray(transform.translation, *facing);
+            }
+            return;
+        }
+
+        match settings.control_scheme {
+            ControlScheme::MouseAim => {
+                let Ok(window) = primary_window.get_single() else {
+                    return;
+                };
+                if let Some(mut cursor_pos) = window.cursor_position() {
+                    if let Some(recoil) = recoil {
+                        cursor_pos.y -= recoil.0;
+                    }
+                    if let Some(ray) = screen_ray(camera, camera_transform, cursor_pos) {
+                        player_input.aim_ray = ray;
+                    }
+                }
+                return;
+            }
+            ControlScheme::MovementFacing => {
+                let movement =
+                    screen_direction(camera_transform, player_input.forward, player_input.right);
+                if movement != Vec3::ZERO {
+                    *facing = movement;
+                    // Always run the way we're facing
+                    player_input.forward = 1.0;
+                    player_input.right = 0.0;
+                }
             }
+            ControlScheme::FaceKeys => {
+                let axis = |positive, negative| {
+                    input.pressed(positive) as i8 as f32 - input.pressed(negative) as i8 as f32
+                };
+                let face = screen_direction(
+                    camera_transform,
+                    axis(InputAction::FaceForward, InputAction::FaceBack),
+                    axis(InputAction::FaceRight, InputAction::FaceLeft),
+                );
+                if face != Vec3::ZERO {
+                    *facing = face;
+                }
+                // Movement is relative to the facing on the server, so convert from screen space
+                let movement =
+                    screen_direction(camera_transform, player_input.forward, player_input.right);
+                let facing = if *facing == Vec3::ZERO {
+                    transform.forward()
+                } else {
+                    *facing
+                };
+                player_input.forward = movement.dot(facing);
+                player_input.right = movement.dot(facing.cross(Vec3::Y));
+            }
+        }
+        if *facing != Vec3::ZERO {
+            player_input.aim_ray = facing_ray(transform.translation, *facing);
         }
     }
 }
 
 pub fn update_crosshair(
-    query: Query<&PlayerInput, With<LocalPlayer>>,
+    config: Res<Config>,
+    settings: Res<Settings>,
+    spatial_query: SpatialQuery,
+    query: Query<(&PlayerInput, &Transform), With<LocalPlayer>>,
     mut crosshair_query: Query<&mut Transform, (With<Crosshair>, Without<LocalPlayer>)>,
 ) {
-    let mut crosshair_transform = crosshair_query.single_mut();
-    if let Ok(player_input) = query.get_single() {
-        let aim_ray = player_input.aim_ray;
-        if let Some(distance) = aim_ray.intersect_plane(Vec3::ZERO, Vec3::Y) {
-            crosshair_transform.translation = aim_ray.origin + aim_ray.direction * distance;
+    let Ok(mut crosshair_transform) = crosshair_query.get_single_mut() else {
+        return;
+    };
+    if let Ok((player_input, transform)) = query.get_single() {
+        let aim_ray = &player_input.aim_ray;
+        let crosshair = crosshair_point(
+            aim_ray,
+            settings.crosshair,
+            || {
+                spatial_query
+                    .cast_ray(
+                        aim_ray.origin,
+                        aim_ray.direction,
+                        MAX_AIM_DISTANCE,
+                        true,
+                        SpatialQueryFilter::new().with_masks([Layer::Ground]),
+                    )
+                    .map(|hit| hit.time_of_impact)
+            },
+            || aim_height(transform.translation, &config.map, &spatial_query),
+            &config.map,
+        );
+        if let Some(crosshair) = crosshair {
+            crosshair_transform.translation = crosshair;
+        }
+    }
+}
+
+// Where the crosshair goes along the aim ray. geometry_hit is how far along the ray it
+// hits something, falling back to the aim plane when it hits nothing.
+fn crosshair_point(
+    aim_ray: &Ray,
+    mode: CrosshairMode,
+    geometry_hit: impl FnOnce() -> Option<f32>,
+    aim_height: impl FnOnce() -> f32,
+    map: &MapConfig,
+) -> Option<Vec3> {
+    if mode == CrosshairMode::Geometry {
+        if let Some(distance) = geometry_hit() {
+            return Some(aim_ray.origin + aim_ray.direction * distance);
         }
     }
+    aim_point(aim_ray, aim_height(), map)
 }
 
+#[allow(clippy::type_complexity, clippy::too_many_arguments)]
 pub fn player_move(
     config: Res<Config>,
     gravity: Res<Gravity>,
@@ -289,83 +882,537 @@ pub fn player_move(
     time: Res<Time>,
     mut query: Query<
         (
+            Entity,
             &mut PlayerInput,
             &mut IsGrounded,
+            &mut JumpCount,
+            &mut FallSpeed,
+            &InWater,
             &mut LinearVelocity,
             &mut Transform,
+            &mut Stance,
+            &mut Collider,
         ),
         With<Player>,
     >,
+    surfaces: Query<&SurfaceFriction>,
+    mut landed: EventWriter<Landed>,
+    // Where each player last was before anything went wrong
+    mut last_valid: Local<HashMap<Entity, Vec3>>,
 ) {
-    for (mut player_input, mut is_grounded, mut velocity, mut transform) in query.iter_mut() {
-        rotate(&mut transform, &player_input.aim_ray);
+    last_valid.retain(|entity, _| query.contains(*entity));
+    // Where everyone stood before this step, so players push each other apart evenly
+    let positions: Vec<(Entity, Vec3)> = query
+        .iter()
+        .map(|(entity, .., transform, _, _)| (entity, transform.translation))
+        .collect();
+    for (
+        entity,
+        mut player_input,
+        mut is_grounded,
+        mut jump_count,
+        mut fall_speed,
+        in_water,
+        mut velocity,
+        mut transform,
+        mut stance,
+        mut collider,
+    ) in query.iter_mut()
+    {
+        let height = aim_height(transform.translation, &config.map, &spatial_query);
+        rotate(&mut transform, &player_input.aim_ray, height, &config.map);
 
-        is_grounded.0 = check_grounded(&transform, &spatial_query);
+        // Jumping, grounding and falling all go along gravity rather than the Y axis
+        let gravity_strength = gravity.0.length();
+        let down = gravity_down(gravity.0);
+        let up = -down;
 
-        if is_grounded.0 && player_input.jump {
+        // The feet stay put while the capsule changes size, and standing back up needs
+        // room overhead.
+        let wanted = Stance::from_input(&player_input, in_water.0);
+        let wants_crouch = wanted.crouching;
+        if wants_crouch != stance.crouching {
+            let has_room = wants_crouch
+                || spatial_query
+                    .cast_ray(
+                        transform.translation,
+                        up,
+                        stance.half_height() + CROUCH_HEIGHT_DIFFERENCE,
+                        true,
+                        SpatialQueryFilter::new().with_masks([Layer::Ground]),
+                    )
+                    .is_none();
+            if has_room {
+                let shift = up * CROUCH_HEIGHT_DIFFERENCE / 2.0;
+                transform.translation += if wants_crouch { -shift } else { shift };
+                stance.crouching = wants_crouch;
+                *collider = stance.collider();
+            }
+        }
+        if stance.walking != wanted.walking {
+            stance.walking = wanted.walking;
+        }
+
+        let was_grounded = is_grounded.0;
+        let half_height = stance.half_height();
+        let ground = check_grounded(transform.translation, down, half_height, &spatial_query);
+        is_grounded.0 = ground.is_some();
+        if is_grounded.0 && !was_grounded {
+            landed.send(Landed {
+                entity,
+                speed: fall_speed.0,
+            });
+        }
+
+        // Only look at input that came in since the last step, not our own reset of it
+        let released = player_input.is_changed() && !player_input.jump;
+        jump_count.update(is_grounded.0, released, time.delta_seconds());
+
+        // Jump swims up instead while in water
+        if player_input.jump && !in_water.0 && jump_count.can_jump(is_grounded.0, &config.physics) {
+            let jump_velocity = if is_grounded.0 {
+                config
+                    .physics
+                    .jump_velocity(gravity_strength, time.delta_seconds())
+            } else {
+                config
+                    .physics
+                    .air_jump_velocity(gravity_strength, time.delta_seconds())
+            };
+            jump(&mut velocity, up, jump_velocity);
             player_input.jump = false;
             is_grounded.0 = false;
-            velocity.y = (2.0 * config.physics.jump_height * -gravity.0.y).sqrt();
+            jump_count.jump(&config.physics);
         }
 
-        friction(&mut velocity, is_grounded.0, &config, time.delta_seconds());
+        // Walk along the surface gravity pulls us against
+        let wish_dir =
+            transform.forward() * player_input.forward + transform.right() * player_input.right;
+        let wish_dir = (wish_dir - up * wish_dir.dot(up)).normalize_or_zero();
 
-        let wish_dir = (transform.forward() * player_input.forward
-            + transform.right() * player_input.right)
-            .normalize_or_zero();
-        let wish_speed = config.physics.ground_speed;
+        if in_water.0 {
+            swim(
+                &mut velocity,
+                wish_dir,
+                &player_input,
+                gravity.0,
+                up,
+                &config,
+                time.delta_seconds(),
+            );
+        } else {
+            let ground_friction =
+                surface_friction(ground.and_then(|ground| surfaces.get(ground).ok()), &config);
+            friction(
+                &mut velocity,
+                is_grounded.0,
+                ground_friction,
+                &config,
+                time.delta_seconds(),
+            );
 
-        accelerate(
-            &mut velocity,
-            wish_dir,
-            wish_speed,
-            is_grounded.0,
-            &config,
+            accelerate(
+                &mut velocity,
+                wish_dir,
+                stance.speed(&config),
+                is_grounded.0,
+                &config,
+                time.delta_seconds(),
+            );
+
+            stop_at_edge(
+                &mut velocity,
+                transform.translation,
+                up,
+                is_grounded.0,
+                &config,
+                time.delta_seconds(),
+                |position| check_grounded(position, down, half_height, &spatial_query).is_some(),
+            );
+
+            // Kinematic bodies aren't affected by gravity
+            **velocity += gravity.0 * time.delta_seconds();
+        }
+
+        // Water breaks the fall
+        fall_speed.0 = if is_grounded.0 || in_water.0 {
+            0.0
+        } else {
+            velocity.dot(down).max(0.0)
+        };
+
+        // Checked before the sweep too, casting along a NaN isn't something to rely on
+        stop_if_not_finite(&mut velocity, entity);
+        let filter = movement_filter(&config, entity);
+        let sweep = |position, direction, distance| {
+            spatial_query
+                .cast_shape(
+                    &collider,
+                    position,
+                    Quat::IDENTITY,
+                    direction,
+                    distance,
+                    true,
+                    filter.clone(),
+                )
+                // The shape is cast unrotated, so its local normal is also the world normal
+                .map(|hit| (hit.time_of_impact, -hit.normal2))
+        };
+        // Kinematic bodies aren't pushed out of each other by the solver, so step out of
+        // anyone we're standing inside. The push is swept too so it can't go into walls.
+        if config.physics.player_collision {
+            let push = separation(entity, transform.translation, up, &positions);
+            if push != Vec3::ZERO {
+                let moved = slide_move(sweep, transform.translation, push, 1.0);
+                transform.translation += moved;
+            }
+        }
+        **velocity = slide_move(
+            sweep,
+            transform.translation,
+            **velocity,
             time.delta_seconds(),
         );
+
+        stop_if_not_finite(&mut velocity, entity);
+        if !transform.rotation.is_finite() {
+            warn!(
+                "Player {:?} had a non-finite rotation, resetting it",
+                entity
+            );
+            transform.rotation = Quat::IDENTITY;
+        }
+        if transform.translation.is_finite() {
+            last_valid.insert(entity, transform.translation);
+        } else {
+            let translation = last_valid.get(&entity).copied().unwrap_or(Vec3::new(
+                0.0,
+                PLAYER_SPAWN_HEIGHT,
+                0.0,
+            ));
+            warn!(
+                "Player {:?} had a non-finite position, moving it back to {}",
+                entity, translation
+            );
+            transform.translation = translation;
+        }
+    }
+}
+
+// A NaN from anywhere, like bad config or a bad packet, would otherwise stick to the
+// player for good
+fn stop_if_not_finite(velocity: &mut LinearVelocity, entity: Entity) {
+    if !velocity.is_finite() {
+        warn!(
+            "Player {:?} had a non-finite velocity {}, stopping it",
+            entity, **velocity
+        );
+        **velocity = Vec3::ZERO;
     }
 }
 
-fn rotate(transform: &mut Transform, aim_ray: &Ray) {
-    if let Some(distance) = aim_ray.intersect_plane(Vec3::ZERO, Vec3::Y) {
-        let mut aim_point = aim_ray.origin + aim_ray.direction * distance;
+pub fn apply_fall_damage(
+    config: Res<Config>,
+    mut landed: EventReader<Landed>,
+    mut fall_damage: EventWriter<FallDamage>,
+) {
+    let Some(fall_damage_config) = &config.fall_damage else {
+        landed.clear();
+        return;
+    };
+    for event in landed.read() {
+        let damage = fall_damage_config.damage(event.speed);
+        if damage > 0.0 {
+            fall_damage.send(FallDamage {
+                target: event.entity,
+                damage,
+            });
+        }
+    }
+}
+
+fn movement_filter(config: &Config, entity: Entity) -> SpatialQueryFilter {
+    let filter = if config.physics.player_collision {
+        SpatialQueryFilter::new().with_masks([Layer::Ground, Layer::Player])
+    } else {
+        SpatialQueryFilter::new().with_masks([Layer::Ground])
+    };
+    filter.without_entities([entity])
+}
+
+// How far to move out of other players overlapping this one, sideways only and half
+// the overlap since the other player moves the other way. Players on the exact same
+// spot go opposite ways picked by their entities.
+fn separation(entity: Entity, position: Vec3, up: Vec3, others: &[(Entity, Vec3)]) -> Vec3 {
+    let mut push = Vec3::ZERO;
+    for &(other, other_position) in others {
+        let offset = position - other_position;
+        if other == entity || offset.dot(up).abs() >= PLAYER_HEIGHT {
+            continue;
+        }
+        let offset = offset - up * offset.dot(up);
+        let overlap = 2.0 * PLAYER_RADIUS - offset.length();
+        if overlap <= 0.0 {
+            continue;
+        }
+        let away = offset.try_normalize().unwrap_or_else(|| {
+            let side = up.any_orthonormal_vector();
+            if entity < other {
+                side
+            } else {
+                -side
+            }
+        });
+        push += away * overlap / 2.0;
+    }
+    push
+}
+
+// Gap kept between the player and whatever it bumps into, so the next sweep
+// doesn't start out touching the surface
+const SKIN_WIDTH: f32 = 0.01;
+const MAX_SLIDES: usize = 4;
+
+// Sweeps the collider along the velocity, stopping at and sliding along anything in
+// the way. sweep takes a position, direction and distance and returns how far along
+// the first hit is and the normal of what was hit. Returns the velocity that takes
+// the player exactly where the sweep ended.
+fn slide_move(
+    sweep: impl Fn(Vec3, Vec3, f32) -> Option<(f32, Vec3)>,
+    origin: Vec3,
+    velocity: Vec3,
+    delta_time: f32,
+) -> Vec3 {
+    if delta_time <= 0.0 {
+        return velocity;
+    }
+
+    let original_velocity = velocity;
+    let mut position = origin;
+    let mut velocity = velocity;
+    let mut time_left = delta_time;
+    let mut planes: Vec<Vec3> = Vec::with_capacity(MAX_SLIDES);
+    for _ in 0..MAX_SLIDES {
+        let distance = velocity.length() * time_left;
+        if distance <= f32::EPSILON {
+            break;
+        }
+        let direction = velocity / velocity.length();
+        let Some((time_of_impact, normal)) = sweep(position, direction, distance) else {
+            position += velocity * time_left;
+            break;
+        };
+
+        position += direction * (time_of_impact - SKIN_WIDTH).max(0.0);
+        time_left *= 1.0 - time_of_impact / distance;
+
+        velocity = clip_velocity(velocity, normal, &planes);
+        planes.push(normal);
+
+        // Turning back on ourselves means we're wedged into a corner, so stop there
+        // instead of bouncing between the walls
+        if velocity.dot(original_velocity) <= 0.0 {
+            break;
+        }
+    }
+
+    (position - origin) / delta_time
+}
+
+// Projects the velocity onto the plane it hit. If that pushes it into a plane hit
+// earlier in the same move, it can only go along the crease where the two meet.
+fn clip_velocity(velocity: Vec3, normal: Vec3, planes: &[Vec3]) -> Vec3 {
+    let clipped = velocity - normal * velocity.dot(normal).min(0.0);
+    for plane in planes {
+        // The same surface again, or close enough that there's no crease to follow
+        if plane.dot(normal) > 0.99 || clipped.dot(*plane) >= 0.0 {
+            continue;
+        }
+        let crease = normal.cross(*plane).normalize_or_zero();
+        return crease * crease.dot(velocity);
+    }
+    clipped
+}
+
+// Rays grazing the ground meet it absurdly far away, if at all. Those are ignored so
+// the crosshair and facing stay at the last sensible aim point.
+const MIN_AIM_RAY_SLOPE: f32 = 1e-3;
+const MAX_AIM_DISTANCE: f32 = 1000.0;
+
+// How far below the player to look for the floor the aim plane sits on, far enough
+// that it stays put during a jump
+const AIM_FLOOR_DISTANCE: f32 = 20.0;
+
+// Height of the plane the aim ray is projected onto. Maps can fix it, otherwise it's
+// the floor below the player so aiming works on raised ground.
+pub fn aim_height(position: Vec3, map: &MapConfig, spatial_query: &SpatialQuery) -> f32 {
+    aim_plane_height(position, map, || {
+        spatial_query
+            .cast_ray(
+                position,
+                -Vec3::Y,
+                AIM_FLOOR_DISTANCE,
+                true,
+                SpatialQueryFilter::new().with_masks([Layer::Ground]),
+            )
+            .map(|hit| hit.time_of_impact)
+    })
+}
+
+// floor_distance looks for the floor below the position, only when the map needs it
+fn aim_plane_height(
+    position: Vec3,
+    map: &MapConfig,
+    floor_distance: impl FnOnce() -> Option<f32>,
+) -> f32 {
+    if let Some(height) = map.aim_height {
+        return height;
+    }
+    floor_distance().map_or(0.0, |distance| position.y - distance)
+}
+
+// Where the aim ray meets the plane at the given height, kept within the map
+pub fn aim_point(aim_ray: &Ray, height: f32, map: &MapConfig) -> Option<Vec3> {
+    if aim_ray.direction.y.abs() < MIN_AIM_RAY_SLOPE {
+        return None;
+    }
+    let distance = aim_ray.intersect_plane(Vec3::Y * height, Vec3::Y)?;
+    if !distance.is_finite() || distance > MAX_AIM_DISTANCE {
+        return None;
+    }
+    Some(map.clamp_to_bounds(aim_ray.origin + aim_ray.direction * distance))
+}
+
+fn rotate(transform: &mut Transform, aim_ray: &Ray, height: f32, map: &MapConfig) {
+    if let Some(mut aim_point) = aim_point(aim_ray, height, map) {
         aim_point.y = transform.translation.y;
+        // Looking at our own position has no direction and would fill the rotation with NaN
+        if aim_point.distance_squared(transform.translation) < f32::EPSILON {
+            return;
+        }
         transform.look_at(aim_point, Vec3::Y);
     }
 }
 
-fn check_grounded(transform: &Transform, spatial_query: &SpatialQuery) -> bool {
-    if let Some(_hit) = spatial_query.cast_ray(
-        transform.translation,
-        -Vec3::Y,
-        0.6, // TODO: Magic number. Would be better to use collision events?
-        true,
-        SpatialQueryFilter::new().with_masks([Layer::Ground]),
-    ) {
-        return true;
+// Returns what the player is standing on, if anything
+// Straight down when there's no gravity to go by
+fn gravity_down(gravity: Vec3) -> Vec3 {
+    gravity.try_normalize().unwrap_or(Vec3::NEG_Y)
+}
+
+// Replaces the speed along up with the jump, keeping the rest of the movement
+fn jump(velocity: &mut LinearVelocity, up: Vec3, jump_velocity: f32) {
+    let vertical_speed = velocity.dot(up);
+    **velocity += up * (jump_velocity - vertical_speed);
+}
+
+// Ice, mud and the like override the usual ground friction while stood on
+fn surface_friction(surface: Option<&SurfaceFriction>, config: &Config) -> f32 {
+    surface.map_or(config.physics.ground_friction, |surface| surface.0)
+}
+
+// Refuses steps that would carry a walking player off a ledge, with the edge stop on.
+// Jumping still leaves the ground on purpose since the player is no longer grounded.
+fn stop_at_edge(
+    velocity: &mut LinearVelocity,
+    position: Vec3,
+    up: Vec3,
+    is_grounded: bool,
+    config: &Config,
+    delta_time: f32,
+    has_ground: impl Fn(Vec3) -> bool,
+) {
+    if !config.physics.edge_stop || !is_grounded {
+        return;
     }
+    let along_ground = **velocity - up * velocity.dot(up);
+    if !has_ground(position + along_ground * delta_time) {
+        **velocity -= along_ground;
+    }
+}
 
-    false
+fn check_grounded(
+    position: Vec3,
+    down: Vec3,
+    half_height: f32,
+    spatial_query: &SpatialQuery,
+) -> Option<Entity> {
+    spatial_query
+        .cast_ray(
+            position,
+            down,
+            half_height + GROUND_CHECK_DISTANCE, // TODO: Would be better to use collision events?
+            true,
+            SpatialQueryFilter::new().with_masks([Layer::Ground]),
+        )
+        .map(|hit| hit.entity)
 }
 
-fn friction(velocity: &mut LinearVelocity, is_grounded: bool, config: &Config, delta_time: f32) {
+const MIN_EXPONENTIAL_SPEED: f32 = 0.01;
+
+fn friction(
+    velocity: &mut LinearVelocity,
+    is_grounded: bool,
+    ground_friction: f32,
+    config: &Config,
+    delta_time: f32,
+) {
     let current_speed = velocity.length();
     if current_speed == 0.0 {
         return;
     }
 
     let friction = if is_grounded {
-        config.physics.ground_friction
+        ground_friction
     } else {
         config.physics.air_friction
     };
 
-    // TODO: Use stop_speed instead of walk_speed?
-    let drop = current_speed.max(config.physics.ground_speed) * friction * delta_time;
-    let new_speed = (current_speed - drop).max(0.0);
+    let new_speed = match config.physics.friction_model {
+        FrictionModel::QuakeLinear => {
+            // TODO: Use stop_speed instead of walk_speed?
+            let drop = current_speed.max(config.physics.ground_speed) * friction * delta_time;
+            (current_speed - drop).max(0.0)
+        }
+        FrictionModel::Exponential => {
+            // Decay never quite reaches zero, so snap to a stop once it's negligible
+            let new_speed = current_speed * (-friction * delta_time).exp();
+            if new_speed < MIN_EXPONENTIAL_SPEED {
+                0.0
+            } else {
+                new_speed
+            }
+        }
+    };
     **velocity *= new_speed / current_speed;
 }
 
+// Water drag slows the player down in every direction, jump and crouch swim up and
+// down, and buoyancy above 1 outweighs gravity to float the player to the surface
+fn swim(
+    velocity: &mut LinearVelocity,
+    wish_dir: Vec3,
+    player_input: &PlayerInput,
+    gravity: Vec3,
+    up: Vec3,
+    config: &Config,
+    delta_time: f32,
+) {
+    **velocity *= (-config.physics.water_drag * delta_time).exp();
+
+    let vertical = player_input.jump as i32 - player_input.crouch as i32;
+    let wish_dir = (wish_dir + up * vertical as f32).normalize_or_zero();
+    let current_speed = velocity.dot(wish_dir);
+    let add_speed = config.physics.swim_speed - current_speed;
+    if add_speed > 0.0 {
+        let accel_speed =
+            add_speed.min(config.physics.swim_accel * config.physics.swim_speed * delta_time);
+        **velocity += wish_dir * accel_speed;
+    }
+
+    **velocity += gravity * (1.0 - config.physics.water_buoyancy) * delta_time;
+}
+
 fn accelerate(
     velocity: &mut LinearVelocity,
     wish_dir: Vec3,
@@ -396,44 +1443,1012 @@ fn accelerate(
     **velocity += wish_dir * accel_speed;
 }
 
+// Speeds at which the walk cycle starts and stops. The gap between them, together
+// with the debounce time, keeps animations from flickering at the boundary.
+const WALK_START_SPEED: f32 = 0.2;
+const WALK_STOP_SPEED: f32 = 0.1;
+const SEQUENCE_DEBOUNCE: f32 = 0.1;
+
+#[derive(Component, Default)]
+pub struct SequenceDebounce {
+    pending: Option<Sequence>,
+    elapsed: f32,
+    jumps: u32,
+}
+
+#[allow(clippy::type_complexity)]
 pub fn update_sequence(
-    mut query: Query<(&mut Sequence, &Parent), Without<Player>>,
-    p_query: Query<(&IsGrounded, &LinearVelocity), With<Player>>,
+    time: Res<Time>,
+    mut query: Query<(&mut Sequence, &mut SequenceDebounce, &Parent), Without<Player>>,
+    p_query: Query<
+        (
+            &IsGrounded,
+            Option<&JumpCount>,
+            Option<&InWater>,
+            &LinearVelocity,
+            Has<Deploying>,
+        ),
+        With<Player>,
+    >,
 ) {
-    for (mut sequence, parent) in query.iter_mut() {
-        if let Ok((is_grounded, velocity)) = p_query.get(parent.get()) {
-            let new_sequence = if !is_grounded.0 {
+    for (mut sequence, mut debounce, parent) in query.iter_mut() {
+        if let Ok((is_grounded, jump_count, in_water, velocity, is_deploying)) =
+            p_query.get(parent.get())
+        {
+            let jumps = jump_count.map_or(0, |jump_count| jump_count.count);
+            let air_jumped = jumps > debounce.jumps && *sequence == Sequence::Jump;
+            debounce.jumps = jumps;
+
+            let speed = velocity.xz().length();
+            let walk_speed = if *sequence == Sequence::Walk {
+                WALK_STOP_SPEED
+            } else {
+                WALK_START_SPEED
+            };
+            let new_sequence = if in_water.is_some_and(|in_water| in_water.0) {
+                Sequence::Swim
+            } else if !is_grounded.0 {
                 Sequence::Jump
-            } else if velocity.xz().length() > f32::EPSILON {
+            } else if is_deploying {
+                Sequence::Deploy
+            } else if speed > walk_speed {
                 Sequence::Walk
             } else {
                 Sequence::Idle
             };
-            if new_sequence != *sequence {
-                *sequence = new_sequence;
+
+            if new_sequence == *sequence {
+                debounce.pending = None;
+                // Play the jump from the start again for every air jump
+                if air_jumped && new_sequence == Sequence::Jump {
+                    sequence.set_changed();
+                }
+                continue;
+            }
+
+            // Only the idle/walk boundary is noisy, everything else switches immediately
+            let is_ground_move = matches!(*sequence, Sequence::Idle | Sequence::Walk)
+                && matches!(new_sequence, Sequence::Idle | Sequence::Walk);
+            if is_ground_move {
+                if debounce.pending != Some(new_sequence) {
+                    debounce.pending = Some(new_sequence);
+                    debounce.elapsed = 0.0;
+                }
+                debounce.elapsed += time.delta_seconds();
+                if debounce.elapsed < SEQUENCE_DEBOUNCE {
+                    continue;
+                }
+            }
+            debounce.pending = None;
+            *sequence = new_sequence;
+        }
+    }
+}
+
+// Bounds for how much the walk cycle speeds up or slows down with movement speed
+const MIN_WALK_ANIMATION_SCALE: f32 = 0.5;
+const MAX_WALK_ANIMATION_SCALE: f32 = 2.0;
+
+pub fn walk_animation_scale(speed: f32, ground_speed: f32) -> f32 {
+    if ground_speed <= 0.0 {
+        return 1.0;
+    }
+    (speed / ground_speed).clamp(MIN_WALK_ANIMATION_SCALE, MAX_WALK_ANIMATION_SCALE)
+}
+
+pub fn update_animation_speed(
+    config: Res<Config>,
+    mut query: Query<(&mut AnimationSpeedScale, &Sequence, &Parent), Without<Player>>,
+    p_query: Query<&LinearVelocity, With<Player>>,
+) {
+    for (mut speed_scale, sequence, parent) in query.iter_mut() {
+        let scale = match (sequence, p_query.get(parent.get())) {
+            (Sequence::Walk, Ok(velocity)) => {
+                walk_animation_scale(velocity.xz().length(), config.physics.ground_speed)
             }
+            _ => 1.0,
+        };
+        if speed_scale.0 != scale {
+            speed_scale.0 = scale;
         }
     }
 }
 
+// Steps through the other players by id, and back to our own view after the last one.
+// Without a player of our own there's no view to go back to, so it always follows someone.
+pub fn cycle_spectate_target(
+    input: Res<Input<InputAction>>,
+    mut spectate: ResMut<Spectate>,
+    players: Query<(Entity, &Player), Without<LocalPlayer>>,
+    spectator: Option<Res<Spectator>>,
+) {
+    // Stop following players that left
+    if spectate.0.is_some_and(|target| !players.contains(target)) {
+        spectate.0 = None;
+    }
+    let spectating_only = spectator.is_some();
+    let needs_target = spectating_only && spectate.0.is_none();
+    if !(input.just_pressed(InputAction::SpectateNext) || needs_target) {
+        return;
+    }
+    let mut targets: Vec<_> = players.iter().collect();
+    targets.sort_by_key(|(_, player)| player.id.raw());
+    let next = match spectate.0 {
+        Some(current) => targets
+            .iter()
+            .skip_while(|(entity, _)| *entity != current)
+            .nth(1),
+        None => targets.first(),
+    };
+    spectate.0 = next
+        .or(targets.first().filter(|_| spectating_only))
+        .map(|(entity, _)| *entity);
+}
+
 #[allow(clippy::type_complexity)]
 pub fn camera_follow_player(
+    settings: Res<Settings>,
+    spectate: Res<Spectate>,
     mut query: Query<&mut Transform, With<MainCamera>>,
     player_query: Query<&Transform, (With<LocalPlayer>, Without<MainCamera>)>,
+    spectate_query: Query<&Transform, (With<Player>, Without<MainCamera>)>,
     crosshair_query: Query<
         &Transform,
         (With<Crosshair>, Without<MainCamera>, Without<LocalPlayer>),
     >,
+    mut warned: Local<bool>,
 ) {
-    if let (Ok(player_transform), Ok(crosshair_transform), Ok(mut transform)) = (
-        player_query.get_single(),
-        crosshair_query.get_single(),
-        query.get_single_mut(),
-    ) {
-        let camera_offset = Vec3::ONE * 6.0;
+    let mut transform = match query.get_single_mut() {
+        Ok(transform) => {
+            *warned = false;
+            transform
+        }
+        Err(err) => {
+            if !*warned {
+                warn!("Unable to follow the player without a main camera: {}", err);
+                *warned = true;
+            }
+            return;
+        }
+    };
+    if let Some(target_transform) = spectate
+        .0
+        .and_then(|target| spectate_query.get(target).ok())
+    {
+        *transform = settings.camera.follow(target_transform, Vec3::ZERO);
+        return;
+    }
+    if let (Ok(player_transform), Ok(crosshair_transform)) =
+        (player_query.get_single(), crosshair_query.get_single())
+    {
         let mut translation = player_transform.translation;
         translation.y = 0.0;
-        transform.translation =
-            translation + (crosshair_transform.translation - translation) / 6.0 + camera_offset;
+        let lead = (crosshair_transform.translation - translation) / 6.0;
+        *transform = settings.camera.follow(player_transform, lead);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::networked_yaw;
+    use crate::settings::CameraSettings;
+    use std::time::Duration;
+
+    // Sweeps a point against walls given as a point on each and its normal
+    fn walls(walls: &[(Vec3, Vec3)]) -> impl Fn(Vec3, Vec3, f32) -> Option<(f32, Vec3)> + '_ {
+        move |position, direction, distance| {
+            walls
+                .iter()
+                .filter_map(|(point, normal)| {
+                    let approach = -direction.dot(*normal);
+                    if approach <= 0.0 {
+                        return None;
+                    }
+                    let time_of_impact = (position - *point).dot(*normal).max(0.0) / approach;
+                    (time_of_impact <= distance).then_some((time_of_impact, *normal))
+                })
+                .min_by(|a, b| a.0.total_cmp(&b.0))
+        }
+    }
+
+    #[test]
+    fn players_spawned_together_separate() {
+        let positions = [
+            (
+                Entity::from_raw(1),
+                Vec3::new(2.0, PLAYER_SPAWN_HEIGHT, 3.0),
+            ),
+            (
+                Entity::from_raw(2),
+                Vec3::new(2.0, PLAYER_SPAWN_HEIGHT, 3.0),
+            ),
+        ];
+        let moved: Vec<Vec3> = positions
+            .iter()
+            .map(|&(entity, position)| position + separation(entity, position, Vec3::Y, &positions))
+            .collect();
+        let apart = moved[0] - moved[1];
+        assert!((apart.length() - 2.0 * PLAYER_RADIUS).abs() < 1e-6);
+        assert_eq!(apart.y, 0.0);
+        // Nothing left to push once they're apart
+        let positions = [(positions[0].0, moved[0]), (positions[1].0, moved[1])];
+        assert!(separation(positions[0].0, moved[0], Vec3::Y, &positions).length() < 1e-6);
+    }
+
+    #[test]
+    fn players_apart_or_stacked_dont_push() {
+        let below = (Entity::from_raw(1), Vec3::ZERO);
+        let beside = (Entity::from_raw(2), Vec3::new(0.75, 0.0, 0.0));
+        let above = (Entity::from_raw(3), Vec3::new(0.0, PLAYER_HEIGHT, 0.0));
+        let positions = [below, beside, above];
+        for (entity, position) in positions {
+            assert_eq!(
+                separation(entity, position, Vec3::Y, &positions),
+                Vec3::ZERO
+            );
+        }
+    }
+
+    #[test]
+    fn slide_move_keeps_velocity_in_the_open() {
+        let velocity = Vec3::new(3.0, 0.0, 4.0);
+        assert_eq!(slide_move(walls(&[]), Vec3::ZERO, velocity, 0.1), velocity);
+    }
+
+    #[test]
+    fn slide_move_slides_along_a_wall() {
+        let wall = [(Vec3::X, Vec3::NEG_X)];
+        let velocity = slide_move(walls(&wall), Vec3::ZERO, Vec3::new(20.0, 0.0, 5.0), 0.1);
+        // Stopped short of the wall, but kept going along it
+        let end = velocity * 0.1;
+        assert!(end.x < 1.0 && end.x > 1.0 - 2.0 * SKIN_WIDTH);
+        assert!((velocity.z - 5.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn slide_move_stops_head_on() {
+        let wall = [(Vec3::X, Vec3::NEG_X)];
+        let start = Vec3::X * (1.0 - SKIN_WIDTH);
+        let velocity = slide_move(walls(&wall), start, Vec3::X * 10.0, 0.1);
+        assert!(velocity.length() < 1e-3);
+    }
+
+    #[test]
+    fn slide_move_slides_at_45_degrees() {
+        let wall = [(Vec3::X, Vec3::NEG_X)];
+        let start = Vec3::X * (1.0 - SKIN_WIDTH);
+        let velocity = slide_move(walls(&wall), start, Vec3::new(5.0, 0.0, 5.0), 0.1);
+        // Short of the wall, keeping nearly all of the speed along it
+        assert!(start.x + velocity.x * 0.1 < 1.0);
+        assert!(velocity.z > 4.5 && velocity.z <= 5.0);
+    }
+
+    #[test]
+    fn slide_move_settles_in_a_corner() {
+        let corner = [(Vec3::X, Vec3::NEG_X), (Vec3::Z, Vec3::NEG_Z)];
+        let mut position = Vec3::new(0.9, 0.0, 0.8);
+        let mut positions = Vec::new();
+        for _ in 0..10 {
+            position += slide_move(walls(&corner), position, Vec3::new(5.0, 0.0, 5.0), 0.1) * 0.1;
+            positions.push(position);
+        }
+        // Wedged in without bouncing back out
+        for window in positions.windows(2) {
+            assert!(window[1].x >= window[0].x - 1e-4 && window[1].z >= window[0].z - 1e-4);
+        }
+        assert!(position.x < 1.0 && position.z < 1.0);
+        assert!((position - positions[positions.len() - 2]).length() < 1e-3);
+    }
+
+    #[test]
+    fn clip_velocity_follows_the_crease() {
+        let floor = Vec3::Y;
+        let wall = Vec3::NEG_X;
+        let velocity = clip_velocity(Vec3::new(3.0, -1.0, 4.0), wall, &[floor]);
+        assert!((velocity - Vec3::Z * 4.0).length() < 1e-4);
+    }
+
+    // Runs update_sequence once per speed at 60 fps, returning the sequence after each
+    fn sequences(speeds: &[f32]) -> Vec<Sequence> {
+        let mut world = World::new();
+        world.init_resource::<Time>();
+        let player = world
+            .spawn((
+                Player {
+                    id: ClientId::from_raw(1),
+                    team: Team::Nsf,
+                },
+                IsGrounded(true),
+                LinearVelocity::ZERO,
+            ))
+            .id();
+        let sprite = world
+            .spawn((Sequence::Idle, SequenceDebounce::default()))
+            .set_parent(player)
+            .id();
+
+        let mut schedule = Schedule::default();
+        schedule.add_systems(update_sequence);
+        speeds
+            .iter()
+            .map(|speed| {
+                world
+                    .resource_mut::<Time>()
+                    .advance_by(Duration::from_secs_f32(1.0 / 60.0));
+                world
+                    .entity_mut(player)
+                    .insert(LinearVelocity(Vec3::X * *speed));
+                schedule.run(&mut world);
+                *world.get::<Sequence>(sprite).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn flooded_inputs_dont_move_faster() {
+        let config = Config::default();
+        let delta_time = 1.0 / 60.0;
+        let forward = PlayerInput {
+            forward: 1.0,
+            ..default()
+        };
+        let mut queue = InputQueue::default();
+        let mut velocity = LinearVelocity::default();
+        for _ in 0..120 {
+            for _ in 0..50 {
+                queue.push(forward);
+            }
+            // The server steps movement once per tick with whatever next_tick gives
+            let input = queue.next_tick().unwrap();
+            let wish_dir = Vec3::NEG_Z * input.forward;
+            friction(
+                &mut velocity,
+                true,
+                config.physics.ground_friction,
+                &config,
+                delta_time,
+            );
+            accelerate(
+                &mut velocity,
+                wish_dir,
+                config.physics.ground_speed,
+                true,
+                &config,
+                delta_time,
+            );
+            let displacement = velocity.length() * delta_time;
+            assert!(displacement <= config.physics.ground_speed * delta_time + 1e-5);
+            assert!(queue.len() <= MAX_QUEUED_INPUTS);
+        }
+        assert_eq!(queue.processed_ticks, 120);
+    }
+
+    #[test]
+    fn input_queue_steps_a_bounded_number_per_tick() {
+        let mut queue = InputQueue::default();
+        for forward in [0.25, 0.5, 0.75] {
+            assert!(!queue.push(PlayerInput {
+                forward,
+                ..default()
+            }));
+        }
+        assert_eq!(queue.next_tick().unwrap().forward, 0.5);
+        assert_eq!(queue.next_tick().unwrap().forward, 0.75);
+        assert!(queue.next_tick().is_none());
+        assert_eq!(queue.processed_ticks, 2);
+    }
+
+    #[test]
+    fn dropped_inputs_keep_their_jump() {
+        let mut queue = InputQueue::default();
+        queue.push(PlayerInput {
+            jump: true,
+            ..default()
+        });
+        for _ in 0..MAX_QUEUED_INPUTS {
+            queue.push(PlayerInput::default());
+        }
+        assert_eq!(queue.len(), MAX_QUEUED_INPUTS);
+        assert!(queue.next_tick().unwrap().jump);
+    }
+
+    // Highest point of a jump stepped the way player_move does it, gravity first and
+    // then the move
+    fn simulated_apex(physics: &PhysicsConfig, delta_time: f32) -> f32 {
+        let gravity = physics.gravity();
+        let mut speed = physics.jump_velocity(gravity, delta_time);
+        let mut height = 0.0;
+        loop {
+            speed -= gravity * delta_time;
+            if speed <= 0.0 {
+                return height;
+            }
+            height += speed * delta_time;
+        }
+    }
+
+    #[test]
+    fn jumps_peak_at_jump_height() {
+        let mut physics = PhysicsConfig::quake();
+        for delta_time in [1.0 / 30.0, 1.0 / 60.0, 1.0 / 144.0] {
+            let apex = simulated_apex(&physics, delta_time);
+            assert!((apex - physics.jump_height).abs() < physics.gravity * delta_time * delta_time);
+        }
+        physics.jump_height = 1.5;
+        physics.gravity = 30.0;
+        let apex = simulated_apex(&physics, 1.0 / 60.0);
+        assert!((apex - 1.5).abs() < 30.0 / 3600.0);
+    }
+
+    #[test]
+    fn apex_time_sets_gravity_and_keeps_the_height() {
+        let mut physics = PhysicsConfig::quake();
+        physics.jump_height = 1.0;
+        physics.jump_apex_time = Some(0.5);
+        assert_eq!(physics.gravity(), 8.0);
+        let delta_time = 1.0 / 60.0;
+        let apex = simulated_apex(&physics, delta_time);
+        assert!((apex - 1.0).abs() < 8.0 * delta_time * delta_time);
+        // Getting there takes about the apex time
+        let takeoff = physics.jump_velocity(8.0, delta_time);
+        assert!((takeoff / 8.0 - 0.5).abs() < delta_time);
+    }
+
+    // Jumps taken off the ground and then in the air, pressing and letting go of jump
+    // every other step with the cooldown long over in between
+    fn jumps_before_landing(physics: &PhysicsConfig) -> u32 {
+        let mut jump_count = JumpCount::default();
+        let mut is_grounded = true;
+        let mut jumps = 0;
+        for step in 0..20 {
+            let pressed = step % 2 == 1;
+            jump_count.update(is_grounded, !pressed, 0.25);
+            if pressed && jump_count.can_jump(is_grounded, physics) {
+                jump_count.jump(physics);
+                is_grounded = false;
+                jumps += 1;
+            }
+        }
+        jumps
+    }
+
+    #[test]
+    fn max_jumps_are_allowed_before_landing() {
+        let mut physics = PhysicsConfig::quake();
+        for max_jumps in 1..=4 {
+            physics.max_jumps = max_jumps;
+            assert_eq!(jumps_before_landing(&physics), max_jumps);
+        }
+    }
+
+    #[test]
+    fn landing_gives_the_jumps_back() {
+        let mut physics = PhysicsConfig::quake();
+        physics.max_jumps = 2;
+        let mut jump_count = JumpCount {
+            count: 2,
+            released: true,
+            cooldown: 0.0,
+        };
+        assert!(!jump_count.can_jump(false, &physics));
+        jump_count.update(true, true, 0.25);
+        assert_eq!(jump_count.count, 0);
+        assert!(jump_count.can_jump(true, &physics));
+    }
+
+    // Ticks at which a player holding jump jumps, landing 16 ticks after each jump.
+    // Tapping lets go and presses again halfway through every jump.
+    fn jump_ticks(jump_mode: JumpMode, tapping: bool) -> Vec<u32> {
+        let mut physics = PhysicsConfig::quake();
+        physics.jump_mode = jump_mode;
+        physics.max_jumps = 1;
+        let mut jump_count = JumpCount {
+            released: true,
+            ..default()
+        };
+        let (mut grounded, mut airborne) = (true, 0);
+        let mut jumps = Vec::new();
+        for tick in 0..128 {
+            if !grounded {
+                airborne += 1;
+                grounded = airborne == 16;
+            }
+            let released = tapping && airborne == 8;
+            jump_count.update(grounded, released, 1.0 / 64.0);
+            if jump_count.can_jump(grounded, &physics) {
+                jump_count.jump(&physics);
+                (grounded, airborne) = (false, 0);
+                jumps.push(tick);
+            }
+        }
+        jumps
+    }
+
+    #[test]
+    fn one_press_jumps_once_whatever_the_deltas() {
+        let physics = PhysicsConfig::quake();
+        let mut jump_count = JumpCount {
+            released: true,
+            ..default()
+        };
+        let deltas = [
+            1.0 / 30.0,
+            1.0 / 144.0,
+            1.0 / 60.0,
+            0.0,
+            1.0 / 8.0,
+            1.0 / 240.0,
+        ];
+        // Still touching the ground the steps after the jump, the worst case for it
+        let mut jumps = 0;
+        for delta_time in deltas.iter().cycle().take(24) {
+            jump_count.update(true, false, *delta_time);
+            if jump_count.can_jump(true, &physics) {
+                jump_count.jump(&physics);
+                jumps += 1;
+            }
+        }
+        assert_eq!(jumps, 1);
+
+        // Letting go and pressing again jumps again once the cooldown is up
+        jump_count.update(true, true, physics.jump_cooldown);
+        assert!(jump_count.can_jump(true, &physics));
+    }
+
+    #[test]
+    fn jump_modes_set_the_cadence() {
+        let every_landing: Vec<u32> = (0..128).step_by(16).collect();
+        // Tap needs a fresh press for every jump
+        assert_eq!(jump_ticks(JumpMode::Tap, false), [0]);
+        assert_eq!(jump_ticks(JumpMode::Tap, true), every_landing);
+        // Hold jumps again on every landing
+        assert_eq!(jump_ticks(JumpMode::Hold, false), every_landing);
+    }
+
+    #[test]
+    fn walk_cycle_follows_movement_speed() {
+        assert_eq!(walk_animation_scale(3.0, 3.0), 1.0);
+        assert_eq!(walk_animation_scale(1.5, 3.0), 0.5);
+        assert_eq!(walk_animation_scale(4.5, 3.0), 1.5);
+        // Clamped so creeping or bunny hopping doesn't look absurd
+        assert_eq!(walk_animation_scale(0.25, 3.0), MIN_WALK_ANIMATION_SCALE);
+        assert_eq!(walk_animation_scale(30.0, 3.0), MAX_WALK_ANIMATION_SCALE);
+        assert_eq!(walk_animation_scale(2.0, 0.0), 1.0);
+    }
+
+    #[test]
+    fn non_finite_velocity_is_stopped_before_the_sweep() {
+        let wall = [(Vec3::X, Vec3::NEG_X)];
+        for bad in [f32::NAN, f32::INFINITY] {
+            let mut velocity = LinearVelocity(Vec3::new(bad, 0.0, 1.0));
+            stop_if_not_finite(&mut velocity, Entity::PLACEHOLDER);
+            assert_eq!(velocity.0, Vec3::ZERO);
+            let velocity = slide_move(walls(&wall), Vec3::ZERO, velocity.0, 0.1);
+            assert!(velocity.is_finite());
+        }
+        let mut velocity = LinearVelocity(Vec3::new(1.0, -2.0, 3.0));
+        stop_if_not_finite(&mut velocity, Entity::PLACEHOLDER);
+        assert_eq!(velocity.0, Vec3::new(1.0, -2.0, 3.0));
+    }
+
+    #[test]
+    fn aiming_parallel_to_the_ground_keeps_the_facing() {
+        let map = Config::default().map;
+        let start = Transform::from_xyz(1.0, PLAYER_SPAWN_HEIGHT, 2.0)
+            .with_rotation(Quat::from_rotation_y(0.5));
+        for direction in [Vec3::X, Vec3::new(1.0, -1e-5, 0.0).normalize()] {
+            let aim_ray = Ray {
+                origin: Vec3::new(0.0, 0.5, 0.0),
+                direction,
+            };
+            assert_eq!(aim_point(&aim_ray, 0.0, &map), None);
+            let mut transform = start;
+            rotate(&mut transform, &aim_ray, 0.0, &map);
+            assert_eq!(transform, start);
+        }
+
+        // Looking down onto the ground still turns the player towards it
+        let aim_ray = Ray {
+            origin: Vec3::new(1.0, 8.0, 6.0),
+            direction: Vec3::NEG_Y,
+        };
+        let mut transform = start;
+        rotate(&mut transform, &aim_ray, 0.0, &map);
+        assert!(transform.rotation.is_finite());
+        assert!(transform.forward().abs_diff_eq(Vec3::Z, 1e-5));
+    }
+
+    #[test]
+    fn strafing_players_face_their_aim() {
+        let map = Config::default().map;
+        let mut transform = Transform::from_xyz(0.0, PLAYER_SPAWN_HEIGHT, 0.0);
+        // Aiming at a point off to +X while moving along Z
+        let aim_ray = Ray {
+            origin: Vec3::new(4.0, 8.0, 0.0),
+            direction: Vec3::NEG_Y,
+        };
+        rotate(&mut transform, &aim_ray, 0.0, &map);
+        let velocity = Vec3::new(0.0, 0.0, 6.0);
+
+        // What remote clients rebuild from the networked yaw
+        let remote = Quat::from_rotation_y(networked_yaw(transform.rotation));
+        let facing = remote * Vec3::NEG_Z;
+        assert!(facing.abs_diff_eq(Vec3::X, 1e-5));
+        assert!(facing.dot(velocity.normalize()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn keyboard_facing_is_normalized_and_aimed_along() {
+        let map = Config::default().map;
+        let cameras = [
+            GlobalTransform::from(
+                Transform::from_xyz(8.0, 8.0, 8.0).looking_at(Vec3::ZERO, Vec3::Y),
+            ),
+            // Straight down, where the camera's forward has nothing along the ground
+            GlobalTransform::from(
+                Transform::from_xyz(0.0, 16.0, 0.0).looking_at(Vec3::ZERO, Vec3::NEG_Z),
+            ),
+        ];
+        for camera in cameras {
+            assert_eq!(screen_direction(&camera, 0.0, 0.0), Vec3::ZERO);
+            for (forward, right) in [(1.0, 0.0), (0.0, -1.0), (1.0, 1.0), (-1.0, 1.0)] {
+                let facing = screen_direction(&camera, forward, right);
+                assert!((facing.length() - 1.0).abs() < 1e-5);
+                assert_eq!(facing.y, 0.0);
+
+                // Both keyboard schemes aim at the ground ahead along the facing
+                let mut transform = Transform::from_xyz(1.0, PLAYER_SPAWN_HEIGHT, -2.0);
+                let aim_ray = facing_ray(transform.translation, facing);
+                rotate(&mut transform, &aim_ray, 0.0, &map);
+                assert!(transform.forward().abs_diff_eq(facing, 1e-5));
+            }
+        }
+    }
+
+    // Where a player walking towards a ledge at x = 1 ends up after a second
+    fn walk_to_ledge(edge_stop: bool) -> Vec3 {
+        let mut config = Config::default();
+        config.physics.edge_stop = edge_stop;
+        let mut position = Vec3::new(0.0, PLAYER_SPAWN_HEIGHT, 0.0);
+        for _ in 0..60 {
+            let mut velocity = LinearVelocity(Vec3::X * 4.0);
+            stop_at_edge(
+                &mut velocity,
+                position,
+                Vec3::Y,
+                true,
+                &config,
+                1.0 / 60.0,
+                |position| position.x < 1.0,
+            );
+            position += *velocity / 60.0;
+        }
+        position
+    }
+
+    #[test]
+    fn edge_stop_keeps_players_on_the_ledge() {
+        let stopped = walk_to_ledge(true);
+        assert!(stopped.x < 1.0 && stopped.x > 0.9);
+        // Off, they walk straight over it
+        assert!(walk_to_ledge(false).x > 1.0);
+    }
+
+    #[test]
+    fn aim_follows_the_floor_under_the_player() {
+        let mut map = Config::default().map;
+        // Standing on a platform 2 up
+        let position = Vec3::new(1.0, 2.0 + PLAYER_HALF_HEIGHT, 1.0);
+        let height = aim_plane_height(position, &map, || Some(PLAYER_HALF_HEIGHT));
+        assert_eq!(height, 2.0);
+        let aim_ray = Ray {
+            origin: Vec3::new(1.0, 6.0, 5.0),
+            direction: Vec3::new(0.0, -1.0, -1.0).normalize(),
+        };
+        let on_platform = aim_point(&aim_ray, height, &map).unwrap();
+        assert!(on_platform.abs_diff_eq(Vec3::new(1.0, 2.0, 1.0), 1e-5));
+        let on_floor = aim_point(&aim_ray, 0.0, &map).unwrap();
+        assert!(on_floor.abs_diff_eq(Vec3::new(1.0, 0.0, -1.0), 1e-5));
+
+        // Nothing below falls back to the world floor, and maps can fix the height
+        assert_eq!(aim_plane_height(position, &map, || None), 0.0);
+        map.aim_height = Some(1.0);
+        assert_eq!(
+            aim_plane_height(position, &map, || Some(PLAYER_HALF_HEIGHT)),
+            1.0
+        );
+    }
+
+    #[test]
+    fn crosshair_lands_on_the_crate_top() {
+        let map = Config::default().map;
+        // Looking down at a crate whose top is 1 up
+        let aim_ray = Ray {
+            origin: Vec3::new(0.0, 8.0, 7.0),
+            direction: Vec3::new(0.0, -1.0, -1.0).normalize(),
+        };
+        let crate_top = || Some(7.0 * std::f32::consts::SQRT_2);
+        let crosshair = crosshair_point(&aim_ray, CrosshairMode::Geometry, crate_top, || 0.0, &map);
+        assert!(crosshair
+            .unwrap()
+            .abs_diff_eq(Vec3::new(0.0, 1.0, 0.0), 1e-5));
+
+        // The plane mode goes straight through to the floor behind it
+        let crosshair = crosshair_point(&aim_ray, CrosshairMode::Plane, crate_top, || 0.0, &map);
+        assert!(crosshair
+            .unwrap()
+            .abs_diff_eq(Vec3::new(0.0, 0.0, -1.0), 1e-5));
+        // As does missing everything
+        let crosshair = crosshair_point(&aim_ray, CrosshairMode::Geometry, || None, || 0.0, &map);
+        assert!(crosshair
+            .unwrap()
+            .abs_diff_eq(Vec3::new(0.0, 0.0, -1.0), 1e-5));
+    }
+
+    #[test]
+    fn jumps_go_against_sideways_gravity() {
+        let gravity = Vec3::NEG_X * 12.0;
+        let down = gravity_down(gravity);
+        assert_eq!(down, Vec3::NEG_X);
+        // Standing on a wall at x = 0 and running along it
+        let mut velocity = LinearVelocity(Vec3::new(-1.0, 2.0, 0.0));
+        jump(&mut velocity, -down, 4.0);
+        assert_eq!(velocity.0, Vec3::new(4.0, 2.0, 0.0));
+
+        // Rising off the wall and coming back down onto it
+        let mut x = 0.0;
+        let mut highest = 0.0f32;
+        for _ in 0..64 {
+            velocity.0 += gravity / 64.0;
+            x += velocity.x / 64.0;
+            highest = highest.max(x);
+        }
+        assert!(highest > 0.5);
+        assert!(x < 0.0);
+        // No gravity at all still has a way down for grounding
+        assert_eq!(gravity_down(Vec3::ZERO), Vec3::NEG_Y);
+    }
+
+    #[test]
+    fn every_camera_mode_aims_at_the_ground() {
+        let map = Config::default().map;
+        let player = Transform::from_xyz(2.0, PLAYER_SPAWN_HEIGHT, 3.0)
+            .with_rotation(Quat::from_rotation_y(0.5));
+        let ground = Vec3::new(2.0, 0.0, 3.0);
+        let mut camera = CameraSettings::default();
+        let mut transforms: Vec<Transform> = Vec::new();
+        for mode in [
+            CameraMode::Isometric,
+            CameraMode::TopDown,
+            CameraMode::OverShoulder,
+        ] {
+            camera.mode = mode;
+            let transform = camera.follow(&player, Vec3::ZERO);
+            assert!(!transforms.contains(&transform));
+            transforms.push(transform);
+
+            // The cursor in the middle of the screen looking down at the map, or the
+            // ground ahead when the mouse turns the player
+            let (aim_ray, expected) = if mode == CameraMode::OverShoulder {
+                let facing = player.forward();
+                let aim_ray = facing_ray(player.translation, facing);
+                (aim_ray, ground + facing * KEYBOARD_AIM_DISTANCE)
+            } else {
+                let aim_ray = Ray {
+                    origin: transform.translation,
+                    direction: transform.forward(),
+                };
+                (aim_ray, ground)
+            };
+            let aim = aim_point(&aim_ray, 0.0, &map).unwrap();
+            assert!(aim.abs_diff_eq(expected, 1e-4));
+        }
+    }
+
+    #[test]
+    fn player_dimensions_are_pinned() {
+        // Changing these moves hitboxes, grounding and shadows, on every client and server
+        assert_eq!(PLAYER_RADIUS, 0.25);
+        assert_eq!(PLAYER_CAPSULE_LENGTH, 0.5);
+        assert_eq!(PLAYER_HEIGHT, 1.0);
+        assert_eq!(PLAYER_HALF_HEIGHT, 0.5);
+        assert_eq!(PLAYER_CROUCH_CAPSULE_LENGTH, 0.0);
+        // Spawned just above the ground, close enough to count as standing on it
+        let gap = PLAYER_SPAWN_HEIGHT - PLAYER_HALF_HEIGHT;
+        assert!(gap > 0.0 && gap < GROUND_CHECK_DISTANCE);
+    }
+
+    #[test]
+    fn both_ends_spawn_the_same_capsule() {
+        // The client's collider and the server's collider and debug mesh all come from a
+        // fresh stance in player_bundle and server_spawn_player
+        let capsule = Stance::default().capsule();
+        assert_eq!(capsule.depth, PLAYER_CAPSULE_LENGTH);
+        assert_eq!(capsule.radius, PLAYER_RADIUS);
+        assert_eq!(capsule.depth / 2.0 + capsule.radius, PLAYER_HALF_HEIGHT);
+    }
+
+    #[test]
+    fn crouching_input_gives_the_crouched_hitbox() {
+        // The server only has the remote player's input to go on
+        let input = PlayerInput {
+            crouch: true,
+            ..default()
+        };
+        let stance = Stance::from_input(&input, false);
+        assert!(stance.crouching);
+        assert_eq!(stance.capsule_length(), PLAYER_CROUCH_CAPSULE_LENGTH);
+        assert_eq!(
+            stance.half_height(),
+            PLAYER_CROUCH_CAPSULE_LENGTH / 2.0 + PLAYER_RADIUS
+        );
+
+        let swimming = Stance::from_input(&input, true);
+        assert_eq!(swimming.capsule_length(), PLAYER_CAPSULE_LENGTH);
+        assert_eq!(swimming.half_height(), PLAYER_HALF_HEIGHT);
+    }
+
+    // Speed after braking from 6 on the ground for a number of 60 fps ticks
+    fn braked_speed(model: FrictionModel, ticks: u32) -> f32 {
+        let mut config = Config::default();
+        config.physics.friction_model = model;
+        let mut velocity = LinearVelocity(Vec3::X * 6.0);
+        for _ in 0..ticks {
+            friction(&mut velocity, true, 5.0, &config, 1.0 / 60.0);
+        }
+        velocity.length()
+    }
+
+    #[test]
+    fn quake_friction_drops_linearly_to_a_stop() {
+        // Above ground_speed the drop scales with speed
+        let expected = 6.0 * (11.0f32 / 12.0).powi(4);
+        assert!((braked_speed(FrictionModel::QuakeLinear, 4) - expected).abs() < 1e-4);
+        assert_eq!(braked_speed(FrictionModel::QuakeLinear, 30), 0.0);
+    }
+
+    #[test]
+    fn ice_slows_players_down_slower() {
+        let config = Config::default();
+        let speed_after = |surface: Option<&SurfaceFriction>| {
+            let ground_friction = surface_friction(surface, &config);
+            let mut velocity = LinearVelocity(Vec3::X * 6.0);
+            for _ in 0..15 {
+                friction(&mut velocity, true, ground_friction, &config, 1.0 / 60.0);
+            }
+            velocity.length()
+        };
+        let ground = speed_after(None);
+        let ice = speed_after(Some(&SurfaceFriction(config.physics.ground_friction / 8.0)));
+        let mud = speed_after(Some(&SurfaceFriction(config.physics.ground_friction * 2.0)));
+        assert!(ice > ground);
+        assert!(mud < ground);
+    }
+
+    #[test]
+    fn water_floats_players_up_and_slows_them() {
+        let config = Config::default();
+        let gravity = Vec3::NEG_Y * config.physics.gravity;
+        let idle = PlayerInput::default();
+        let mut sinking = LinearVelocity(Vec3::NEG_Y * 4.0);
+        for _ in 0..60 {
+            swim(
+                &mut sinking,
+                Vec3::ZERO,
+                &idle,
+                gravity,
+                Vec3::Y,
+                &config,
+                1.0 / 60.0,
+            );
+        }
+        assert!(sinking.y > 0.0);
+
+        // Running into the water for a second against running on the ground
+        let mut swimming = LinearVelocity(Vec3::ZERO);
+        let mut running = LinearVelocity(Vec3::ZERO);
+        for _ in 0..60 {
+            swim(
+                &mut swimming,
+                Vec3::X,
+                &idle,
+                gravity,
+                Vec3::Y,
+                &config,
+                1.0 / 60.0,
+            );
+            friction(&mut running, true, 5.0, &config, 1.0 / 60.0);
+            accelerate(
+                &mut running,
+                Vec3::X,
+                config.physics.ground_speed,
+                true,
+                &config,
+                1.0 / 60.0,
+            );
+        }
+        assert!(swimming.x <= config.physics.swim_speed + 1e-4);
+        assert!(swimming.x < running.x);
+    }
+
+    #[test]
+    fn exponential_friction_decays() {
+        let expected = 6.0 * (-5.0f32 * 0.5).exp();
+        assert!((braked_speed(FrictionModel::Exponential, 30) - expected).abs() < 1e-3);
+        assert_eq!(braked_speed(FrictionModel::Exponential, 300), 0.0);
+    }
+
+    #[test]
+    fn repeated_create_keeps_one_player() {
+        let id = ClientId::from_raw(1);
+        let client_entity = Entity::from_raw(10);
+        let mut lobby = ClientLobby::default();
+        let mut network_mapping = NetworkMapping::default();
+        lobby.players.insert(
+            id,
+            PlayerInfo {
+                server_entity: Entity::from_raw(1),
+                client_entity,
+            },
+        );
+        network_mapping.0.insert(Entity::from_raw(1), client_entity);
+
+        // The same create again changes nothing
+        assert_eq!(
+            remap_player(&mut lobby, &mut network_mapping, id, Entity::from_raw(1)),
+            None
+        );
+        // A create with a new server entity moves the mapping over
+        assert_eq!(
+            remap_player(&mut lobby, &mut network_mapping, id, Entity::from_raw(2)),
+            Some(client_entity)
+        );
+        assert_eq!(lobby.players.len(), 1);
+        assert_eq!(network_mapping.0.len(), 1);
+        assert_eq!(network_mapping.0[&Entity::from_raw(2)], client_entity);
+
+        let unknown = ClientId::from_raw(2);
+        assert_eq!(
+            remap_player(
+                &mut lobby,
+                &mut network_mapping,
+                unknown,
+                Entity::from_raw(3)
+            ),
+            None
+        );
+    }
+
+    // Difference between two angles in degrees, ignoring whole turns
+    fn angle_diff(a: f32, b: f32) -> f32 {
+        let diff = (a - b).to_degrees().rem_euclid(360.0);
+        diff.min(360.0 - diff)
+    }
+
+    #[test]
+    fn lerp_yaw_turns_forward_through_zero() {
+        let from = 350.0f32.to_radians();
+        let to = 10.0f32.to_radians();
+        assert!(angle_diff(lerp_yaw(from, to, 0.25), 355.0f32.to_radians()) < 1e-3);
+        assert!(angle_diff(lerp_yaw(from, to, 0.5), 0.0) < 1e-3);
+        assert!(angle_diff(lerp_yaw(from, to, 1.0), to) < 1e-3);
+        // And back the same way
+        assert!(angle_diff(lerp_yaw(to, from, 0.5), 0.0) < 1e-3);
+    }
+
+    #[test]
+    fn oscillating_speed_doesnt_flicker() {
+        let speeds: Vec<f32> = (0..60).map(|i| [0.05, 0.3][i % 2]).collect();
+        assert!(sequences(&speeds)
+            .iter()
+            .all(|sequence| *sequence == Sequence::Idle));
+    }
+
+    #[test]
+    fn steady_speed_switches_after_debounce() {
+        let sequences = sequences(&[0.3; 12]);
+        assert_eq!(sequences[0], Sequence::Idle);
+        assert_eq!(sequences[11], Sequence::Walk);
     }
 }
@@ -0,0 +1,88 @@
+use std::marker::PhantomData;
+
+use bevy::prelude::*;
+
+// Keeps short lived effects like floating text around once they're done, hidden, and
+// hands them out again for the next one. Reused entities get their whole bundle again,
+// which lands them in the same archetype instead of spawning and despawning.
+#[derive(Resource)]
+pub struct EffectPool<T> {
+    free: Vec<Entity>,
+    // Effects spawned new and handed out again since startup, for the stats overlay
+    pub spawned: u32,
+    pub reused: u32,
+    marker: PhantomData<T>,
+}
+
+impl<T> Default for EffectPool<T> {
+    fn default() -> Self {
+        Self {
+            free: Vec::new(),
+            spawned: 0,
+            reused: 0,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Component> EffectPool<T> {
+    pub fn acquire(&mut self, commands: &mut Commands, bundle: impl Bundle) -> Entity {
+        match self.free.pop() {
+            Some(entity) => {
+                self.reused += 1;
+                commands.entity(entity).insert(bundle);
+                entity
+            }
+            None => {
+                self.spawned += 1;
+                commands.spawn(bundle).id()
+            }
+        }
+    }
+
+    // Hidden until acquired again, systems updating the effect should skip hidden ones
+    pub fn release(&mut self, commands: &mut Commands, entity: Entity) {
+        commands.entity(entity).insert(Visibility::Hidden);
+        self.free.push(entity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::CommandQueue;
+
+    use super::*;
+
+    #[derive(Component)]
+    struct Effect(u32);
+
+    #[test]
+    fn released_effects_are_handed_out_again() {
+        let mut world = World::new();
+        let mut queue = CommandQueue::default();
+        let mut pool = EffectPool::<Effect>::default();
+
+        let first = pool.acquire(
+            &mut Commands::new(&mut queue, &world),
+            (Effect(1), Visibility::Inherited),
+        );
+        queue.apply(&mut world);
+        pool.release(&mut Commands::new(&mut queue, &world), first);
+        queue.apply(&mut world);
+        assert_eq!(world.get::<Visibility>(first), Some(&Visibility::Hidden));
+
+        let second = pool.acquire(
+            &mut Commands::new(&mut queue, &world),
+            (Effect(2), Visibility::Inherited),
+        );
+        queue.apply(&mut world);
+        assert_eq!(second, first);
+        assert_eq!(world.get::<Effect>(second).map(|effect| effect.0), Some(2));
+        assert_eq!(
+            world.get::<Visibility>(second),
+            Some(&Visibility::Inherited)
+        );
+        assert_eq!((pool.spawned, pool.reused), (1, 1));
+        assert_eq!(world.entities().len(), 1);
+    }
+}
@@ -0,0 +1,557 @@
+use bevy::{
+    prelude::*,
+    render::camera::ScalingMode,
+    utils::{HashMap, Instant},
+    window::{PresentMode, PrimaryWindow, WindowResized},
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::input::{Chord, InputAction, WheelDirection};
+use crate::networking::Team;
+use crate::player::CAMERA_DISTANCE;
+use crate::MainCamera;
+
+const SETTINGS_PATH: &str = "config/settings.ron";
+
+// User preferences, kept apart from the gameplay values in Config. The client loads
+// them before building the app since the window is created from them.
+pub struct SettingsPlugin;
+
+impl Plugin for SettingsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            Update,
+            (
+                toggle_projection,
+                toggle_vsync,
+                track_window_resolution,
+                apply_camera_settings,
+                apply_present_mode,
+                save_settings,
+            )
+                .chain(),
+        )
+        .add_systems(Last, limit_frame_rate);
+    }
+}
+
+// Settings missing from an older file are filled in from the defaults, so adding one
+// doesn't throw away the rest
+#[derive(Serialize, Deserialize, Resource)]
+#[serde(default)]
+pub struct Settings {
+    pub key_bindings: HashMap<KeyCode, Vec<InputAction>>,
+    pub chord_bindings: Vec<Chord>,
+    pub mouse_bindings: HashMap<MouseButton, Vec<InputAction>>,
+    pub wheel_bindings: HashMap<WheelDirection, Vec<InputAction>>,
+    pub camera: CameraSettings,
+    pub audio: AudioSettings,
+    pub graphics: GraphicsSettings,
+    pub hud: HudSettings,
+    pub palette: Palette,
+    pub control_scheme: ControlScheme,
+    pub crosshair: CrosshairMode,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ControlScheme {
+    // Face the cursor and move relative to where you're facing
+    MouseAim,
+    // Move relative to the screen and face where you're going
+    MovementFacing,
+    // Move relative to the screen and face with the face keys, twin-stick style
+    FaceKeys,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CrosshairMode {
+    // On the floor under the player, wherever the cursor points
+    Plane,
+    // On whatever the cursor points at, like the top of a crate or the side of a wall
+    Geometry,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct CameraSettings {
+    // Screen pixels per world unit
+    pub zoom: f32,
+    pub perspective: bool,
+    pub angle: CameraAngle,
+    pub mode: CameraMode,
+}
+
+// Where the camera follows the player from
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CameraMode {
+    // From a corner, at the angle in the settings
+    Isometric,
+    // Straight down from above
+    TopDown,
+    // Behind the player and turning with them, always in perspective. The mouse turns
+    // the player instead of aiming at the cursor.
+    OverShoulder,
+}
+
+// Over the shoulder camera, relative to the player and the point it looks at ahead of
+// them
+const SHOULDER_OFFSET: Vec3 = Vec3::new(0.4, 1.2, 2.5);
+const SHOULDER_TARGET: Vec3 = Vec3::new(0.0, 0.6, -4.0);
+const SHOULDER_FOV: f32 = 70.0;
+
+impl CameraSettings {
+    // Where the camera sits when following the target. The lead moves the camera
+    // toward the crosshair in the modes looking down at the map.
+    pub fn follow(&self, target: &Transform, lead: Vec3) -> Transform {
+        let mut ground = target.translation;
+        ground.y = 0.0;
+        match self.mode {
+            CameraMode::Isometric => {
+                let mut transform = self.angle.transform();
+                transform.translation += ground + lead;
+                transform
+            }
+            CameraMode::TopDown => {
+                Transform::from_translation(ground + lead + Vec3::Y * CAMERA_DISTANCE)
+                    .looking_at(ground + lead, Vec3::NEG_Z)
+            }
+            CameraMode::OverShoulder => {
+                let facing = Quat::from_rotation_y(target.rotation.to_euler(EulerRot::YXZ).0);
+                Transform::from_translation(target.translation + facing * SHOULDER_OFFSET)
+                    .looking_at(target.translation + facing * SHOULDER_TARGET, Vec3::Y)
+            }
+        }
+    }
+
+    pub fn uses_perspective(&self) -> bool {
+        self.perspective || self.mode == CameraMode::OverShoulder
+    }
+}
+
+// How steeply the camera looks down at the map, always from a corner
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CameraAngle {
+    // True isometric, all three axes shortened the same
+    Isometric,
+    // Lower at 30 degrees, tiles come out twice as wide as they are tall like in pixel
+    // art isometric games
+    TwoToOne,
+    // Higher at 45 degrees, showing more of the ground between things
+    Dimetric,
+}
+
+impl CameraAngle {
+    fn elevation(&self) -> f32 {
+        match self {
+            CameraAngle::Isometric => (1.0 / 2.0_f32.sqrt()).atan(),
+            CameraAngle::TwoToOne => 30.0_f32.to_radians(),
+            CameraAngle::Dimetric => 45.0_f32.to_radians(),
+        }
+    }
+
+    // Where the camera sits relative to the point it follows
+    pub fn offset(&self) -> Vec3 {
+        let elevation = self.elevation();
+        let across = elevation.cos() * std::f32::consts::FRAC_1_SQRT_2;
+        Vec3::new(across, elevation.sin(), across) * CAMERA_DISTANCE
+    }
+
+    pub fn transform(&self) -> Transform {
+        Transform::from_translation(self.offset()).looking_at(Vec3::ZERO, Vec3::Y)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioSettings {
+    pub master_volume: f32,
+    pub music_volume: f32,
+    pub effects_volume: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct GraphicsSettings {
+    pub resolution: (f32, f32),
+    pub vsync: bool,
+    // Frames per second to stay under, to save power when the frame rate is uncapped
+    pub frame_cap: Option<f32>,
+    pub texture_filtering: TextureFiltering,
+    // Fade between animation frames instead of switching straight to the next
+    pub blend_frames: bool,
+    // How dark sprites get where they meet the ground, 0 to turn it off. Applies to
+    // sprites spawned after it's changed.
+    pub contact_shadow: f32,
+    // Sprites further than this from the middle of the screen aren't drawn, no limit
+    // when unset
+    pub draw_distance: Option<f32>,
+}
+
+// How textures are sampled when scaled. Nearest keeps pixel art crisp, linear suits
+// high resolution sheets. Only read at startup since it's set up with the ImagePlugin.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TextureFiltering {
+    Nearest,
+    Linear,
+}
+
+impl GraphicsSettings {
+    pub fn present_mode(&self) -> PresentMode {
+        if self.vsync {
+            PresentMode::Fifo
+        } else {
+            PresentMode::AutoNoVsync
+        }
+    }
+}
+
+impl TextureFiltering {
+    pub fn image_plugin(&self) -> ImagePlugin {
+        match self {
+            TextureFiltering::Nearest => ImagePlugin::default_nearest(),
+            TextureFiltering::Linear => ImagePlugin::default_linear(),
+        }
+    }
+}
+
+// Whether the HUD is shown at all, and which of its elements
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct HudSettings {
+    pub visible: bool,
+    pub fps: bool,
+    pub speed: bool,
+    pub max_speed: bool,
+    // Detailed breakdown of the velocity for movement practice
+    pub velocity: bool,
+    pub font_size: f32,
+    // Where each readout sits, in pixels from the top left of the window
+    pub fps_position: (f32, f32),
+    pub speed_position: (f32, f32),
+    pub max_speed_position: (f32, f32),
+    pub velocity_position: (f32, f32),
+}
+
+// Colors used to tell the teams apart, with alternatives for color vision deficiencies
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Palette {
+    // Green and red
+    Standard,
+    // Safe for red-green deficiencies
+    OrangeBlue,
+    // Safe for blue-yellow deficiencies
+    MagentaTeal,
+}
+
+impl Palette {
+    // Jinrai first, then NSF
+    pub fn team_colors(&self) -> [Color; 2] {
+        match self {
+            Palette::Standard => [Color::rgb(0.3, 0.8, 0.3), Color::rgb(0.9, 0.25, 0.2)],
+            Palette::OrangeBlue => [Color::rgb(1.0, 0.6, 0.1), Color::rgb(0.2, 0.45, 1.0)],
+            Palette::MagentaTeal => [Color::rgb(0.9, 0.2, 0.7), Color::rgb(0.1, 0.7, 0.7)],
+        }
+    }
+
+    pub fn team_color(&self, team: Team) -> Color {
+        let [jinrai, nsf] = self.team_colors();
+        match team {
+            Team::Jinrai => jinrai,
+            Team::Nsf => nsf,
+        }
+    }
+}
+
+impl Default for CameraSettings {
+    fn default() -> Self {
+        Self {
+            zoom: 64.0,
+            perspective: false,
+            angle: CameraAngle::Isometric,
+            mode: CameraMode::Isometric,
+        }
+    }
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        Self {
+            master_volume: 1.0,
+            music_volume: 1.0,
+            effects_volume: 1.0,
+        }
+    }
+}
+
+impl Default for GraphicsSettings {
+    fn default() -> Self {
+        Self {
+            resolution: (1280.0, 720.0),
+            vsync: true,
+            frame_cap: None,
+            texture_filtering: TextureFiltering::Nearest,
+            blend_frames: false,
+            contact_shadow: 0.0,
+            draw_distance: None,
+        }
+    }
+}
+
+impl Default for HudSettings {
+    fn default() -> Self {
+        Self {
+            visible: true,
+            fps: true,
+            speed: true,
+            max_speed: true,
+            velocity: false,
+            font_size: 24.0,
+            fps_position: (12.0, 0.0),
+            speed_position: (12.0, 20.0),
+            max_speed_position: (12.0, 40.0),
+            velocity_position: (12.0, 60.0),
+        }
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            key_bindings: HashMap::from_iter(vec![
+                (KeyCode::W, vec![InputAction::Forward]),
+                (KeyCode::S, vec![InputAction::Back]),
+                (KeyCode::A, vec![InputAction::Left]),
+                (KeyCode::D, vec![InputAction::Right]),
+                (KeyCode::Space, vec![InputAction::Jump]),
+                (KeyCode::ControlLeft, vec![InputAction::Crouch]),
+                (KeyCode::ShiftLeft, vec![InputAction::Walk]),
+                (KeyCode::Key1, vec![InputAction::Slot1]),
+                (KeyCode::Key2, vec![InputAction::Slot2]),
+                (KeyCode::Key3, vec![InputAction::Slot3]),
+                (KeyCode::F2, vec![InputAction::ToggleHud]),
+                (KeyCode::F3, vec![InputAction::ToggleFps]),
+                (KeyCode::F4, vec![InputAction::ToggleSpeed]),
+                (KeyCode::F5, vec![InputAction::ToggleMaxSpeed]),
+                (KeyCode::F6, vec![InputAction::ResetMaxSpeed]),
+                (KeyCode::F7, vec![InputAction::ToggleVelocity]),
+                (KeyCode::F8, vec![InputAction::ToggleSpeedRecording]),
+                (KeyCode::F9, vec![InputAction::ToggleVsync]),
+                (KeyCode::F10, vec![InputAction::ToggleStats]),
+                (KeyCode::F11, vec![InputAction::ReloadMap]),
+                (KeyCode::P, vec![InputAction::ToggleProjection]),
+                (KeyCode::V, vec![InputAction::SpectateNext]),
+                (KeyCode::Up, vec![InputAction::FaceForward]),
+                (KeyCode::Down, vec![InputAction::FaceBack]),
+                (KeyCode::Left, vec![InputAction::FaceLeft]),
+                (KeyCode::Right, vec![InputAction::FaceRight]),
+            ]),
+            chord_bindings: Vec::new(),
+            mouse_bindings: HashMap::from_iter(vec![(
+                MouseButton::Left,
+                vec![InputAction::Attack],
+            )]),
+            wheel_bindings: HashMap::from_iter(vec![
+                (WheelDirection::Up, vec![InputAction::PrevWeapon]),
+                (WheelDirection::Down, vec![InputAction::NextWeapon]),
+            ]),
+            camera: CameraSettings::default(),
+            audio: AudioSettings::default(),
+            graphics: GraphicsSettings::default(),
+            hud: HudSettings::default(),
+            palette: Palette::Standard,
+            control_scheme: ControlScheme::MouseAim,
+            crosshair: CrosshairMode::Plane,
+        }
+    }
+}
+
+impl Settings {
+    // Runs before the app and its logger exist, so problems are printed directly
+    pub fn load() -> Self {
+        match std::fs::read_to_string(SETTINGS_PATH) {
+            Ok(settings) => ron::from_str(&settings).unwrap_or_else(|err| {
+                println!(
+                    "Failed to parse settings! Backing up and writing new ones.\n{}",
+                    err
+                );
+                std::fs::copy(SETTINGS_PATH, "config/settings.old.ron").unwrap_or_else(|err| {
+                    println!("Unable to backup old settings!\n{}", err);
+                    0
+                });
+                Self::save_default()
+            }),
+            _ => Self::save_default(),
+        }
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let pretty = ron::ser::PrettyConfig::new().depth_limit(2);
+        let settings_str = ron::ser::to_string_pretty(self, pretty)?;
+        std::fs::create_dir_all("config/")?;
+        std::fs::write(SETTINGS_PATH, settings_str)?;
+        Ok(())
+    }
+
+    fn save_default() -> Self {
+        let settings = Self::default();
+        settings.save().unwrap_or_else(|err| {
+            println!("Failed to write settings to '{}'!\n{}", SETTINGS_PATH, err)
+        });
+        settings
+    }
+}
+
+fn track_window_resolution(
+    mut resize_events: EventReader<WindowResized>,
+    primary_window: Query<Entity, With<PrimaryWindow>>,
+    mut settings: ResMut<Settings>,
+) {
+    let Ok(primary_window) = primary_window.get_single() else {
+        return;
+    };
+    for event in resize_events.read() {
+        let resolution = (event.width, event.height);
+        if event.window == primary_window && settings.graphics.resolution != resolution {
+            settings.graphics.resolution = resolution;
+        }
+    }
+}
+
+fn toggle_projection(input: Res<Input<InputAction>>, mut settings: ResMut<Settings>) {
+    if input.just_pressed(InputAction::ToggleProjection) {
+        settings.camera.perspective = !settings.camera.perspective;
+    }
+}
+
+fn toggle_vsync(input: Res<Input<InputAction>>, mut settings: ResMut<Settings>) {
+    if input.just_pressed(InputAction::ToggleVsync) {
+        settings.graphics.vsync = !settings.graphics.vsync;
+        info!(
+            "Vsync {}.",
+            if settings.graphics.vsync { "on" } else { "off" }
+        );
+    }
+}
+
+fn apply_present_mode(
+    settings: Res<Settings>,
+    mut primary_window: Query<&mut Window, With<PrimaryWindow>>,
+) {
+    if !settings.is_changed() {
+        return;
+    }
+    let present_mode = settings.graphics.present_mode();
+    for mut window in primary_window.iter_mut() {
+        // Only touched when it differs, since changing the window reconfigures it
+        if window.present_mode != present_mode {
+            window.present_mode = present_mode;
+        }
+    }
+}
+
+// Sleeps off whatever is left of the frame when running faster than the cap
+fn limit_frame_rate(settings: Res<Settings>, mut frame_start: Local<Option<Instant>>) {
+    if let (Some(frame_cap), Some(start)) = (settings.graphics.frame_cap, *frame_start) {
+        if frame_cap > 0.0 {
+            let frame_time = Duration::from_secs_f32(1.0 / frame_cap);
+            if let Some(remaining) = frame_time.checked_sub(start.elapsed()) {
+                std::thread::sleep(remaining);
+            }
+        }
+    }
+    *frame_start = Some(Instant::now());
+}
+
+fn apply_camera_settings(
+    settings: Res<Settings>,
+    primary_window: Query<&Window, With<PrimaryWindow>>,
+    mut query: Query<(&mut Projection, &mut Transform), With<MainCamera>>,
+) {
+    if !settings.is_changed() {
+        return;
+    }
+    for (mut projection, mut transform) in query.iter_mut() {
+        transform.rotation = settings
+            .camera
+            .follow(&Transform::IDENTITY, Vec3::ZERO)
+            .rotation;
+        match (projection.as_mut(), settings.camera.uses_perspective()) {
+            (Projection::Orthographic(orthographic), false) => {
+                orthographic.scale = 1.0 / settings.camera.zoom;
+            }
+            (_, false) => {
+                *projection = OrthographicProjection {
+                    scaling_mode: ScalingMode::WindowSize(1.0),
+                    scale: 1.0 / settings.camera.zoom,
+                    ..default()
+                }
+                .into();
+            }
+            (_, true) if settings.camera.mode == CameraMode::OverShoulder => {
+                *projection = PerspectiveProjection {
+                    fov: SHOULDER_FOV.to_radians(),
+                    ..default()
+                }
+                .into();
+            }
+            (_, true) => {
+                // Pick the field of view that shows as much of the ground around the
+                // player as the orthographic view does at the same zoom
+                let height = primary_window
+                    .get_single()
+                    .map_or(settings.graphics.resolution.1, |window| window.height());
+                let visible_height = height / settings.camera.zoom;
+                *projection = PerspectiveProjection {
+                    fov: 2.0 * (visible_height / 2.0 / CAMERA_DISTANCE).atan(),
+                    ..default()
+                }
+                .into();
+            }
+        }
+    }
+}
+
+fn save_settings(settings: Res<Settings>) {
+    if settings.is_changed() && !settings.is_added() {
+        settings.save().unwrap_or_else(|err| {
+            error!("Failed to write settings to '{}': {}", SETTINGS_PATH, err)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_settings_use_defaults() {
+        let settings: Settings =
+            ron::from_str("(graphics: (vsync: false), hud: (fps: false))").unwrap();
+        assert!(!settings.graphics.vsync);
+        assert_eq!(settings.graphics.resolution, (1280.0, 720.0));
+        assert!(!settings.graphics.blend_frames);
+        assert!(!settings.hud.fps);
+        assert_eq!(settings.hud.font_size, 24.0);
+        assert_eq!(settings.camera.mode, CameraMode::Isometric);
+        assert_eq!(settings.palette, Palette::Standard);
+        assert!(!settings.key_bindings.is_empty());
+    }
+
+    #[test]
+    fn palettes_map_teams_to_their_colors() {
+        let palette = Palette::OrangeBlue;
+        assert_eq!(palette.team_color(Team::Jinrai), Color::rgb(1.0, 0.6, 0.1));
+        assert_eq!(palette.team_color(Team::Nsf), Color::rgb(0.2, 0.45, 1.0));
+        let palette = Palette::MagentaTeal;
+        assert_eq!(palette.team_color(Team::Jinrai), Color::rgb(0.9, 0.2, 0.7));
+        assert_eq!(palette.team_color(Team::Nsf), Color::rgb(0.1, 0.7, 0.7));
+        // Every palette keeps the two teams apart
+        for palette in [Palette::Standard, Palette::OrangeBlue, Palette::MagentaTeal] {
+            assert_ne!(
+                palette.team_color(Team::Jinrai),
+                palette.team_color(Team::Nsf)
+            );
+        }
+    }
+}
@@ -7,7 +7,7 @@ use bevy::{
 use bevy_xpbd_3d::plugins::spatial_query::{SpatialQuery, SpatialQueryFilter};
 use serde::{Deserialize, Serialize};
 
-use crate::{physics::Layer, MainCamera};
+use crate::{physics::Layer, player::Despawning, settings::Settings, MainCamera};
 
 pub struct Sprite3dPlugin;
 
@@ -20,7 +20,16 @@ impl Plugin for Sprite3dPlugin {
                 PostUpdate,
                 (check_sequence, rotate_sprites, animate_sprites).chain(),
             )
-            .add_systems(Last, (align_billboards, project_blob_shadows));
+            .add_systems(Update, attach_contact_shadows)
+            .add_systems(
+                Last,
+                (
+                    (align_billboards, align_contact_shadows).chain(),
+                    project_blob_shadows,
+                    cull_distant_sprites,
+                )
+                    .run_if(any_with_component::<MainCamera>()),
+            );
     }
 }
 
@@ -32,6 +41,8 @@ pub struct Animation {
     length: u8,
     speed: f32,
     rotates: bool,
+    // One-shot animations stop on their last frame instead of starting over
+    looping: bool,
 }
 
 #[derive(Component)]
@@ -40,6 +51,9 @@ pub struct Animator {
     frame: u8,
     direction: u8,
     next_frame: f64,
+    // Length of the animation playing, kept for progress queries
+    length: u8,
+    finished: bool,
 }
 
 impl Animator {
@@ -49,16 +63,99 @@ impl Animator {
             frame: 0,
             direction: 0,
             next_frame: 0.0,
+            length: 0,
+            finished: false,
+        }
+    }
+
+    pub fn current_frame(&self) -> u8 {
+        self.frame
+    }
+
+    // Whether a one-shot animation has shown its last frame for its full duration.
+    // Looping animations never finish.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    // Moves on a frame once the current one has been shown for its time. The first call
+    // only starts the clock.
+    fn advance(&mut self, animation: &Animation, speed_scale: f32, now: f64) {
+        self.length = animation.length;
+        if animation.speed <= 0.0 || speed_scale <= 0.0 || self.finished || now <= self.next_frame {
+            return;
+        }
+        if self.next_frame == 0.0 {
+            self.next_frame = now;
+        } else if animation.looping || self.frame + 1 < animation.length {
+            self.frame = (self.frame + 1) % animation.length;
+        } else {
+            self.finished = true;
+        }
+        self.next_frame += (animation.speed / speed_scale) as f64
+    }
+
+    // Frame to fade in over the current one and how far along towards it we are, none
+    // when blending is off or the animation isn't moving on to another frame
+    fn blend(
+        &self,
+        animation: &Animation,
+        speed_scale: f32,
+        now: f64,
+        blend_frames: bool,
+    ) -> Option<(u8, f32)> {
+        let animating = blend_frames
+            && animation.length > 1
+            && animation.speed > 0.0
+            && speed_scale > 0.0
+            && (animation.looping || self.frame + 1 < animation.length);
+        if !animating {
+            return None;
+        }
+        let frame_time = (animation.speed / speed_scale) as f64;
+        let progress = 1.0 - ((self.next_frame - now) / frame_time) as f32;
+        let next_frame = (self.frame + 1) % animation.length + self.direction * animation.length;
+        Some((next_frame, progress))
+    }
+
+    // How far through the sequence we are, from 0 at the first frame to 1 once finished
+    pub fn sequence_progress(&self) -> f32 {
+        if self.finished {
+            1.0
+        } else {
+            self.frame as f32 / self.length.max(1) as f32
         }
     }
 }
 
-#[derive(Component, Debug, Hash, Eq, PartialEq, Serialize, Deserialize)]
+// Multiplier for how fast an entity plays its animations
+#[derive(Component)]
+pub struct AnimationSpeedScale(pub f32);
+
+impl Default for AnimationSpeedScale {
+    fn default() -> Self {
+        Self(1.0)
+    }
+}
+
+#[derive(Component, Debug, Clone, Copy, Hash, Eq, PartialEq, Serialize, Deserialize)]
 pub enum Sequence {
     None,
     Idle,
     Walk,
     Jump,
+    Deploy,
+    Swim,
+}
+
+impl Sequence {
+    // What to play instead when an animation set has nothing for this sequence
+    fn fallback(&self) -> Sequence {
+        match self {
+            Sequence::Swim => Sequence::Jump,
+            _ => Sequence::Idle,
+        }
+    }
 }
 
 #[derive(Asset, Deref, DerefMut, Serialize, Deserialize, TypeUuid, TypePath)]
@@ -99,17 +196,16 @@ fn check_sequence(
     animation_sets: Res<Assets<AnimationSet>>,
     asset_server: Res<AssetServer>,
     mut materials: ResMut<Assets<StandardMaterial>>,
-    mut query: Query<(&mut Animator, &mut Sequence, &Handle<StandardMaterial>), Changed<Sequence>>,
+    mut query: Query<(&mut Animator, &Sequence, &Handle<StandardMaterial>), Changed<Sequence>>,
 ) {
-    for (mut animator, mut sequence, material_handle) in &mut query {
-        if let Some(animation_set) = animation_sets.get(&animator.animation_handle) {
-            if !animation_set.contains_key(sequence.as_ref()) {
-                *sequence = Sequence::Idle;
-            }
+    for (mut animator, sequence, material_handle) in &mut query {
+        if let Some(animation) =
+            get_animation(&animation_sets, &animator.animation_handle, sequence)
+        {
             animator.frame = 0;
             animator.next_frame = 0.0;
+            animator.finished = false;
             if let Some(material) = materials.get_mut(material_handle) {
-                let animation = animation_set.get(sequence.as_ref()).unwrap();
                 material.base_color_texture = Some(asset_server.load(&animation.texture));
             }
         }
@@ -121,13 +217,16 @@ fn get_animation<'a>(
     animation_handle: &Handle<AnimationSet>,
     sequence: &Sequence,
 ) -> Option<&'a Animation> {
-    animation_sets.get(animation_handle)?.get(sequence)
+    let animation_set = animation_sets.get(animation_handle)?;
+    animation_set
+        .get(sequence)
+        .or_else(|| animation_set.get(&sequence.fallback()))
 }
 
 fn get_texture<'a>(
-    materials: &'a Res<Assets<StandardMaterial>>,
+    materials: &'a Assets<StandardMaterial>,
     material_handle: &Handle<StandardMaterial>,
-    textures: &'a Res<Assets<Image>>,
+    textures: &'a Assets<Image>,
 ) -> Option<&'a Image> {
     let texture_handle = materials
         .get(material_handle)?
@@ -136,19 +235,35 @@ fn get_texture<'a>(
     textures.get(texture_handle)
 }
 
+// Facing of the isometric camera, which the sprite sheets are drawn for
+const SHEET_VIEW_YAW: f32 = std::f32::consts::FRAC_PI_4;
+
 fn rotate_sprites(
     animation_sets: Res<Assets<AnimationSet>>,
     mut query: Query<(&mut Animator, &Sequence, &Parent)>,
-    p_query: Query<&Transform, Changed<Transform>>,
+    p_query: Query<Ref<Transform>, Without<MainCamera>>,
+    cam_query: Query<Ref<Transform>, With<MainCamera>>,
 ) {
+    // Directions are picked relative to the camera, for the camera modes that turn
+    let (view, cam_changed) = cam_query.get_single().map_or((0.0, false), |cam| {
+        (
+            cam.rotation.to_euler(EulerRot::YXZ).0 - SHEET_VIEW_YAW,
+            cam.is_changed(),
+        )
+    });
     for (mut animator, sequence, parent) in query.iter_mut() {
-        if let (Some(animation), Ok(transform)) = (
-            get_animation(&animation_sets, &animator.animation_handle, sequence),
-            p_query.get(parent.get()),
-        ) {
+        let Ok(transform) = p_query.get(parent.get()) else {
+            continue;
+        };
+        if !transform.is_changed() && !cam_changed {
+            continue;
+        }
+        if let Some(animation) =
+            get_animation(&animation_sets, &animator.animation_handle, sequence)
+        {
             animator.direction = if animation.rotates {
-                let (direction, _, _) = transform.rotation.to_euler(EulerRot::YXZ);
-                ((-direction + 3.0 * std::f32::consts::FRAC_PI_8 + std::f32::consts::TAU)
+                let direction = transform.rotation.to_euler(EulerRot::YXZ).0 - view;
+                ((-direction + 3.0 * std::f32::consts::FRAC_PI_8).rem_euclid(std::f32::consts::TAU)
                     / std::f32::consts::FRAC_PI_4) as u8
                     % 8
             } else {
@@ -158,63 +273,142 @@ fn rotate_sprites(
     }
 }
 
+// Sprite with a second quad on top showing the next frame, faded in over the current
+// one to smooth out animations with few frames
+#[derive(Component)]
+pub struct FrameBlend {
+    pub overlay: Entity,
+}
+
+#[derive(Component)]
+pub struct FrameBlendOverlay;
+
+// Material for the blend overlay, drawn just in front of the sprite it covers
+pub fn frame_blend_material() -> StandardMaterial {
+    StandardMaterial {
+        alpha_mode: AlphaMode::Blend,
+        reflectance: 0.0,
+        metallic: 0.0,
+        perceptual_roughness: 1.0,
+        depth_bias: 1.0,
+        ..default()
+    }
+}
+
+#[allow(clippy::type_complexity, clippy::too_many_arguments)]
 fn animate_sprites(
     time: Res<Time>,
+    settings: Option<Res<Settings>>,
     mut meshes: ResMut<Assets<Mesh>>,
     animation_sets: Res<Assets<AnimationSet>>,
-    materials: Res<Assets<StandardMaterial>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
     textures: Res<Assets<Image>>,
     mut query: Query<(
         &Handle<Mesh>,
         &Handle<StandardMaterial>,
         &mut Animator,
         &Sequence,
+        Option<&AnimationSpeedScale>,
+        Option<&FrameBlend>,
     )>,
+    mut overlays: Query<
+        (&Handle<Mesh>, &Handle<StandardMaterial>, &mut Visibility),
+        With<FrameBlendOverlay>,
+    >,
 ) {
-    for (mesh_handle, material_handle, mut animator, sequence) in query.iter_mut() {
+    let blend_frames = settings.is_some_and(|settings| settings.graphics.blend_frames);
+    for (mesh_handle, material_handle, mut animator, sequence, speed_scale, frame_blend) in
+        query.iter_mut()
+    {
         if let Some(animation) =
             get_animation(&animation_sets, &animator.animation_handle, sequence)
         {
-            if animation.speed > 0.0 && time.elapsed_seconds_f64() > animator.next_frame {
-                if animator.next_frame == 0.0 {
-                    animator.next_frame = time.elapsed_seconds_f64();
-                } else {
-                    animator.frame = (animator.frame + 1) % animation.length;
-                }
-                animator.next_frame += animation.speed as f64
-            }
+            let speed_scale = speed_scale.map_or(1.0, |scale| scale.0);
+            animator.advance(animation, speed_scale, time.elapsed_seconds_f64());
 
             let frame = animator.frame + animator.direction * animation.length;
 
-            if let Some(texture) = get_texture(&materials, material_handle, &textures) {
-                let texture_size = texture.size();
-                let size_x = animation.size.0 / texture_size.x as f32;
-                let size_y = animation.size.1 / texture_size.y as f32;
-                let offset_x = (frame % animation.length) as f32 * size_x;
-                let offset_y = (frame / animation.length) as f32 * size_y;
-                // info!("frame: {}, size_x: {}, size_y: {}", frame, size_x, size_y);
-
-                if let Some(mesh) = meshes.get_mut(mesh_handle) {
-                    let uvs = vec![
-                        [0.0 + offset_x, size_y + offset_y],
-                        [0.0 + offset_x, 0.0 + offset_y],
-                        [size_x + offset_x, 0.0 + offset_y],
-                        [size_x + offset_x, size_y + offset_y],
-                    ];
-                    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
-                }
-            } else {
-                info!("Texture not loaded");
+            let Some(texture_size) =
+                get_texture(&materials, material_handle, &textures).map(|texture| texture.size())
+            else {
+                debug!("Texture not loaded");
+                continue;
+            };
+            if let Some(mesh) = meshes.get_mut(mesh_handle) {
+                mesh.insert_attribute(
+                    Mesh::ATTRIBUTE_UV_0,
+                    frame_uvs(animation, frame, texture_size),
+                );
+            }
+
+            let Some(Ok((overlay_mesh, overlay_material, mut visibility))) =
+                frame_blend.map(|frame_blend| overlays.get_mut(frame_blend.overlay))
+            else {
+                continue;
+            };
+            let Some((next_frame, progress)) = animator.blend(
+                animation,
+                speed_scale,
+                time.elapsed_seconds_f64(),
+                blend_frames,
+            ) else {
+                *visibility = Visibility::Hidden;
+                continue;
+            };
+            *visibility = Visibility::Inherited;
+            if let Some(mesh) = meshes.get_mut(overlay_mesh) {
+                mesh.insert_attribute(
+                    Mesh::ATTRIBUTE_UV_0,
+                    frame_uvs(animation, next_frame, texture_size),
+                );
+            }
+            let Some((texture, base_color)) = materials
+                .get(material_handle)
+                .map(|material| (material.base_color_texture.clone(), material.base_color))
+            else {
+                continue;
+            };
+            if let Some(material) = materials.get_mut(overlay_material) {
+                material.base_color_texture = texture;
+                material.base_color = base_color.with_a(base_color.a() * progress.clamp(0.0, 1.0));
             }
         }
     }
 }
 
+// Texture coordinates of a frame in the sheet, rows being the directions
+fn frame_uvs(animation: &Animation, frame: u8, texture_size: UVec2) -> Vec<[f32; 2]> {
+    let size_x = animation.size.0 / texture_size.x as f32;
+    let size_y = animation.size.1 / texture_size.y as f32;
+    let offset_x = (frame % animation.length) as f32 * size_x;
+    let offset_y = (frame / animation.length) as f32 * size_y;
+    // info!("frame: {}, size_x: {}, size_y: {}", frame, size_x, size_y);
+    vec![
+        [0.0 + offset_x, size_y + offset_y],
+        [0.0 + offset_x, 0.0 + offset_y],
+        [size_x + offset_x, 0.0 + offset_y],
+        [size_x + offset_x, size_y + offset_y],
+    ]
+}
+
 fn align_billboards(
     mut query: Query<&mut GlobalTransform, (With<Billboard>, Without<MainCamera>)>,
     cam_query: Query<&GlobalTransform, With<MainCamera>>,
+    mut warned: Local<bool>,
 ) {
-    let cam_transform = cam_query.single();
+    let cam_transform = match cam_query.get_single() {
+        Ok(cam_transform) => {
+            *warned = false;
+            cam_transform
+        }
+        Err(err) => {
+            if !*warned {
+                warn!("Unable to align billboards: {}", err);
+                *warned = true;
+            }
+            return;
+        }
+    };
     for mut transform in query.iter_mut() {
         let translation = transform.translation();
         *transform = GlobalTransform::from(
@@ -224,6 +418,146 @@ fn align_billboards(
     }
 }
 
+// Sprite that gets darkened where it meets the ground when contact shadows are on, on
+// top of its blob shadow
+#[derive(Component)]
+pub struct ContactShadow;
+
+// The darkening itself, a quad fading out from the bottom of the sprite
+#[derive(Component)]
+struct ContactShadowQuad;
+
+// Share of the sprite's height covered, from the bottom
+const CONTACT_SHADOW_HEIGHT: f32 = 0.25;
+
+fn attach_contact_shadows(
+    mut commands: Commands,
+    settings: Option<Res<Settings>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    query: Query<(Entity, &Handle<Mesh>), Added<ContactShadow>>,
+) {
+    let strength = settings.map_or(0.0, |settings| settings.graphics.contact_shadow);
+    if strength <= 0.0 {
+        return;
+    }
+    for (entity, mesh_handle) in query.iter() {
+        let Some(aabb) = meshes.get(mesh_handle).and_then(|mesh| mesh.compute_aabb()) else {
+            continue;
+        };
+        let size = Vec2::new(aabb.half_extents.x, aabb.half_extents.y) * 2.0;
+        let height = size.y * CONTACT_SHADOW_HEIGHT;
+        let mut mesh = Mesh::from(shape::Quad {
+            size: Vec2::new(size.x, height),
+            ..default()
+        });
+        // Vertex colors make the gradient, so it needs no texture of its own
+        if let Some(positions) = mesh
+            .attribute(Mesh::ATTRIBUTE_POSITION)
+            .and_then(|positions| positions.as_float3())
+        {
+            let colors: Vec<[f32; 4]> = positions
+                .iter()
+                .map(|[_, y, _]| {
+                    [
+                        0.0,
+                        0.0,
+                        0.0,
+                        if *y < 0.0 { strength.min(1.0) } else { 0.0 },
+                    ]
+                })
+                .collect();
+            mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+        }
+        let bottom = aabb.center.y - aabb.half_extents.y;
+        commands.entity(entity).with_children(|parent| {
+            parent
+                .spawn(PbrBundle {
+                    mesh: meshes.add(mesh),
+                    material: materials.add(StandardMaterial {
+                        alpha_mode: AlphaMode::Blend,
+                        unlit: true,
+                        depth_bias: 1.0,
+                        ..default()
+                    }),
+                    transform: Transform::from_xyz(0.0, bottom + height / 2.0, 0.0),
+                    ..default()
+                })
+                .insert(ContactShadowQuad);
+        });
+    }
+}
+
+// Billboards are turned after transforms propagate, so the quads are placed on their
+// sprites again to follow
+fn align_contact_shadows(
+    sprites: Query<&GlobalTransform, (With<ContactShadow>, Without<ContactShadowQuad>)>,
+    mut query: Query<(&mut GlobalTransform, &Transform, &Parent), With<ContactShadowQuad>>,
+) {
+    for (mut global_transform, transform, parent) in query.iter_mut() {
+        if let Ok(sprite_transform) = sprites.get(parent.get()) {
+            *global_transform = sprite_transform.mul_transform(*transform);
+        }
+    }
+}
+
+// Distance over which sprites fade out before reaching the draw distance
+const DRAW_DISTANCE_FADE: f32 = 2.0;
+
+// Hides whatever a sprite belongs to once it's past the draw distance, measured across
+// the screen since the orthographic camera sees everything along its view the same.
+// Animated sprites fade out near the edge, the rest share materials and are cut off.
+#[allow(clippy::type_complexity)]
+fn cull_distant_sprites(
+    settings: Option<Res<Settings>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    cam_query: Query<&GlobalTransform, With<MainCamera>>,
+    sprites: Query<
+        (
+            &GlobalTransform,
+            &Parent,
+            &Handle<StandardMaterial>,
+            Has<Animator>,
+        ),
+        (With<Billboard>, Without<FrameBlendOverlay>),
+    >,
+    mut parents: Query<(&mut Visibility, Has<Despawning>)>,
+) {
+    let Ok(cam_transform) = cam_query.get_single() else {
+        return;
+    };
+    let draw_distance = settings
+        .and_then(|settings| settings.graphics.draw_distance)
+        .unwrap_or(f32::INFINITY);
+    let forward = cam_transform.forward();
+    for (transform, parent, material_handle, animated) in sprites.iter() {
+        let Ok((mut visibility, despawning)) = parents.get_mut(parent.get()) else {
+            continue;
+        };
+        let offset = transform.translation() - cam_transform.translation();
+        let distance = (offset - forward * offset.dot(forward)).length();
+        let new_visibility = if distance > draw_distance {
+            Visibility::Hidden
+        } else {
+            Visibility::Inherited
+        };
+        if *visibility != new_visibility {
+            *visibility = new_visibility;
+        }
+        // Despawning players are already fading out on their own
+        if animated && !despawning {
+            let alpha = ((draw_distance - distance) / DRAW_DISTANCE_FADE).clamp(0.0, 1.0);
+            if let Some(material) = materials.get(material_handle) {
+                if material.base_color.a() != alpha {
+                    if let Some(material) = materials.get_mut(material_handle) {
+                        material.base_color.set_a(alpha);
+                    }
+                }
+            }
+        }
+    }
+}
+
 #[derive(Component)]
 pub struct BlobShadow;
 
@@ -254,3 +588,150 @@ fn project_blob_shadows(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn animation(length: u8, looping: bool) -> Animation {
+        Animation {
+            texture: "textures/test.png".into(),
+            offset: (0.0, 0.0),
+            size: (32.0, 32.0),
+            length,
+            speed: 0.25,
+            rotates: false,
+            looping,
+        }
+    }
+
+    #[test]
+    fn one_shot_finishes_after_its_last_frame() {
+        let animation = animation(3, false);
+        let mut animator = Animator::new(Handle::default());
+        // Starts the clock, the first frame then shows until 0.25
+        animator.advance(&animation, 1.0, 0.001);
+        for (now, frame) in [(0.125, 0), (0.375, 1), (0.625, 2)] {
+            animator.advance(&animation, 1.0, now);
+            assert_eq!(animator.current_frame(), frame);
+            assert!(!animator.is_finished());
+        }
+        assert!((animator.sequence_progress() - 2.0 / 3.0).abs() < 1e-6);
+        // The last frame still gets its full time before the animation counts as done
+        animator.advance(&animation, 1.0, 0.875);
+        assert_eq!(animator.current_frame(), 2);
+        assert!(animator.is_finished());
+        assert_eq!(animator.sequence_progress(), 1.0);
+    }
+
+    #[test]
+    fn frames_blend_towards_the_next_one() {
+        let animation = animation(2, true);
+        let mut animator = Animator::new(Handle::default());
+        animator.advance(&animation, 1.0, 0.125);
+        let (next_frame, progress) = animator.blend(&animation, 1.0, 0.25, true).unwrap();
+        assert_eq!(next_frame, 1);
+        assert!((progress - 0.5).abs() < 1e-6);
+        // A one-shot on its last frame has nothing to blend to
+        let one_shot = self::animation(1, false);
+        assert!(animator.blend(&one_shot, 1.0, 0.25, true).is_none());
+    }
+
+    #[test]
+    fn no_overlay_without_frame_blending() {
+        let animation = animation(4, true);
+        let mut animator = Animator::new(Handle::default());
+        let mut frames = Vec::new();
+        for step in 0..8 {
+            let now = step as f64 * 0.125;
+            animator.advance(&animation, 1.0, now);
+            frames.push(animator.frame);
+            assert!(animator.blend(&animation, 1.0, now, false).is_none());
+        }
+        // The base frame still steps along as it did before blending existed
+        assert_eq!(frames, [0, 0, 0, 0, 1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn sprites_past_the_draw_distance_are_hidden() {
+        let mut world = World::new();
+        let mut settings = Settings::default();
+        settings.graphics.draw_distance = Some(5.0);
+        world.insert_resource(settings);
+        world.init_resource::<Assets<StandardMaterial>>();
+        world.spawn((MainCamera, GlobalTransform::IDENTITY));
+        // Distance is measured across the view, the camera looks down -Z
+        let mut sprite = |x: f32, visibility: Visibility| {
+            let parent = world.spawn(visibility).id();
+            world
+                .spawn((
+                    Billboard,
+                    GlobalTransform::from_xyz(x, 0.0, -16.0),
+                    Handle::<StandardMaterial>::default(),
+                ))
+                .set_parent(parent);
+            parent
+        };
+        let near = sprite(3.0, Visibility::Hidden);
+        let far = sprite(8.0, Visibility::Inherited);
+
+        Schedule::default()
+            .add_systems(cull_distant_sprites)
+            .run(&mut world);
+
+        assert_eq!(world.get::<Visibility>(near), Some(&Visibility::Inherited));
+        assert_eq!(world.get::<Visibility>(far), Some(&Visibility::Hidden));
+    }
+
+    #[test]
+    fn doubling_the_speed_scale_halves_the_frame_time() {
+        let animation = animation(4, true);
+        let frame_time = |speed_scale: f32| {
+            let mut animator = Animator::new(Handle::default());
+            animator.advance(&animation, speed_scale, 1.0);
+            animator.next_frame - 1.0
+        };
+        assert_eq!(frame_time(1.0), 0.25);
+        assert_eq!(frame_time(2.0), 0.125);
+    }
+
+    #[test]
+    fn plugin_runs_without_a_camera() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, AssetPlugin::default(), Sprite3dPlugin))
+            .init_asset::<Mesh>()
+            .init_asset::<Image>()
+            .init_asset::<StandardMaterial>();
+        app.world.spawn((
+            Billboard,
+            GlobalTransform::IDENTITY,
+            Handle::<StandardMaterial>::default(),
+        ));
+        app.update();
+        app.update();
+    }
+
+    #[test]
+    fn billboards_stay_put_without_a_camera() {
+        let mut world = World::new();
+        let transform = GlobalTransform::from_xyz(1.0, 2.0, 3.0);
+        let billboard = world.spawn((Billboard, transform)).id();
+        let mut schedule = Schedule::default();
+        schedule.add_systems(align_billboards);
+        schedule.run(&mut world);
+        schedule.run(&mut world);
+        assert_eq!(world.get::<GlobalTransform>(billboard), Some(&transform));
+    }
+
+    #[test]
+    fn looping_animations_wrap_and_never_finish() {
+        let animation = animation(2, true);
+        let mut animator = Animator::new(Handle::default());
+        animator.advance(&animation, 1.0, 0.001);
+        for (now, frame) in [(0.375, 1), (0.625, 0), (0.875, 1)] {
+            animator.advance(&animation, 1.0, now);
+            assert_eq!(animator.current_frame(), frame);
+        }
+        assert!(!animator.is_finished());
+    }
+}
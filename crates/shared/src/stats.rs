@@ -0,0 +1,172 @@
+use std::path::{Path, PathBuf};
+
+use bevy::{prelude::*, utils::HashMap};
+use bevy_renet::renet::ClientId;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct PlayerStats {
+    pub kills: u32,
+    pub deaths: u32,
+    // Seconds spent connected, over all sessions
+    pub playtime: f64,
+}
+
+// Stats that outlive a connection, kept on the server and written to disk whenever a
+// player leaves. Players are told apart by name until there are proper accounts, so
+// stats are counted per connection and only added to the name's when it ends. Two
+// players using the same name can't change each other's stats mid-game that way.
+#[derive(Resource)]
+pub struct StatsStore {
+    path: PathBuf,
+    players: HashMap<String, PlayerStats>,
+    sessions: HashMap<ClientId, Session>,
+}
+
+// Everyone currently playing, with what they did since connecting
+struct Session {
+    name: String,
+    connected_at: f64,
+    stats: PlayerStats,
+}
+
+impl StatsStore {
+    pub fn load(path: &Path) -> Self {
+        let players = match std::fs::read_to_string(path) {
+            Ok(stats) => ron::from_str(&stats).unwrap_or_else(|err| {
+                warn!(
+                    "Failed to parse stats, backing up and starting over: {}",
+                    err
+                );
+                std::fs::copy(path, path.with_extension("old.ron")).unwrap_or_else(|err| {
+                    error!("Unable to backup old stats: {}", err);
+                    0
+                });
+                HashMap::default()
+            }),
+            Err(_) => HashMap::default(),
+        };
+        Self {
+            path: path.into(),
+            players,
+            sessions: HashMap::default(),
+        }
+    }
+
+    // Written next to the old file and then moved over it, so a crash halfway through
+    // can't leave a truncated file behind
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let pretty = ron::ser::PrettyConfig::new().depth_limit(2);
+        let stats_str = ron::ser::to_string_pretty(&self.players, pretty)?;
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let temp_path = self.path.with_extension("tmp");
+        std::fs::write(&temp_path, stats_str)?;
+        std::fs::rename(temp_path, &self.path)?;
+        Ok(())
+    }
+
+    // Returns the stats recorded for the name so far
+    pub fn connect(&mut self, client_id: ClientId, name: &str, now: f64) -> &PlayerStats {
+        self.sessions.insert(
+            client_id,
+            Session {
+                name: name.into(),
+                connected_at: now,
+                stats: PlayerStats::default(),
+            },
+        );
+        self.players.entry(name.into()).or_default()
+    }
+
+    // Adds the session's playtime and saves, if the player was being tracked
+    pub fn disconnect(&mut self, client_id: ClientId, now: f64) {
+        if self.end_session(client_id, now) {
+            self.save_or_log();
+        }
+    }
+
+    pub fn disconnect_all(&mut self, now: f64) {
+        let client_ids: Vec<_> = self.sessions.keys().copied().collect();
+        for client_id in client_ids {
+            self.end_session(client_id, now);
+        }
+        self.save_or_log();
+    }
+
+    // Stats of the current session only
+    pub fn get_mut(&mut self, client_id: ClientId) -> Option<&mut PlayerStats> {
+        self.sessions
+            .get_mut(&client_id)
+            .map(|session| &mut session.stats)
+    }
+
+    fn end_session(&mut self, client_id: ClientId, now: f64) -> bool {
+        let Some(session) = self.sessions.remove(&client_id) else {
+            return false;
+        };
+        let stats = self.players.entry(session.name).or_default();
+        stats.kills += session.stats.kills;
+        stats.deaths += session.stats.deaths;
+        stats.playtime += (now - session.connected_at).max(0.0);
+        true
+    }
+
+    fn save_or_log(&self) {
+        self.save().unwrap_or_else(|err| {
+            error!(
+                "Failed to write stats to '{}': {}",
+                self.path.display(),
+                err
+            )
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(name: &str) -> StatsStore {
+        let path = std::env::temp_dir()
+            .join(format!("isotokyo-stats-{}-{}", name, std::process::id()))
+            .with_extension("ron");
+        let _ = std::fs::remove_file(&path);
+        StatsStore::load(&path)
+    }
+
+    #[test]
+    fn stats_are_restored_on_reconnect() {
+        let mut stats = store("reconnect");
+        let client_id = ClientId::from_raw(1);
+        stats.connect(client_id, "Kagami", 0.0);
+        stats.get_mut(client_id).unwrap().kills += 2;
+        stats.disconnect(client_id, 60.0);
+
+        let mut stats = StatsStore::load(&stats.path);
+        let player_stats = stats.connect(ClientId::from_raw(2), "Kagami", 100.0);
+        assert_eq!(player_stats.kills, 2);
+        assert_eq!(player_stats.playtime, 60.0);
+        let _ = std::fs::remove_file(&stats.path);
+    }
+
+    #[test]
+    fn same_name_sessions_are_kept_apart() {
+        let mut stats = store("same-name");
+        let first = ClientId::from_raw(1);
+        let second = ClientId::from_raw(2);
+        stats.connect(first, "Kagami", 0.0);
+        stats.connect(second, "Kagami", 0.0);
+        stats.get_mut(first).unwrap().kills += 1;
+        stats.get_mut(second).unwrap().deaths += 1;
+        assert_eq!(stats.get_mut(second).unwrap().kills, 0);
+
+        stats.disconnect_all(10.0);
+        let player_stats = &stats.players["Kagami"];
+        assert_eq!(player_stats.kills, 1);
+        assert_eq!(player_stats.deaths, 1);
+        assert_eq!(player_stats.playtime, 20.0);
+        let _ = std::fs::remove_file(&stats.path);
+    }
+}
@@ -0,0 +1,319 @@
+use bevy::prelude::*;
+use bevy_xpbd_3d::components::{Collider, CollisionLayers, LinearVelocity, RigidBody, Sensor};
+use bevy_xpbd_3d::plugins::spatial_query::{SpatialQuery, SpatialQueryFilter};
+
+use crate::config::{JumpPadConfig, SafeZoneConfig, TeleporterConfig, WaterConfig};
+use crate::networking::{Player, Team};
+use crate::physics::Layer;
+use crate::player::{InWater, IsGrounded};
+use crate::MapEntity;
+
+// Time before a player that just went through a teleporter can use one again, so
+// landing next to the return pad doesn't send them straight back
+const TELEPORT_COOLDOWN: f32 = 0.5;
+
+#[derive(Component)]
+pub struct Teleporter {
+    pub target: Vec3,
+    pub keep_velocity: bool,
+}
+
+impl Teleporter {
+    fn teleport(&self, transform: &mut Transform, velocity: &mut LinearVelocity) {
+        transform.translation = self.target;
+        if !self.keep_velocity {
+            **velocity = Vec3::ZERO;
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct TeleportCooldown(pub Timer);
+
+#[derive(Component)]
+pub struct JumpPad {
+    pub impulse: Vec3,
+}
+
+impl JumpPad {
+    fn launch(&self, velocity: &mut LinearVelocity, is_grounded: &mut IsGrounded) {
+        **velocity = self.impulse;
+        is_grounded.0 = false;
+    }
+}
+
+#[derive(Component)]
+pub struct Water;
+
+#[derive(Component)]
+pub struct SafeZone {
+    pub team: Team,
+    pub protect_team: bool,
+}
+
+impl SafeZone {
+    // Enemies in a base are kept out of combat, its own team only when configured
+    pub fn protects(&self, team: Team) -> bool {
+        self.team != team || self.protect_team
+    }
+}
+
+// Triggers all come with the map and go with it
+fn trigger_bundle(size: Vec3) -> impl Bundle {
+    (
+        MapEntity,
+        RigidBody::Static,
+        Sensor,
+        Collider::cuboid(size.x, size.y, size.z),
+        CollisionLayers::new([Layer::Trigger], [Layer::Player]),
+    )
+}
+
+pub fn spawn_teleporters(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    teleporters: &[TeleporterConfig],
+) {
+    let material_handle = materials.add(StandardMaterial {
+        base_color: Color::rgba(0.3, 0.8, 1.0, 0.4),
+        alpha_mode: AlphaMode::Blend,
+        unlit: true,
+        ..default()
+    });
+
+    for teleporter in teleporters {
+        let size = Vec3::from(teleporter.size);
+        commands
+            .spawn(PbrBundle {
+                mesh: meshes.add(Mesh::from(shape::Box::new(size.x, size.y, size.z))),
+                material: material_handle.clone(),
+                transform: Transform::from_translation(teleporter.position.into()),
+                ..default()
+            })
+            .insert(trigger_bundle(size))
+            .insert(Teleporter {
+                target: teleporter.target.into(),
+                keep_velocity: teleporter.keep_velocity,
+            });
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub fn teleport_players(
+    mut commands: Commands,
+    spatial_query: SpatialQuery,
+    teleporters: Query<(&Teleporter, &Collider, &Transform), Without<Player>>,
+    mut players: Query<
+        (&mut Transform, &mut LinearVelocity),
+        (With<Player>, Without<TeleportCooldown>),
+    >,
+) {
+    for (teleporter, collider, transform) in teleporters.iter() {
+        for entity in spatial_query.shape_intersections(
+            collider,
+            transform.translation,
+            transform.rotation,
+            SpatialQueryFilter::new().with_masks([Layer::Player]),
+        ) {
+            let Ok((mut player_transform, mut velocity)) = players.get_mut(entity) else {
+                continue;
+            };
+            teleporter.teleport(&mut player_transform, &mut velocity);
+            commands
+                .entity(entity)
+                .insert(TeleportCooldown(Timer::from_seconds(
+                    TELEPORT_COOLDOWN,
+                    TimerMode::Once,
+                )));
+        }
+    }
+}
+
+pub fn spawn_jump_pads(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    jump_pads: &[JumpPadConfig],
+) {
+    let material_handle = materials.add(StandardMaterial {
+        base_color: Color::rgba(1.0, 0.6, 0.2, 0.4),
+        alpha_mode: AlphaMode::Blend,
+        unlit: true,
+        ..default()
+    });
+
+    for jump_pad in jump_pads {
+        let size = Vec3::from(jump_pad.size);
+        commands
+            .spawn(PbrBundle {
+                mesh: meshes.add(Mesh::from(shape::Box::new(size.x, size.y, size.z))),
+                material: material_handle.clone(),
+                transform: Transform::from_translation(jump_pad.position.into()),
+                ..default()
+            })
+            .insert(trigger_bundle(size))
+            .insert(JumpPad {
+                impulse: jump_pad.impulse.into(),
+            });
+    }
+}
+
+// Runs on the server and on the client, so the launch shows up right away instead
+// of waiting for the server to correct the player
+pub fn launch_players(
+    spatial_query: SpatialQuery,
+    jump_pads: Query<(&JumpPad, &Collider, &Transform)>,
+    mut players: Query<(&mut LinearVelocity, &mut IsGrounded), With<Player>>,
+) {
+    for (jump_pad, collider, transform) in jump_pads.iter() {
+        for entity in spatial_query.shape_intersections(
+            collider,
+            transform.translation,
+            transform.rotation,
+            SpatialQueryFilter::new().with_masks([Layer::Player]),
+        ) {
+            if let Ok((mut velocity, mut is_grounded)) = players.get_mut(entity) {
+                jump_pad.launch(&mut velocity, &mut is_grounded);
+            }
+        }
+    }
+}
+
+pub fn spawn_water(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    water: &[WaterConfig],
+) {
+    let material_handle = materials.add(StandardMaterial {
+        base_color: Color::rgba(0.1, 0.35, 0.8, 0.5),
+        alpha_mode: AlphaMode::Blend,
+        ..default()
+    });
+
+    for volume in water {
+        let size = Vec3::from(volume.size);
+        commands
+            .spawn(PbrBundle {
+                mesh: meshes.add(Mesh::from(shape::Box::new(size.x, size.y, size.z))),
+                material: material_handle.clone(),
+                transform: Transform::from_translation(volume.position.into()),
+                ..default()
+            })
+            .insert(trigger_bundle(size))
+            .insert(Water);
+    }
+}
+
+// Players count as in water once their center is under the surface, so they float
+// with their head above it
+pub fn check_water(
+    spatial_query: SpatialQuery,
+    water: Query<(), With<Water>>,
+    mut players: Query<(&Transform, &mut InWater), With<Player>>,
+) {
+    for (transform, mut in_water) in players.iter_mut() {
+        let submerged = spatial_query
+            .point_intersections(
+                transform.translation,
+                SpatialQueryFilter::new().with_masks([Layer::Trigger]),
+            )
+            .into_iter()
+            .any(|entity| water.contains(entity));
+        if in_water.0 != submerged {
+            in_water.0 = submerged;
+        }
+    }
+}
+
+// Invisible, players are expected to know where the bases are from the map itself
+pub fn spawn_safe_zones(commands: &mut Commands, safe_zones: &[SafeZoneConfig]) {
+    for zone in safe_zones {
+        commands
+            .spawn(TransformBundle::from(Transform::from_translation(
+                zone.position.into(),
+            )))
+            .insert(trigger_bundle(zone.size.into()))
+            .insert(SafeZone {
+                team: zone.team,
+                protect_team: zone.protect_team,
+            });
+    }
+}
+
+// Whether a player of the team at the position is kept out of combat by a base
+pub fn in_safe_zone(
+    spatial_query: &SpatialQuery,
+    zones: &Query<&SafeZone>,
+    position: Vec3,
+    team: Team,
+) -> bool {
+    spatial_query
+        .point_intersections(
+            position,
+            SpatialQueryFilter::new().with_masks([Layer::Trigger]),
+        )
+        .into_iter()
+        .filter_map(|entity| zones.get(entity).ok())
+        .any(|zone| zone.protects(team))
+}
+
+pub fn update_teleport_cooldowns(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut query: Query<(Entity, &mut TeleportCooldown)>,
+) {
+    for (entity, mut cooldown) in query.iter_mut() {
+        if cooldown.0.tick(time.delta()).finished() {
+            commands.entity(entity).remove::<TeleportCooldown>();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn teleporters_move_players_to_their_target() {
+        let target = Vec3::new(8.0, 1.0, -4.0);
+        let entered = || {
+            (
+                Transform::from_xyz(1.0, 1.0, 1.0),
+                LinearVelocity(Vec3::new(3.0, 0.0, 2.0)),
+            )
+        };
+
+        let (mut transform, mut velocity) = entered();
+        let teleporter = Teleporter {
+            target,
+            keep_velocity: false,
+        };
+        teleporter.teleport(&mut transform, &mut velocity);
+        assert_eq!(transform.translation, target);
+        assert_eq!(velocity.0, Vec3::ZERO);
+
+        let (mut transform, mut velocity) = entered();
+        let teleporter = Teleporter {
+            target,
+            keep_velocity: true,
+        };
+        teleporter.teleport(&mut transform, &mut velocity);
+        assert_eq!(transform.translation, target);
+        assert_eq!(velocity.0, Vec3::new(3.0, 0.0, 2.0));
+    }
+
+    #[test]
+    fn jump_pads_launch_at_their_impulse() {
+        let jump_pad = JumpPad {
+            impulse: Vec3::new(2.0, 12.0, 0.0),
+        };
+        // Whatever the player was doing before is replaced, falling included
+        let mut velocity = LinearVelocity(Vec3::new(-5.0, -20.0, 1.0));
+        let mut is_grounded = IsGrounded(true);
+        jump_pad.launch(&mut velocity, &mut is_grounded);
+        assert_eq!(velocity.0, jump_pad.impulse);
+        assert!(!is_grounded.0);
+    }
+}
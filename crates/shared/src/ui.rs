@@ -1,8 +1,55 @@
-use bevy::diagnostic::{DiagnosticsStore, FrameTimeDiagnosticsPlugin};
+use std::io::Write;
+use std::path::Path;
+
+use bevy::asset::LoadState;
+use bevy::diagnostic::{
+    DiagnosticsStore, EntityCountDiagnosticsPlugin, FrameTimeDiagnosticsPlugin,
+};
 use bevy::prelude::*;
 use bevy_xpbd_3d::components::LinearVelocity;
 
-use crate::player::LocalPlayer;
+use crate::config::Config;
+use crate::input::InputAction;
+use crate::networking::Player;
+use crate::player::{Crosshair, InWater, LocalPlayer, Spectate, PLAYER_HALF_HEIGHT};
+use crate::pool::EffectPool;
+use crate::settings::{HudSettings, Settings};
+use crate::sprites::{Animator, Billboard};
+use crate::MainCamera;
+
+#[derive(Component)]
+enum HudElement {
+    Fps,
+    Speed,
+    MaxSpeed,
+    Velocity,
+}
+
+impl HudElement {
+    fn shown(&self, hud: &HudSettings) -> bool {
+        match self {
+            HudElement::Fps => hud.fps,
+            HudElement::Speed => hud.speed,
+            HudElement::MaxSpeed => hud.max_speed,
+            HudElement::Velocity => hud.velocity,
+        }
+    }
+
+    fn style(&self, hud: &HudSettings) -> Style {
+        let (left, top) = match self {
+            HudElement::Fps => hud.fps_position,
+            HudElement::Speed => hud.speed_position,
+            HudElement::MaxSpeed => hud.max_speed_position,
+            HudElement::Velocity => hud.velocity_position,
+        };
+        Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(top),
+            left: Val::Px(left),
+            ..default()
+        }
+    }
+}
 
 #[derive(Component)]
 struct FpsCounter;
@@ -13,20 +60,132 @@ struct Speedometer;
 #[derive(Component, Default)]
 struct MaxSpeed(f32);
 
+#[derive(Component)]
+struct VelocityReadout;
+
+#[derive(Component)]
+struct SpectateLabel;
+
+// Tints the screen while the local player is under water
+#[derive(Component)]
+struct WaterOverlay;
+
+// Developer tools like the stats overlay are only available with this inserted
+#[derive(Resource)]
+pub struct DevMode;
+
+#[derive(Component)]
+struct StatsOverlay;
+
+const RECORDINGS_DIR: &str = "recordings";
+const FONT_PATH: &str = "fonts/X-SCALE_.TTF";
+
+// Horizontal speed samples as (seconds since the start, speed), while recording
+#[derive(Resource, Default)]
+struct SpeedRecording {
+    start: f64,
+    samples: Option<Vec<(f32, f32)>>,
+}
+
+// Sent on the client when the server confirms one of our shots landed
+#[derive(Event)]
+pub struct HitConfirmed {
+    pub target: Entity,
+    pub damage: f32,
+}
+
+// Text that tracks a point in the world, rising and fading until it expires
+#[derive(Component)]
+struct FloatingText {
+    anchor: Vec3,
+    rise: f32,
+    timer: Timer,
+}
+
 pub struct UiPlugin;
 
 impl Plugin for UiPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins(FrameTimeDiagnosticsPlugin)
-            .add_systems(Startup, setup_ui)
-            .add_systems(Update, (update_fps, update_speed, max_speed));
+        app.add_plugins((FrameTimeDiagnosticsPlugin, EntityCountDiagnosticsPlugin))
+            .add_event::<HitConfirmed>()
+            .init_resource::<SpeedRecording>()
+            .init_resource::<EffectPool<FloatingText>>()
+            .add_systems(
+                Startup,
+                (
+                    setup_ui,
+                    setup_stats_overlay.run_if(resource_exists::<DevMode>()),
+                ),
+            )
+            .add_systems(
+                Update,
+                (
+                    update_fps,
+                    update_speed,
+                    max_speed,
+                    update_velocity_readout,
+                    record_speed,
+                    spawn_hit_feedback,
+                    update_floating_text,
+                    update_water_overlay,
+                    update_spectate_label,
+                    (toggle_hud, update_hud_visibility).chain(),
+                    update_stats_overlay.run_if(resource_exists::<DevMode>()),
+                    fall_back_font,
+                ),
+            );
+    }
+}
+
+// The HUD font, or Bevy's built in one when it couldn't be loaded so text still shows
+fn ui_font(asset_server: &AssetServer) -> Handle<Font> {
+    let font = asset_server.load(FONT_PATH);
+    if asset_server.get_load_state(&font) == Some(LoadState::Failed) {
+        Handle::default()
+    } else {
+        font
+    }
+}
+
+// Text spawned before the font failed to load still points at it, so it's switched over
+// once that's known
+fn fall_back_font(
+    asset_server: Res<AssetServer>,
+    mut done: Local<bool>,
+    mut query: Query<&mut Text>,
+) {
+    if *done {
+        return;
+    }
+    let font: Handle<Font> = asset_server.load(FONT_PATH);
+    match asset_server.get_load_state(&font) {
+        Some(LoadState::Failed) => {
+            warn!(
+                "Unable to load the font {}, falling back to the default font.",
+                FONT_PATH
+            );
+            *done = true;
+        }
+        Some(LoadState::Loaded) => {
+            *done = true;
+            return;
+        }
+        _ => return,
+    }
+    for mut text in query.iter_mut() {
+        for section in text.sections.iter_mut() {
+            if section.style.font == font {
+                section.style.font = Handle::default();
+            }
+        }
     }
 }
 
-fn setup_ui(mut commands: Commands, asset_server: Res<AssetServer>) {
+fn setup_ui(mut commands: Commands, asset_server: Res<AssetServer>, settings: Res<Settings>) {
+    let hud = &settings.hud;
     let style = TextStyle {
-        font: asset_server.load("fonts/X-SCALE_.TTF"),
-        font_size: 24.0,
+        font: ui_font(&asset_server),
+        font_size: hud.font_size,
         ..default()
     };
 
@@ -36,42 +195,243 @@ fn setup_ui(mut commands: Commands, asset_server: Res<AssetServer>) {
                 TextSection::new("FPS: ", style.clone()),
                 TextSection::new("", style.clone()),
             ])
-            .with_style(Style {
-                position_type: PositionType::Absolute,
-                top: Val::Px(0.0),
-                left: Val::Px(12.0),
-                ..default()
-            }),
+            .with_style(HudElement::Fps.style(hud)),
         )
-        .insert(FpsCounter);
+        .insert(FpsCounter)
+        .insert(HudElement::Fps);
     commands
         .spawn(
             TextBundle::from_sections([
                 TextSection::new("Speed: ", style.clone()),
                 TextSection::new("", style.clone()),
             ])
-            .with_style(Style {
-                position_type: PositionType::Absolute,
-                top: Val::Px(20.0),
-                left: Val::Px(12.0),
-                ..default()
-            }),
+            .with_style(HudElement::Speed.style(hud)),
         )
-        .insert(Speedometer);
+        .insert(Speedometer)
+        .insert(HudElement::Speed);
     commands
         .spawn(
             TextBundle::from_sections([
                 TextSection::new("Max: ", style.clone()),
-                TextSection::new("", style),
+                TextSection::new("", style.clone()),
             ])
-            .with_style(Style {
+            .with_style(HudElement::MaxSpeed.style(hud)),
+        )
+        .insert(MaxSpeed::default())
+        .insert(HudElement::MaxSpeed);
+    commands
+        .spawn(
+            TextBundle::from_sections([
+                TextSection::new("Horizontal: ", style.clone()),
+                TextSection::new("", style.clone()),
+                TextSection::new("  Vertical: ", style.clone()),
+                TextSection::new("", style.clone()),
+                TextSection::new("  Strafe: ", style.clone()),
+                TextSection::new("", style.clone()),
+            ])
+            .with_style(HudElement::Velocity.style(hud)),
+        )
+        .insert(VelocityReadout)
+        .insert(HudElement::Velocity);
+    commands
+        .spawn(
+            TextBundle::from_section("", style)
+                .with_style(Style {
+                    position_type: PositionType::Absolute,
+                    bottom: Val::Px(12.0),
+                    width: Val::Percent(100.0),
+                    justify_content: JustifyContent::Center,
+                    ..default()
+                })
+                .with_text_alignment(TextAlignment::Center),
+        )
+        .insert(SpectateLabel);
+    commands
+        .spawn(NodeBundle {
+            style: Style {
                 position_type: PositionType::Absolute,
-                top: Val::Px(40.0),
-                left: Val::Px(12.0),
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
                 ..default()
-            }),
+            },
+            background_color: Color::rgba(0.1, 0.3, 0.6, 0.25).into(),
+            visibility: Visibility::Hidden,
+            z_index: ZIndex::Global(-1),
+            ..default()
+        })
+        .insert(WaterOverlay);
+}
+
+fn setup_stats_overlay(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let style = TextStyle {
+        font: ui_font(&asset_server),
+        font_size: 18.0,
+        ..default()
+    };
+    commands
+        .spawn(
+            TextBundle::from_section("", style)
+                .with_style(Style {
+                    position_type: PositionType::Absolute,
+                    top: Val::Px(0.0),
+                    right: Val::Px(12.0),
+                    ..default()
+                })
+                .with_text_alignment(TextAlignment::Right),
+        )
+        .insert(StatsOverlay)
+        .insert(Visibility::Hidden);
+}
+
+// Counts to check the cost of big maps, Bevy doesn't report draw calls so visible
+// meshes stand in for them
+fn update_stats_overlay(
+    input: Res<Input<InputAction>>,
+    diagnostics: Res<DiagnosticsStore>,
+    billboards: Query<(), With<Billboard>>,
+    animators: Query<(), With<Animator>>,
+    meshes: Query<&ViewVisibility, With<Handle<Mesh>>>,
+    floating_text_pool: Res<EffectPool<FloatingText>>,
+    mut query: Query<(&mut Text, &mut Visibility), With<StatsOverlay>>,
+) {
+    for (mut text, mut visibility) in query.iter_mut() {
+        if input.just_pressed(InputAction::ToggleStats) {
+            *visibility = if *visibility == Visibility::Hidden {
+                Visibility::Inherited
+            } else {
+                Visibility::Hidden
+            };
+        }
+        if *visibility == Visibility::Hidden {
+            continue;
+        }
+        let diagnostic = |id| {
+            diagnostics
+                .get(id)
+                .and_then(|diagnostic| diagnostic.smoothed())
+                .unwrap_or_default()
+        };
+        text.sections[0].value = format!(
+            "Frame: {:.2} ms\nEntities: {:.0}\nSprites: {} ({} animated)\nMeshes: {}/{} visible\nFloating text: {} spawned, {} reused",
+            diagnostic(FrameTimeDiagnosticsPlugin::FRAME_TIME),
+            diagnostic(EntityCountDiagnosticsPlugin::ENTITY_COUNT),
+            billboards.iter().count(),
+            animators.iter().count(),
+            meshes.iter().filter(|visibility| visibility.get()).count(),
+            meshes.iter().count(),
+            floating_text_pool.spawned,
+            floating_text_pool.reused,
+        );
+    }
+}
+
+// Message in the middle of the screen that stays up, for when the game can't go on
+pub fn spawn_notice(commands: &mut Commands, asset_server: &AssetServer, message: &str) {
+    commands.spawn(
+        TextBundle::from_section(
+            message,
+            TextStyle {
+                font: ui_font(asset_server),
+                font_size: 32.0,
+                ..default()
+            },
         )
-        .insert(MaxSpeed::default());
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Percent(40.0),
+            width: Val::Percent(100.0),
+            justify_content: JustifyContent::Center,
+            ..default()
+        })
+        .with_text_alignment(TextAlignment::Center),
+    );
+}
+
+fn toggle_hud(input: Res<Input<InputAction>>, mut settings: ResMut<Settings>) {
+    let hud = &mut settings.hud;
+    if input.just_pressed(InputAction::ToggleHud) {
+        hud.visible = !hud.visible;
+    }
+    if input.just_pressed(InputAction::ToggleFps) {
+        hud.fps = !hud.fps;
+    }
+    if input.just_pressed(InputAction::ToggleSpeed) {
+        hud.speed = !hud.speed;
+    }
+    if input.just_pressed(InputAction::ToggleMaxSpeed) {
+        hud.max_speed = !hud.max_speed;
+    }
+    if input.just_pressed(InputAction::ToggleVelocity) {
+        hud.velocity = !hud.velocity;
+    }
+}
+
+// Also moves and resizes the elements, so the layout can be changed while playing
+fn update_hud_visibility(
+    settings: Res<Settings>,
+    mut query: Query<(&HudElement, &mut Visibility, &mut Style, &mut Text)>,
+) {
+    if !settings.is_changed() {
+        return;
+    }
+    let hud = &settings.hud;
+    for (element, mut visibility, mut style, mut text) in query.iter_mut() {
+        // Only touched when they differ, since changing them lays the UI out again
+        let layout = element.style(hud);
+        if *style != layout {
+            *style = layout;
+        }
+        if text
+            .sections
+            .iter()
+            .any(|section| section.style.font_size != hud.font_size)
+        {
+            for section in text.sections.iter_mut() {
+                section.style.font_size = hud.font_size;
+            }
+        }
+        *visibility = if hud.visible && element.shown(hud) {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        };
+    }
+}
+
+fn update_spectate_label(
+    spectate: Res<Spectate>,
+    players: Query<&Player>,
+    mut query: Query<&mut Text, With<SpectateLabel>>,
+) {
+    if !spectate.is_changed() {
+        return;
+    }
+    let label = spectate
+        .0
+        .and_then(|target| players.get(target).ok())
+        .map_or_else(String::new, |player| {
+            format!("Spectating player {}", player.id)
+        });
+    for mut text in query.iter_mut() {
+        text.sections[0].value = label.clone();
+    }
+}
+
+fn update_water_overlay(
+    player_query: Query<&InWater, With<LocalPlayer>>,
+    mut query: Query<&mut Visibility, With<WaterOverlay>>,
+) {
+    let in_water = player_query.get_single().is_ok_and(|in_water| in_water.0);
+    for mut visibility in query.iter_mut() {
+        let new_visibility = if in_water {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        };
+        if *visibility != new_visibility {
+            *visibility = new_visibility;
+        }
+    }
 }
 
 fn update_fps(diagnostics: Res<DiagnosticsStore>, mut query: Query<&mut Text, With<FpsCounter>>) {
@@ -98,10 +458,18 @@ fn update_speed(
 }
 
 fn max_speed(
+    input: Res<Input<InputAction>>,
     player_query: Query<&LinearVelocity, With<LocalPlayer>>,
+    spawned_query: Query<(), Added<LocalPlayer>>,
     mut query: Query<(&mut Text, &mut MaxSpeed), With<MaxSpeed>>,
 ) {
+    // Start over on request or whenever the player (re)spawns
+    let reset = input.just_pressed(InputAction::ResetMaxSpeed) || !spawned_query.is_empty();
     for (mut text, mut max_speed) in query.iter_mut() {
+        if reset {
+            max_speed.0 = 0.0;
+            text.sections[1].value.clear();
+        }
         if let Ok(velocity) = player_query.get_single() {
             if velocity.xz().length() > max_speed.0 {
                 max_speed.0 = velocity.xz().length();
@@ -111,3 +479,276 @@ fn max_speed(
         }
     }
 }
+
+// Signed angle in degrees from where the player is looking to where they're moving
+fn strafe_angle(velocity: Vec2, forward: Vec2) -> f32 {
+    if velocity == Vec2::ZERO || forward == Vec2::ZERO {
+        return 0.0;
+    }
+    forward.angle_between(velocity).to_degrees()
+}
+
+fn update_velocity_readout(
+    player_query: Query<(&LinearVelocity, &Transform), With<LocalPlayer>>,
+    mut query: Query<(&mut Text, &Visibility), With<VelocityReadout>>,
+) {
+    let Ok((velocity, transform)) = player_query.get_single() else {
+        return;
+    };
+    for (mut text, visibility) in query.iter_mut() {
+        if visibility == Visibility::Hidden {
+            continue;
+        }
+        let forward = transform.forward().xz();
+        text.sections[1].value = format!("{:.2}", velocity.xz().length());
+        text.sections[3].value = format!("{:.2}", velocity.y);
+        text.sections[5].value = format!("{:.1}", strafe_angle(velocity.xz(), forward));
+    }
+}
+
+fn record_speed(
+    input: Res<Input<InputAction>>,
+    time: Res<Time>,
+    mut recording: ResMut<SpeedRecording>,
+    player_query: Query<&LinearVelocity, With<LocalPlayer>>,
+) {
+    if input.just_pressed(InputAction::ToggleSpeedRecording) {
+        if let Some(samples) = recording.samples.take() {
+            let path = Path::new(RECORDINGS_DIR).join(format!(
+                "speed-{}.csv",
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map_or(0, |duration| duration.as_secs())
+            ));
+            match write_speed_csv(&path, &samples) {
+                Ok(()) => info!("Wrote speed recording to '{}'.", path.display()),
+                Err(err) => error!(
+                    "Failed to write speed recording to '{}': {}",
+                    path.display(),
+                    err
+                ),
+            }
+        } else {
+            info!("Recording speed.");
+            recording.start = time.elapsed_seconds_f64();
+            recording.samples = Some(Vec::new());
+        }
+    }
+
+    let start = recording.start;
+    if let (Some(samples), Ok(velocity)) = (&mut recording.samples, player_query.get_single()) {
+        let elapsed = (time.elapsed_seconds_f64() - start) as f32;
+        samples.push((elapsed, velocity.xz().length()));
+    }
+}
+
+fn write_speed_csv(path: &Path, samples: &[(f32, f32)]) -> std::io::Result<()> {
+    let max = samples
+        .iter()
+        .fold(0.0_f32, |max, (_, speed)| max.max(*speed));
+    let average = if samples.is_empty() {
+        0.0
+    } else {
+        samples.iter().map(|(_, speed)| speed).sum::<f32>() / samples.len() as f32
+    };
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    writeln!(file, "# max_speed: {:.3}", max)?;
+    writeln!(file, "# average_speed: {:.3}", average)?;
+    writeln!(file, "time,speed")?;
+    for (time, speed) in samples {
+        writeln!(file, "{:.3},{:.3}", time, speed)?;
+    }
+    file.flush()
+}
+
+fn spawn_hit_feedback(
+    mut commands: Commands,
+    mut pool: ResMut<EffectPool<FloatingText>>,
+    asset_server: Res<AssetServer>,
+    config: Res<Config>,
+    mut hit_events: EventReader<HitConfirmed>,
+    crosshair_query: Query<&Transform, With<Crosshair>>,
+    target_query: Query<&GlobalTransform>,
+) {
+    for hit in hit_events.read() {
+        if config.ui.hit_markers {
+            if let Ok(crosshair_transform) = crosshair_query.get_single() {
+                pool.acquire(
+                    &mut commands,
+                    floating_text(
+                        "X",
+                        Color::WHITE,
+                        crosshair_transform.translation,
+                        0.0,
+                        &asset_server,
+                    ),
+                );
+            }
+        }
+        if config.ui.damage_numbers {
+            if let Ok(target_transform) = target_query.get(hit.target) {
+                pool.acquire(
+                    &mut commands,
+                    floating_text(
+                        &format!("{:.0}", hit.damage),
+                        Color::ORANGE_RED,
+                        target_transform.translation() + Vec3::Y * PLAYER_HALF_HEIGHT,
+                        1.0,
+                        &asset_server,
+                    ),
+                );
+            }
+        }
+    }
+}
+
+fn floating_text(
+    value: &str,
+    color: Color,
+    anchor: Vec3,
+    rise: f32,
+    asset_server: &AssetServer,
+) -> (TextBundle, FloatingText) {
+    let style = TextStyle {
+        font: ui_font(asset_server),
+        font_size: 24.0,
+        color,
+    };
+    (
+        TextBundle::from_section(value, style).with_style(Style {
+            position_type: PositionType::Absolute,
+            ..default()
+        }),
+        FloatingText {
+            anchor,
+            rise,
+            timer: Timer::from_seconds(0.6, TimerMode::Once),
+        },
+    )
+}
+
+fn update_floating_text(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut pool: ResMut<EffectPool<FloatingText>>,
+    cam_query: Query<(&Camera, &GlobalTransform), With<MainCamera>>,
+    mut query: Query<(
+        Entity,
+        &mut FloatingText,
+        &mut Style,
+        &mut Text,
+        &Visibility,
+    )>,
+) {
+    let Ok((camera, camera_transform)) = cam_query.get_single() else {
+        return;
+    };
+    for (entity, mut floating, mut style, mut text, visibility) in query.iter_mut() {
+        // Back in the pool
+        if *visibility == Visibility::Hidden {
+            continue;
+        }
+        if floating.timer.tick(time.delta()).finished() {
+            pool.release(&mut commands, entity);
+            continue;
+        }
+        floating.anchor.y += floating.rise * time.delta_seconds();
+        if let Some(position) = camera.world_to_viewport(camera_transform, floating.anchor) {
+            // Roughly center the text on its anchor
+            style.left = Val::Px(position.x - 6.0);
+            style.top = Val::Px(position.y - 12.0);
+        }
+        let alpha = floating.timer.percent_left();
+        for section in text.sections.iter_mut() {
+            section.style.color.set_a(alpha);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn readout(world: &World, entity: Entity) -> (f32, String) {
+        let text = world.get::<Text>(entity).unwrap();
+        let max_speed = world.get::<MaxSpeed>(entity).unwrap();
+        (max_speed.0, text.sections[1].value.clone())
+    }
+
+    #[test]
+    fn resetting_max_speed_starts_from_zero() {
+        let mut world = World::new();
+        world.init_resource::<Input<InputAction>>();
+        let hud = world
+            .spawn((
+                Text::from_sections([TextSection::default(), TextSection::default()]),
+                MaxSpeed::default(),
+            ))
+            .id();
+        let player = world
+            .spawn((LocalPlayer, LinearVelocity(Vec3::new(3.0, 0.0, 4.0))))
+            .id();
+        let mut schedule = Schedule::default();
+        schedule.add_systems(max_speed);
+
+        schedule.run(&mut world);
+        assert_eq!(readout(&world, hud), (5.0, "5.00".to_string()));
+
+        // Slowing down keeps the best so far, until it's reset
+        world.get_mut::<LinearVelocity>(player).unwrap().0 = Vec3::ZERO;
+        schedule.run(&mut world);
+        assert_eq!(readout(&world, hud).0, 5.0);
+        world
+            .resource_mut::<Input<InputAction>>()
+            .press(InputAction::ResetMaxSpeed);
+        schedule.run(&mut world);
+        assert_eq!(readout(&world, hud), (0.0, String::new()));
+    }
+
+    #[test]
+    fn hud_text_sits_at_the_configured_positions() {
+        let settings = Settings {
+            hud: HudSettings {
+                fps_position: (4.0, 8.0),
+                speed_position: (16.0, 32.0),
+                max_speed_position: (64.0, 128.0),
+                velocity_position: (256.0, 512.0),
+                ..default()
+            },
+            ..default()
+        };
+        let mut world = World::new();
+        world.insert_resource(settings);
+        let elements = [
+            (HudElement::Fps, (4.0, 8.0)),
+            (HudElement::Speed, (16.0, 32.0)),
+            (HudElement::MaxSpeed, (64.0, 128.0)),
+            (HudElement::Velocity, (256.0, 512.0)),
+        ]
+        .map(|(element, position)| {
+            let entity = world
+                .spawn((
+                    element,
+                    Visibility::Hidden,
+                    Style::default(),
+                    Text::default(),
+                ))
+                .id();
+            (entity, position)
+        });
+        Schedule::default()
+            .add_systems(update_hud_visibility)
+            .run(&mut world);
+
+        for (entity, (left, top)) in elements {
+            let style = world.get::<Style>(entity).unwrap();
+            assert_eq!(style.position_type, PositionType::Absolute);
+            assert_eq!(style.left, Val::Px(left));
+            assert_eq!(style.top, Val::Px(top));
+        }
+    }
+}
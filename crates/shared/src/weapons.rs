@@ -0,0 +1,526 @@
+use bevy::{ecs::system::EntityCommands, prelude::*};
+use bevy_renet::renet::ClientId;
+use bevy_xpbd_3d::components::LinearVelocity;
+use bevy_xpbd_3d::plugins::spatial_query::{SpatialQuery, SpatialQueryFilter};
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, WeaponConfig};
+use crate::input::InputAction;
+use crate::networking::{Player, PlayerCommand, Team};
+use crate::physics::Layer;
+use crate::player::{Crosshair, LocalPlayer, PlayerInput, Stance};
+use crate::triggers::{in_safe_zone, SafeZone};
+
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone, Serialize, Deserialize)]
+pub enum WeaponKind {
+    Knife,
+    Pistol,
+    Smg,
+    Rifle,
+}
+
+#[derive(Debug, Component)]
+pub struct Weapon {
+    pub kind: WeaponKind,
+    next_shot: f64,
+    shots: u32,
+}
+
+impl Weapon {
+    pub fn new(kind: WeaponKind) -> Self {
+        Self {
+            kind,
+            next_shot: 0.0,
+            shots: 0,
+        }
+    }
+
+    // A shot up to tolerance early is let through, but the next one is still timed from
+    // now so the tolerance can't be banked into a faster fire rate
+    fn try_fire(&mut self, now: f64, fire_interval: f32, tolerance: f64) -> bool {
+        if now + tolerance < self.next_shot {
+            return false;
+        }
+        self.next_shot = now + fire_interval as f64;
+        self.shots = self.shots.wrapping_add(1);
+        true
+    }
+}
+
+#[derive(Debug, Component)]
+pub struct Inventory {
+    pub weapons: Vec<Entity>,
+    pub active: usize,
+}
+
+impl Inventory {
+    // Spawns the weapons of a loadout as children of the player
+    pub fn spawn(player: &mut EntityCommands, loadout: &[WeaponKind]) -> Self {
+        let mut weapons = Vec::new();
+        player.with_children(|parent| {
+            for kind in loadout {
+                weapons.push(parent.spawn(Weapon::new(*kind)).id());
+            }
+        });
+        Self { weapons, active: 0 }
+    }
+
+    pub fn active_kind(&self, weapons: &Query<&Weapon>) -> Option<WeaponKind> {
+        let entity = self.weapons.get(self.active)?;
+        weapons.get(*entity).ok().map(|weapon| weapon.kind)
+    }
+
+    pub fn loadout(&self, weapons: &Query<&Weapon>) -> Vec<WeaponKind> {
+        self.weapons
+            .iter()
+            .filter_map(|entity| weapons.get(*entity).ok())
+            .map(|weapon| weapon.kind)
+            .collect()
+    }
+}
+
+// Screen space offset in pixels that pushes the local player's aim upwards
+#[derive(Component, Default)]
+pub struct Recoil(pub f32);
+
+// Present while the active weapon is being drawn
+#[derive(Component)]
+pub struct Deploying(pub Timer);
+
+impl Deploying {
+    pub fn new(kind: WeaponKind, config: &Config) -> Self {
+        let deploy_time = config
+            .weapons
+            .get(&kind)
+            .map_or(0.0, |weapon| weapon.deploy_time);
+        Self(Timer::from_seconds(deploy_time, TimerMode::Once))
+    }
+}
+
+// Returns the slot the player wants to switch to given this frame's input
+pub fn select_slot(input: &Input<InputAction>, current: u8, count: usize) -> u8 {
+    if count == 0 {
+        return 0;
+    }
+    let count = count as u8;
+    let slots = [InputAction::Slot1, InputAction::Slot2, InputAction::Slot3];
+    for (slot, action) in slots.iter().enumerate() {
+        if input.just_pressed(*action) && (slot as u8) < count {
+            return slot as u8;
+        }
+    }
+    if input.just_pressed(InputAction::NextWeapon) {
+        return (current + 1) % count;
+    }
+    if input.just_pressed(InputAction::PrevWeapon) {
+        return (current + count - 1) % count;
+    }
+    current
+}
+
+pub fn switch_weapons(
+    mut commands: Commands,
+    config: Res<Config>,
+    mut query: Query<(Entity, &PlayerInput, &mut Inventory)>,
+    weapons: Query<&Weapon>,
+) {
+    for (entity, player_input, mut inventory) in query.iter_mut() {
+        let slot = player_input.weapon as usize;
+        if slot == inventory.active || slot >= inventory.weapons.len() {
+            continue;
+        }
+        inventory.active = slot;
+        if let Some(kind) = inventory.active_kind(&weapons) {
+            commands
+                .entity(entity)
+                .insert(Deploying::new(kind, &config));
+        }
+    }
+}
+
+pub fn update_deploying(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut query: Query<(Entity, &mut Deploying)>,
+) {
+    for (entity, mut deploying) in query.iter_mut() {
+        if deploying.0.tick(time.delta()).finished() {
+            commands.entity(entity).remove::<Deploying>();
+        }
+    }
+}
+
+// Sent on the server when a client asks to fire its active weapon
+#[derive(Event)]
+pub struct FireWeapon {
+    pub entity: Entity,
+    pub cast_at: Vec3,
+}
+
+#[derive(Event)]
+pub struct WeaponHit {
+    pub attacker: Entity,
+    pub target: Entity,
+    pub kind: WeaponKind,
+    pub point: Vec3,
+}
+
+// Seeded from the server's count of the weapon's shots, so a client can't pick its
+// spread. Clients don't predict spread, only the server traces shots
+pub fn shot_seed(client_id: ClientId, shots: u32) -> u64 {
+    client_id.raw().rotate_left(32) ^ shots as u64
+}
+
+// Spread widens linearly with horizontal speed up to the ground speed, and tightens
+// while crouching
+pub fn current_spread(
+    weapon: &WeaponConfig,
+    speed: f32,
+    ground_speed: f32,
+    crouching: bool,
+) -> f32 {
+    let moving = if ground_speed > 0.0 {
+        (speed / ground_speed).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    let spread = weapon.spread + weapon.move_spread * moving;
+    if crouching {
+        spread * weapon.crouch_spread.max(0.0)
+    } else {
+        spread
+    }
+}
+
+// Rotates a shot around the up axis by a random angle within the spread cone
+pub fn spread_direction(direction: Vec3, spread: f32, seed: u64) -> Vec3 {
+    if spread <= 0.0 {
+        return direction;
+    }
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+    let angle = rng.gen_range(-spread..=spread);
+    Quat::from_rotation_y(angle) * direction
+}
+
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+pub fn client_fire_weapon(
+    time: Res<Time>,
+    config: Res<Config>,
+    input: Res<Input<InputAction>>,
+    mut player_commands: EventWriter<PlayerCommand>,
+    mut player_query: Query<(&Inventory, &mut Recoil), (With<LocalPlayer>, Without<Deploying>)>,
+    mut weapons: Query<&mut Weapon>,
+    crosshair_query: Query<&Transform, With<Crosshair>>,
+) {
+    if !input.pressed(InputAction::Attack) {
+        return;
+    }
+    if let (Ok((inventory, mut recoil)), Ok(crosshair_transform)) =
+        (player_query.get_single_mut(), crosshair_query.get_single())
+    {
+        let Some(mut weapon) = inventory
+            .weapons
+            .get(inventory.active)
+            .and_then(|entity| weapons.get_mut(*entity).ok())
+        else {
+            return;
+        };
+        let Some(weapon_config) = config.weapons.get(&weapon.kind) else {
+            return;
+        };
+        if !weapon.try_fire(time.elapsed_seconds_f64(), weapon_config.fire_interval, 0.0) {
+            return;
+        }
+        recoil.0 = (recoil.0 + weapon_config.recoil).min(weapon_config.max_recoil);
+        player_commands.send(PlayerCommand::BasicAttack {
+            cast_at: crosshair_transform.translation,
+        });
+    }
+}
+
+pub fn recover_recoil(
+    time: Res<Time>,
+    config: Res<Config>,
+    mut query: Query<(&mut Recoil, &Inventory)>,
+    weapons: Query<&Weapon>,
+) {
+    for (mut recoil, inventory) in query.iter_mut() {
+        let recovery = inventory
+            .active_kind(&weapons)
+            .and_then(|kind| config.weapons.get(&kind))
+            .map_or(0.0, |weapon| weapon.recoil_recovery);
+        recoil.0 = (recoil.0 - recovery * time.delta_seconds()).max(0.0);
+    }
+}
+
+// Allow for jitter between when the client and the server see a shot
+const FIRE_TOLERANCE: f64 = 0.05;
+
+// Shot waiting for its weapon's attack frame before the hit is traced
+pub struct PendingAttack {
+    entity: Entity,
+    cast_at: Vec3,
+    seed: u64,
+    kind: WeaponKind,
+    lands_at: f64,
+}
+
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+pub fn server_fire_weapons(
+    time: Res<Time>,
+    config: Res<Config>,
+    spatial_query: SpatialQuery,
+    mut fire_events: EventReader<FireWeapon>,
+    mut hit_events: EventWriter<WeaponHit>,
+    player_query: Query<(
+        &Player,
+        &Transform,
+        &LinearVelocity,
+        &Inventory,
+        &Stance,
+        Has<Deploying>,
+    )>,
+    mut weapons: Query<&mut Weapon>,
+    zones: Query<&SafeZone>,
+    mut pending: Local<Vec<PendingAttack>>,
+) {
+    for fire in fire_events.read() {
+        let Ok((player, _, _, inventory, _, is_deploying)) = player_query.get(fire.entity) else {
+            continue;
+        };
+        if is_deploying {
+            continue;
+        }
+        let Some(mut weapon) = inventory
+            .weapons
+            .get(inventory.active)
+            .and_then(|entity| weapons.get_mut(*entity).ok())
+        else {
+            continue;
+        };
+        let Some(weapon_config) = config.weapons.get(&weapon.kind) else {
+            continue;
+        };
+        let now = time.elapsed_seconds_f64();
+        if !weapon.try_fire(now, weapon_config.fire_interval, FIRE_TOLERANCE) {
+            continue;
+        }
+        pending.push(PendingAttack {
+            entity: fire.entity,
+            cast_at: fire.cast_at,
+            seed: shot_seed(player.id, weapon.shots),
+            kind: weapon.kind,
+            lands_at: now + weapon_config.attack_delay() as f64,
+        });
+    }
+
+    // Traced from where the attacker is when the hit lands, not where they fired from
+    let now = time.elapsed_seconds_f64();
+    let (landed, waiting) = std::mem::take(&mut *pending)
+        .into_iter()
+        .partition(|attack| attack.lands_at <= now);
+    *pending = waiting;
+    for attack in landed {
+        let Ok((player, transform, velocity, _, stance, _)) = player_query.get(attack.entity)
+        else {
+            continue;
+        };
+        let Some(weapon_config) = config.weapons.get(&attack.kind) else {
+            continue;
+        };
+
+        let origin = transform.translation;
+        let mut cast_at = attack.cast_at;
+        cast_at.y = origin.y;
+        let direction = (cast_at - origin).normalize_or_zero();
+        if direction == Vec3::ZERO {
+            continue;
+        }
+        let spread = current_spread(
+            weapon_config,
+            velocity.xz().length(),
+            config.physics.ground_speed,
+            stance.crouching,
+        );
+        let direction = spread_direction(direction, spread, attack.seed);
+
+        if let Some(hit) = spatial_query.cast_ray(
+            origin,
+            direction,
+            weapon_config.range,
+            true,
+            SpatialQueryFilter::new()
+                .with_masks([Layer::Ground, Layer::Player])
+                .without_entities([attack.entity]),
+        ) {
+            // No friendly fire, and shots into or out of a base do nothing
+            let Ok((target, target_transform, ..)) = player_query.get(hit.entity) else {
+                continue;
+            };
+            let in_safe_zone =
+                |position, team| in_safe_zone(&spatial_query, &zones, position, team);
+            if deals_damage(
+                (player.team, origin),
+                (target.team, target_transform.translation),
+                in_safe_zone,
+            ) {
+                hit_events.send(WeaponHit {
+                    attacker: attack.entity,
+                    target: hit.entity,
+                    kind: attack.kind,
+                    point: origin + direction * hit.time_of_impact,
+                });
+            }
+        }
+    }
+}
+
+// Whether a shot between two players at their positions hurts, given a check for
+// whether a team is protected at a position
+fn deals_damage(
+    (attacker, attacker_position): (Team, Vec3),
+    (target, target_position): (Team, Vec3),
+    in_safe_zone: impl Fn(Vec3, Team) -> bool,
+) -> bool {
+    attacker != target
+        && !in_safe_zone(attacker_position, attacker)
+        && !in_safe_zone(target_position, target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rifle() -> WeaponConfig {
+        WeaponConfig {
+            damage: 20.0,
+            deploy_time: 0.5,
+            fire_interval: 0.1,
+            range: 30.0,
+            spread: 0.02,
+            move_spread: 0.1,
+            crouch_spread: 0.5,
+            recoil: 4.0,
+            max_recoil: 40.0,
+            recoil_recovery: 60.0,
+            attack_frame: 0,
+            attack_frame_time: 0.0,
+        }
+    }
+
+    fn pressed(action: InputAction) -> Input<InputAction> {
+        let mut input = Input::default();
+        input.press(action);
+        input
+    }
+
+    #[test]
+    fn select_slot_picks_pressed_slot() {
+        assert_eq!(select_slot(&pressed(InputAction::Slot2), 0, 3), 1);
+        // Slots past the loadout are ignored
+        assert_eq!(select_slot(&pressed(InputAction::Slot3), 1, 2), 1);
+        assert_eq!(select_slot(&Input::default(), 2, 3), 2);
+        assert_eq!(select_slot(&pressed(InputAction::Slot1), 0, 0), 0);
+    }
+
+    #[test]
+    fn select_slot_wraps_around() {
+        assert_eq!(select_slot(&pressed(InputAction::NextWeapon), 2, 3), 0);
+        assert_eq!(select_slot(&pressed(InputAction::PrevWeapon), 0, 3), 2);
+    }
+
+    #[test]
+    fn spread_widens_with_speed() {
+        let rifle = rifle();
+        assert_eq!(current_spread(&rifle, 0.0, 8.0, false), 0.02);
+        assert!((current_spread(&rifle, 4.0, 8.0, false) - 0.07).abs() < 1e-6);
+        assert!((current_spread(&rifle, 16.0, 8.0, false) - 0.12).abs() < 1e-6);
+    }
+
+    #[test]
+    fn crouching_tightens_spread() {
+        let rifle = rifle();
+        assert_eq!(current_spread(&rifle, 0.0, 8.0, true), 0.01);
+        assert!((current_spread(&rifle, 4.0, 8.0, true) - 0.035).abs() < 1e-6);
+    }
+
+    #[test]
+    fn spread_direction_is_seeded() {
+        let seed = shot_seed(ClientId::from_raw(7), 3);
+        let direction = spread_direction(Vec3::Z, 0.1, seed);
+        assert_eq!(direction, spread_direction(Vec3::Z, 0.1, seed));
+        assert_ne!(direction, spread_direction(Vec3::Z, 0.1, seed + 1));
+        assert!(direction.angle_between(Vec3::Z) <= 0.1 + 1e-5);
+        assert_eq!(spread_direction(Vec3::Z, 0.0, seed), Vec3::Z);
+    }
+
+    #[test]
+    fn try_fire_waits_for_interval() {
+        let mut weapon = Weapon::new(WeaponKind::Rifle);
+        assert!(weapon.try_fire(1.0, 0.25, 0.0));
+        assert!(!weapon.try_fire(1.125, 0.25, 0.0));
+        assert!(weapon.try_fire(1.25, 0.25, 0.0));
+    }
+
+    #[test]
+    fn try_fire_tolerance_doesnt_add_up() {
+        let mut weapon = Weapon::new(WeaponKind::Rifle);
+        assert!(weapon.try_fire(1.0, 0.25, 0.125));
+        assert!(weapon.try_fire(1.125, 0.25, 0.125));
+        // Timed from 1.125, so 1.2 is too early even with the tolerance
+        assert!(!weapon.try_fire(1.2, 0.25, 0.125));
+        assert!(weapon.try_fire(1.25, 0.25, 0.125));
+    }
+
+    #[test]
+    fn damage_in_a_safe_zone_is_nullified() {
+        // Jinrai's base covers everything past x = 10
+        let base = SafeZone {
+            team: Team::Jinrai,
+            protect_team: false,
+        };
+        let in_base = |position: Vec3, team| position.x > 10.0 && base.protects(team);
+        let outside = Vec3::ZERO;
+        let inside = Vec3::X * 12.0;
+
+        assert!(deals_damage(
+            (Team::Nsf, outside),
+            (Team::Jinrai, Vec3::X),
+            in_base
+        ));
+        // Into the base, out of it, and from one enemy to another inside it
+        assert!(!deals_damage(
+            (Team::Jinrai, inside),
+            (Team::Nsf, inside),
+            in_base
+        ));
+        assert!(!deals_damage(
+            (Team::Nsf, inside),
+            (Team::Jinrai, outside),
+            in_base
+        ));
+        // The base's own team can still be hit there unless it's protected too
+        assert!(deals_damage(
+            (Team::Nsf, outside),
+            (Team::Jinrai, inside),
+            in_base
+        ));
+        let base = SafeZone {
+            protect_team: true,
+            ..base
+        };
+        let in_base = |position: Vec3, team| position.x > 10.0 && base.protects(team);
+        assert!(!deals_damage(
+            (Team::Nsf, outside),
+            (Team::Jinrai, inside),
+            in_base
+        ));
+        // No friendly fire anywhere
+        assert!(!deals_damage(
+            (Team::Nsf, outside),
+            (Team::Nsf, outside),
+            |_, _| false
+        ));
+    }
+}